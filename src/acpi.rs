@@ -0,0 +1,172 @@
+//! Just enough ACPI to enumerate the CPUs: RSDP, RSDT/XSDT and MADT.
+//!
+//! The tables are read through the bootloader's mapping of physical memory.
+
+use alloc::vec::Vec;
+use core::{fmt, slice};
+use x86_64::VirtAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// No root system description pointer in the BIOS areas.
+    NoRsdp,
+    /// A table with this signature failed its checksum.
+    BadChecksum([u8; 4]),
+    /// A table with this signature claims to be shorter than its header.
+    BadLength([u8; 4]),
+    NoMadt,
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AcpiError::NoRsdp => write!(f, "no ACPI RSDP found"),
+            AcpiError::BadChecksum(signature) => write!(
+                f,
+                "ACPI table {} has a bad checksum",
+                core::str::from_utf8(signature).unwrap_or("????")
+            ),
+            AcpiError::BadLength(signature) => write!(
+                f,
+                "ACPI table {} is shorter than its header",
+                core::str::from_utf8(signature).unwrap_or("????")
+            ),
+            AcpiError::NoMadt => write!(f, "no ACPI MADT found"),
+        }
+    }
+}
+
+/// A processor listed in the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub acpi_id: u32,
+    pub apic_id: u32,
+}
+
+const SDT_HEADER_LEN: usize = 36;
+const MADT_ENTRIES_OFFSET: usize = SDT_HEADER_LEN + 8;
+
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_LOCAL_X2APIC: u8 = 9;
+const MADT_ENABLED: u32 = 1 << 0;
+
+struct Tables {
+    physical_memory_offset: VirtAddr,
+}
+
+impl Tables {
+    /// # Safety
+    /// `len` bytes at `phys` must be mapped physical memory.
+    unsafe fn bytes(&self, phys: u64, len: usize) -> &'static [u8] {
+        slice::from_raw_parts((self.physical_memory_offset + phys).as_ptr(), len)
+    }
+
+    fn find_rsdp(&self) -> Option<u64> {
+        // The first KiB of the extended BIOS data area, then the BIOS ROM.
+        let ebda = unsafe { self.bytes(0x40e, 2) };
+        let ebda = u64::from(u16::from_le_bytes([ebda[0], ebda[1]])) << 4;
+        let areas = [(ebda, 1024), (0xe0000, 0x20000)];
+        areas.into_iter().find_map(|(start, len)| {
+            let area = unsafe { self.bytes(start, len) };
+            (0..=len - 20)
+                .step_by(16)
+                .find(|&offset| {
+                    area[offset..].starts_with(b"RSD PTR ") && checksum(&area[offset..offset + 20])
+                })
+                .map(|offset| start + offset as u64)
+        })
+    }
+
+    /// The table at `phys`, checked.
+    fn table(&self, phys: u64) -> Result<&'static [u8], AcpiError> {
+        let header = unsafe { self.bytes(phys, SDT_HEADER_LEN) };
+        let len = read_u32(header, 4) as usize;
+        if len < SDT_HEADER_LEN {
+            return Err(AcpiError::BadLength(signature(header)));
+        }
+        let table = unsafe { self.bytes(phys, len) };
+        if checksum(table) {
+            Ok(table)
+        } else {
+            Err(AcpiError::BadChecksum(signature(table)))
+        }
+    }
+
+    fn find_madt(&self) -> Result<&'static [u8], AcpiError> {
+        let rsdp_addr = self.find_rsdp().ok_or(AcpiError::NoRsdp)?;
+        let rsdp = unsafe { self.bytes(rsdp_addr, 36) };
+        let revision = rsdp[15];
+        let (root, entry_size) = if revision >= 2 {
+            (read_u64(rsdp, 24), 8)
+        } else {
+            (u64::from(read_u32(rsdp, 16)), 4)
+        };
+        let root = self.table(root)?;
+        for entry in root[SDT_HEADER_LEN..].chunks_exact(entry_size) {
+            let addr = if entry_size == 8 {
+                read_u64(entry, 0)
+            } else {
+                u64::from(read_u32(entry, 0))
+            };
+            if addr == 0 {
+                continue;
+            }
+            let header = unsafe { self.bytes(addr, SDT_HEADER_LEN) };
+            if &signature(header) == b"APIC" {
+                return self.table(addr);
+            }
+        }
+        Err(AcpiError::NoMadt)
+    }
+}
+
+/// The enabled processors listed in the MADT, in table order.
+pub fn processors(physical_memory_offset: VirtAddr) -> Result<Vec<Processor>, AcpiError> {
+    let tables = Tables {
+        physical_memory_offset,
+    };
+    let madt = tables.find_madt()?;
+
+    let mut processors = Vec::new();
+    let mut offset = MADT_ENTRIES_OFFSET;
+    while offset + 2 <= madt.len() {
+        let kind = madt[offset];
+        let len = usize::from(madt[offset + 1]);
+        if len < 2 || offset + len > madt.len() {
+            break;
+        }
+        let entry = &madt[offset..offset + len];
+        let processor = match kind {
+            MADT_LOCAL_APIC if len >= 8 => {
+                Some((u32::from(entry[2]), u32::from(entry[3]), read_u32(entry, 4)))
+            }
+            MADT_LOCAL_X2APIC if len >= 16 => {
+                Some((read_u32(entry, 12), read_u32(entry, 4), read_u32(entry, 8)))
+            }
+            _ => None,
+        };
+        if let Some((acpi_id, apic_id, flags)) = processor {
+            if flags & MADT_ENABLED != 0 {
+                processors.push(Processor { acpi_id, apic_id });
+            }
+        }
+        offset += len;
+    }
+    Ok(processors)
+}
+
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+fn signature(table: &[u8]) -> [u8; 4] {
+    [table[0], table[1], table[2], table[3]]
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
@@ -1,4 +1,5 @@
 use super::align_up;
+use crate::sync::{IrqSafeMutex, IrqSafeMutexGuard};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
@@ -31,18 +32,20 @@ impl BumpAllocator {
     }
 }
 
+/// Keeps interrupts, and with them thread preemption, disabled while the
+/// allocator is in use, so no other thread can find the heap locked.
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: IrqSafeMutex<A>,
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Self {
-            inner: spin::Mutex::new(inner),
+            inner: IrqSafeMutex::new(inner),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, A> {
         self.inner.lock()
     }
 }
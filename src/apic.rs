@@ -0,0 +1,140 @@
+//! The local APIC of each CPU, used for inter-processor interrupts.
+//!
+//! Device interrupts still arrive through the 8259 PICs on the boot CPU; the
+//! local APIC is only enabled to send and receive IPIs.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::{
+    instructions::interrupts,
+    registers::model_specific::Msr,
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// Virtual address the local APIC registers are mapped to.
+pub const LAPIC_START: usize = 0x_5555_5555_0000;
+
+/// Wakes a CPU halted in an executor's idle loop.
+pub const WAKEUP_VECTOR: u8 = 0xf0;
+/// Asks a CPU to invalidate TLB entries, see [`crate::memory::tlb`].
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xf1;
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// register offsets
+const ID: usize = 0x20;
+const TPR: usize = 0x80;
+const EOI: usize = 0xb0;
+const SVR: usize = 0xf0;
+const ICR_LOW: usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const ICR_NMI: u32 = 0b100 << 8;
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_TRIGGER_LEVEL: u32 = 1 << 15;
+
+static MAPPED: AtomicBool = AtomicBool::new(false);
+
+unsafe fn read(offset: usize) -> u32 {
+    ((LAPIC_START + offset) as *const u32).read_volatile()
+}
+
+unsafe fn write(offset: usize, value: u32) {
+    ((LAPIC_START + offset) as *mut u32).write_volatile(value)
+}
+
+/// Maps the local APIC registers and enables the APIC of the boot CPU.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+    let frame = PhysFrame::containing_address(PhysAddr::new(base & APIC_BASE_ADDR_MASK));
+    let page = Page::containing_address(VirtAddr::new(LAPIC_START as u64));
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    MAPPED.store(true, Ordering::Release);
+
+    init_cpu();
+    Ok(())
+}
+
+/// Enables the local APIC of the executing CPU. [`init`] must have run.
+pub fn init_cpu() {
+    assert!(is_initialized(), "local APIC is not mapped");
+    unsafe {
+        let mut msr = Msr::new(IA32_APIC_BASE);
+        msr.write(msr.read() | APIC_BASE_ENABLE);
+        write(TPR, 0);
+        write(SVR, SVR_ENABLE | u32::from(SPURIOUS_VECTOR));
+    }
+    crate::percpu::current().set_online();
+}
+
+/// Whether [`init`] has run, so IPIs can be sent.
+pub fn is_initialized() -> bool {
+    MAPPED.load(Ordering::Acquire)
+}
+
+/// APIC ID of the executing CPU.
+pub fn id() -> u32 {
+    if is_initialized() {
+        unsafe { read(ID) >> 24 }
+    } else {
+        core::arch::x86_64::__cpuid(1).ebx >> 24
+    }
+}
+
+/// Sends interrupt `vector` to the CPU with APIC ID `apic_id`.
+pub fn send_ipi(apic_id: u32, vector: u8) {
+    unsafe { send(apic_id, ICR_LEVEL_ASSERT | u32::from(vector)) };
+}
+
+/// Sends a non-maskable interrupt to the CPU with APIC ID `apic_id`.
+pub fn send_nmi(apic_id: u32) {
+    unsafe { send(apic_id, ICR_NMI | ICR_LEVEL_ASSERT) };
+}
+
+/// Sends an INIT IPI, which resets the CPU to wait for a startup IPI.
+pub fn send_init(apic_id: u32) {
+    unsafe {
+        send(apic_id, ICR_INIT | ICR_TRIGGER_LEVEL | ICR_LEVEL_ASSERT);
+        send(apic_id, ICR_INIT | ICR_TRIGGER_LEVEL);
+    }
+}
+
+/// Sends a startup IPI: the CPU starts in real mode at `page * 4096`.
+pub fn send_startup(apic_id: u32, page: u8) {
+    unsafe { send(apic_id, ICR_STARTUP | ICR_LEVEL_ASSERT | u32::from(page)) };
+}
+
+/// Writes the interrupt command register once the previous IPI went out.
+///
+/// Interrupt handlers send IPIs too, so none may run between the two
+/// writes: its IPI would overwrite the destination in `ICR_HIGH`.
+unsafe fn send(apic_id: u32, command: u32) {
+    interrupts::without_interrupts(|| {
+        while read(ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+        write(ICR_HIGH, apic_id << 24);
+        write(ICR_LOW, command);
+    });
+}
+
+/// Acknowledges the interrupt being handled. Not for PIC interrupts.
+pub fn end_of_interrupt() {
+    unsafe { write(EOI, 0) };
+}
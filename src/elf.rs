@@ -0,0 +1,326 @@
+//! Loading ELF64 executables as user programs.
+//!
+//! [`load`] checks the headers, maps every `PT_LOAD` segment into a new
+//! [`AddressSpace`] with the segment's permissions and lays out a stack the
+//! way the System V ABI describes it for process entry: `argc`, the `argv`
+//! and `envp` pointer arrays and the auxiliary vector, with the strings
+//! above them. [`Program::run`] then starts the program in ring 3.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::fmt;
+use x86_64::{
+    registers::model_specific::{Efer, EferFlags},
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr,
+};
+
+use crate::memory::address_space::{AddressSpace, MapError};
+use crate::user::{self, USER_SPACE_END};
+
+/// Top of the user stack of a loaded program.
+pub const STACK_TOP: u64 = 0x_7fff_ffff_0000;
+pub const STACK_SIZE: u64 = 4096 * 16;
+/// Most pages the segments of an image may take up together. Every page
+/// costs a kernel heap entry while the segments are laid out and a frame
+/// once mapped.
+pub const MAX_IMAGE_PAGES: u64 = 4096;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The image ends before a header or segment does.
+    Truncated,
+    BadMagic,
+    /// Not a little-endian x86_64 executable of ELF version 1.
+    Unsupported(&'static str),
+    /// A segment with sizes or addresses that make no sense.
+    BadSegment,
+    /// `argv` and `envp` do not fit on the stack.
+    ArgumentsTooLarge,
+    Map(MapError),
+}
+
+impl From<MapError> for ElfError {
+    fn from(err: MapError) -> Self {
+        ElfError::Map(err)
+    }
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::Truncated => write!(f, "ELF image is truncated"),
+            ElfError::BadMagic => write!(f, "not an ELF image"),
+            ElfError::Unsupported(what) => write!(f, "unsupported ELF image: {}", what),
+            ElfError::BadSegment => write!(f, "ELF image has an invalid segment"),
+            ElfError::ArgumentsTooLarge => write!(f, "arguments do not fit on the stack"),
+            ElfError::Map(err) => write!(f, "mapping the program failed: {}", err),
+        }
+    }
+}
+
+/// A `PT_LOAD` program header.
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    file_size: u64,
+    mem_size: u64,
+}
+
+impl Segment {
+    fn pages(&self) -> impl Iterator<Item = Page> {
+        let first = Page::containing_address(VirtAddr::new(self.vaddr));
+        let last = Page::containing_address(VirtAddr::new(self.vaddr + self.mem_size - 1));
+        Page::range_inclusive(first, last)
+    }
+
+    fn page_count(&self) -> u64 {
+        let first = self.vaddr / 4096;
+        let last = (self.vaddr + self.mem_size - 1) / 4096;
+        last - first + 1
+    }
+
+    fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::empty();
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= no_execute();
+        }
+        flags
+    }
+}
+
+/// `NO_EXECUTE`, if the CPU has it enabled.
+fn no_execute() -> PageTableFlags {
+    if Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+/// The flags of a page that two segments share: it allows what either
+/// allows.
+fn merge_flags(a: PageTableFlags, b: PageTableFlags) -> PageTableFlags {
+    let executable =
+        !a.contains(PageTableFlags::NO_EXECUTE) || !b.contains(PageTableFlags::NO_EXECUTE);
+    let mut flags = a | b;
+    flags.set(PageTableFlags::NO_EXECUTE, !executable);
+    flags
+}
+
+/// A program loaded into its own address space, ready to run.
+#[derive(Debug)]
+pub struct Program {
+    address_space: AddressSpace,
+    entry: VirtAddr,
+    stack_pointer: VirtAddr,
+}
+
+impl Program {
+    pub fn address_space(&self) -> &AddressSpace {
+        &self.address_space
+    }
+
+    pub fn entry(&self) -> VirtAddr {
+        self.entry
+    }
+
+    /// Initial stack pointer, pointing at `argc`.
+    pub fn stack_pointer(&self) -> VirtAddr {
+        self.stack_pointer
+    }
+
+    /// Runs the program in its address space until it exits, and returns
+    /// its exit code. A program can run more than once, but it gets the
+    /// memory of the previous run.
+    pub fn run(&self) -> u64 {
+        self.address_space
+            .enter(|| unsafe { user::enter_user_mode(self.entry, self.stack_pointer) })
+    }
+}
+
+/// Loads the executable `image` and prepares its stack with `args` and
+/// `env`.
+pub fn load(image: &[u8], args: &[&str], env: &[&str]) -> Result<Program, ElfError> {
+    let header = image.get(..HEADER_SIZE).ok_or(ElfError::Truncated)?;
+    if header[..4] != *b"\x7fELF" {
+        return Err(ElfError::BadMagic);
+    }
+    if header[4] != ELFCLASS64 || header[5] != ELFDATA2LSB || header[6] != EV_CURRENT {
+        return Err(ElfError::Unsupported("not ELF64 little endian version 1"));
+    }
+    if read_u16(header, 16) != ET_EXEC {
+        return Err(ElfError::Unsupported("not an executable"));
+    }
+    if read_u16(header, 18) != EM_X86_64 {
+        return Err(ElfError::Unsupported("not for x86_64"));
+    }
+    let entry = read_u64(header, 24);
+    let ph_offset = read_u64(header, 32);
+    let ph_entry_size = usize::from(read_u16(header, 54));
+    let ph_count = usize::from(read_u16(header, 56));
+    if ph_entry_size != PROGRAM_HEADER_SIZE {
+        return Err(ElfError::Unsupported("unexpected program header size"));
+    }
+    let program_headers = usize::try_from(ph_offset)
+        .ok()
+        .and_then(|start| image.get(start..start.checked_add(ph_count * PROGRAM_HEADER_SIZE)?))
+        .ok_or(ElfError::Truncated)?;
+
+    let mut segments = Vec::new();
+    for header in program_headers.chunks_exact(PROGRAM_HEADER_SIZE) {
+        if read_u32(header, 0) != PT_LOAD || read_u64(header, 40) == 0 {
+            continue;
+        }
+        let segment = Segment {
+            flags: read_u32(header, 4),
+            offset: read_u64(header, 8),
+            vaddr: read_u64(header, 16),
+            file_size: read_u64(header, 32),
+            mem_size: read_u64(header, 40),
+        };
+        let file_end = segment.offset.checked_add(segment.file_size);
+        if file_end.is_none_or(|end| end > image.len() as u64) {
+            return Err(ElfError::Truncated);
+        }
+        let mem_end = segment.vaddr.checked_add(segment.mem_size);
+        if segment.file_size > segment.mem_size || mem_end.is_none_or(|end| end > USER_SPACE_END) {
+            return Err(ElfError::BadSegment);
+        }
+        segments.push(segment);
+    }
+    let entry_mapped = segments
+        .iter()
+        .any(|segment| (segment.vaddr..segment.vaddr + segment.mem_size).contains(&entry));
+    if !entry_mapped {
+        return Err(ElfError::BadSegment);
+    }
+    let page_count: u64 = segments.iter().map(Segment::page_count).sum();
+    if page_count > MAX_IMAGE_PAGES {
+        return Err(ElfError::BadSegment);
+    }
+
+    let mut pages = BTreeMap::<Page, PageTableFlags>::new();
+    for segment in &segments {
+        let flags = segment.page_flags();
+        for page in segment.pages() {
+            pages
+                .entry(page)
+                .and_modify(|shared| *shared = merge_flags(*shared, flags))
+                .or_insert(flags);
+        }
+    }
+    let mut address_space = AddressSpace::new()?;
+    for (&page, &flags) in &pages {
+        address_space.map(Page::range_inclusive(page, page), flags)?;
+    }
+    for segment in &segments {
+        let start = segment.offset as usize;
+        let data = &image[start..start + segment.file_size as usize];
+        address_space.write(VirtAddr::new(segment.vaddr), data)?;
+    }
+
+    // The program headers, if a segment loads them.
+    let phdr = segments.iter().find_map(|segment| {
+        let end = ph_offset + (ph_count * PROGRAM_HEADER_SIZE) as u64;
+        (segment.offset <= ph_offset && end <= segment.offset + segment.file_size)
+            .then(|| segment.vaddr + (ph_offset - segment.offset))
+    });
+    let mut auxv = Vec::new();
+    if let Some(phdr) = phdr {
+        auxv.extend([
+            (AT_PHDR, phdr),
+            (AT_PHENT, PROGRAM_HEADER_SIZE as u64),
+            (AT_PHNUM, ph_count as u64),
+        ]);
+    }
+    auxv.extend([(AT_PAGESZ, 4096), (AT_ENTRY, entry), (AT_NULL, 0)]);
+
+    let stack_pointer = build_stack(&mut address_space, args, env, &auxv)?;
+    Ok(Program {
+        address_space,
+        entry: VirtAddr::new(entry),
+        stack_pointer,
+    })
+}
+
+/// Maps the stack and fills in the process entry data. Returns the stack
+/// pointer.
+fn build_stack(
+    address_space: &mut AddressSpace,
+    args: &[&str],
+    env: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<VirtAddr, ElfError> {
+    let strings_size: usize = args.iter().chain(env).map(|s| s.len() + 1).sum();
+    // argc, both arrays with their null terminators, auxv
+    let words = 1 + args.len() + 1 + env.len() + 1 + 2 * auxv.len();
+    let size = (strings_size + 8 * words) as u64;
+    if size > STACK_SIZE - 4096 {
+        return Err(ElfError::ArgumentsTooLarge);
+    }
+    let stack_pointer = (STACK_TOP - size) & !0xf;
+    let strings_start = stack_pointer + 8 * words as u64;
+
+    let mut strings = Vec::with_capacity(strings_size);
+    let mut vector = Vec::with_capacity(words);
+    vector.push(args.len() as u64);
+    for list in [args, env] {
+        for s in list {
+            vector.push(strings_start + strings.len() as u64);
+            strings.extend_from_slice(s.as_bytes());
+            strings.push(0);
+        }
+        vector.push(0);
+    }
+    for &(key, value) in auxv {
+        vector.extend([key, value]);
+    }
+    let vector: Vec<u8> = vector.iter().flat_map(|word| word.to_le_bytes()).collect();
+
+    let first = Page::containing_address(VirtAddr::new(STACK_TOP - STACK_SIZE));
+    let last = Page::containing_address(VirtAddr::new(STACK_TOP - 1));
+    address_space.map(
+        Page::range_inclusive(first, last),
+        PageTableFlags::WRITABLE | no_execute(),
+    )?;
+    address_space.write(VirtAddr::new(stack_pointer), &vector)?;
+    address_space.write(VirtAddr::new(strings_start), &strings)?;
+    Ok(VirtAddr::new(stack_pointer))
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
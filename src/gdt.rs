@@ -1,51 +1,116 @@
+use alloc::{boxed::Box, vec};
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+pub const DEBUG_IST_INDEX: u16 = 3;
+
+macro_rules! ist_stack {
+    ($size:expr) => {{
+        const STACK_SIZE: usize = $size;
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+        stack_start + STACK_SIZE
+    }};
+}
 
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            stack_start + STACK_SIZE
-        };
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = ist_stack!(4096 * 5);
+        // NMI and #MC can arrive at any instruction, including in the middle of
+        // a stack switch, so they never run on the interrupted stack.
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = ist_stack!(4096 * 5);
+        tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = ist_stack!(4096 * 5);
+        tss.interrupt_stack_table[DEBUG_IST_INDEX as usize] = ist_stack!(4096 * 5);
+        tss.privilege_stack_table[0] = ist_stack!(4096 * 5);
         tss
     };
 }
 
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
-    };
+    static ref GDT: (GlobalDescriptorTable, Selectors) = build_gdt(&TSS);
+}
+
+fn build_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    // `sysret` expects user data right before user code.
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            data_selector,
+            user_code_selector,
+            user_data_selector,
+            tss_selector,
+        },
+    )
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+/// Segment selectors. Every CPU's GDT has the same layout.
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
+}
+
+pub fn selectors() -> Selectors {
+    GDT.1
 }
 
 pub fn init() {
-    use x86_64::instructions::segmentation::{Segment, CS};
+    load(&GDT.0, &GDT.1);
+    crate::percpu::current().set_tss(&TSS);
+}
+
+fn load(gdt: &'static GlobalDescriptorTable, selectors: &Selectors) {
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
+    gdt.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(selectors.code_selector);
+        SS::set_reg(selectors.data_selector);
+        load_tss(selectors.tss_selector);
+    }
+}
+
+/// Size of each IST stack and of the ring 0 stack of an application
+/// processor.
+const AP_IST_STACK_SIZE: usize = 4096 * 2;
+
+/// Gives the executing application processor a GDT and TSS of its own and
+/// loads them. A TSS cannot be shared: loading it marks its descriptor busy,
+/// and two CPUs must never run on the same IST stack. Needs the heap.
+pub fn init_ap() {
+    let mut tss = TaskStateSegment::new();
+    for index in [
+        DOUBLE_FAULT_IST_INDEX,
+        NMI_IST_INDEX,
+        MACHINE_CHECK_IST_INDEX,
+        DEBUG_IST_INDEX,
+    ] {
+        let stack = Box::leak(vec![0u8; AP_IST_STACK_SIZE].into_boxed_slice());
+        tss.interrupt_stack_table[index as usize] =
+            VirtAddr::from_ptr(stack.as_ptr()) + AP_IST_STACK_SIZE;
     }
+    let stack = Box::leak(vec![0u8; AP_IST_STACK_SIZE].into_boxed_slice());
+    tss.privilege_stack_table[0] = VirtAddr::from_ptr(stack.as_ptr()) + AP_IST_STACK_SIZE;
+    let tss = Box::leak(Box::new(tss));
+    let (gdt, selectors) = build_gdt(tss);
+    load(Box::leak(Box::new(gdt)), &selectors);
+    crate::percpu::current().set_tss(tss);
 }
@@ -1,30 +1,102 @@
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
-use spin;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::{
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+    VirtAddr,
+};
 
+use self::entry::{entry, paranoid_entry};
+use self::nesting::{NestedIrq, Nesting};
+use crate::apic;
 use crate::gdt;
-use crate::print;
 use crate::println;
+use crate::process::ExitStatus;
+use crate::sync::IrqSafeMutex;
+
+mod entry;
+pub mod mce;
+pub mod nesting;
+pub mod pic;
+pub mod stats;
+pub mod watchdog;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+// Exception vectors
+pub const DIVIDE_ERROR_VECTOR: u8 = 0;
+pub const DEBUG_VECTOR: u8 = 1;
+pub const NMI_VECTOR: u8 = 2;
+pub const BREAKPOINT_VECTOR: u8 = 3;
+pub const INVALID_OPCODE_VECTOR: u8 = 6;
+pub const GENERAL_PROTECTION_VECTOR: u8 = 13;
+pub const PAGE_FAULT_VECTOR: u8 = 14;
+
+pub static PICS: IrqSafeMutex<ChainedPics> =
+    IrqSafeMutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+entry!(divide_error_entry => divide_error_handler);
+entry!(breakpoint_entry => breakpoint_handler);
+entry!(invalid_opcode_entry => invalid_opcode_handler);
+paranoid_entry!(double_fault_entry => double_fault_handler, error_code);
+paranoid_entry!(nmi_entry => nmi_handler);
+paranoid_entry!(machine_check_entry => machine_check_handler);
+paranoid_entry!(debug_entry => debug_handler);
+entry!(timer_entry => timer_interrupt_handler);
+entry!(keyboard_entry => keyboard_interrupt_handler);
+entry!(spurious1_entry => spurious1_interrupt_handler);
+entry!(spurious2_entry => spurious2_interrupt_handler);
+entry!(wakeup_entry => wakeup_interrupt_handler);
+entry!(tlb_shootdown_entry => tlb_shootdown_handler);
+entry!(apic_spurious_entry => apic_spurious_interrupt_handler);
+// `iretq` to user code raises #GP in kernel mode, after the `swapgs`.
+paranoid_entry!(general_protection_entry => general_protection_handler, error_code);
+entry!(page_fault_entry => page_fault_handler, error_code);
+
+fn stub(entry: unsafe extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(entry as usize as u64)
+}
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        // SAFETY: the stubs save the interrupted state and end in `iretq`.
         unsafe {
+            idt.divide_error.set_handler_addr(stub(divide_error_entry));
+            // `int3` in user code kills the process rather than faulting.
+            idt.breakpoint
+                .set_handler_addr(stub(breakpoint_entry))
+                .set_privilege_level(x86_64::PrivilegeLevel::Ring3);
+            idt.invalid_opcode
+                .set_handler_addr(stub(invalid_opcode_entry));
             idt.double_fault
-                .set_handler_fn(double_fault_handler)
+                .set_handler_addr(stub(double_fault_entry))
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.non_maskable_interrupt
+                .set_handler_addr(stub(nmi_entry))
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.machine_check
+                .set_handler_addr(stub(machine_check_entry))
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+            idt.debug
+                .set_handler_addr(stub(debug_entry))
+                .set_stack_index(gdt::DEBUG_IST_INDEX);
+            idt[InterruptIndex::Timer.as_usize()].set_handler_addr(stub(timer_entry));
+            idt[InterruptIndex::Keyboard.as_usize()].set_handler_addr(stub(keyboard_entry));
+            idt[InterruptIndex::Spurious1.as_usize()].set_handler_addr(stub(spurious1_entry));
+            idt[InterruptIndex::Spurious2.as_usize()].set_handler_addr(stub(spurious2_entry));
+            idt[usize::from(apic::WAKEUP_VECTOR)].set_handler_addr(stub(wakeup_entry));
+            idt[usize::from(apic::TLB_SHOOTDOWN_VECTOR)]
+                .set_handler_addr(stub(tlb_shootdown_entry));
+            idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_addr(stub(apic_spurious_entry));
+            idt.general_protection_fault
+                .set_handler_addr(stub(general_protection_entry));
+            idt.page_fault.set_handler_addr(stub(page_fault_entry));
+            idt[usize::from(crate::user::EXIT_VECTOR)]
+                .set_handler_addr(crate::user::exit_handler())
+                .set_privilege_level(x86_64::PrivilegeLevel::Ring3);
         }
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
         idt
     };
 }
@@ -33,31 +105,149 @@ pub fn init_idt() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+/// Whether the exception came from user mode.
+fn from_user(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 3 == 3
+}
+
+/// Kills the process of the current thread with `status` and leaves user
+/// mode. Returns if the thread does not run a process.
+fn kill_process(status: ExitStatus, measure: stats::Measure) {
+    if let Some(pid) = crate::process::kill_current(status) {
+        println!("process {} killed: {}", pid, status);
+        drop(measure);
+        unsafe { crate::user::return_to_kernel(0) };
+    }
+}
+
+extern "C" fn divide_error_handler(stack_frame: &InterruptStackFrame) {
+    let measure = stats::Measure::start(DIVIDE_ERROR_VECTOR);
+    if from_user(stack_frame) {
+        let instruction = stack_frame.instruction_pointer;
+        kill_process(ExitStatus::DivideError { instruction }, measure);
+    }
+    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+}
+
+extern "C" fn breakpoint_handler(stack_frame: &InterruptStackFrame) {
+    let measure = stats::Measure::start(BREAKPOINT_VECTOR);
+    if from_user(stack_frame) {
+        let instruction = stack_frame.instruction_pointer;
+        kill_process(ExitStatus::Breakpoint { instruction }, measure);
+    }
     println!("EXEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
+extern "C" fn invalid_opcode_handler(stack_frame: &InterruptStackFrame) {
+    let measure = stats::Measure::start(INVALID_OPCODE_VECTOR);
+    if from_user(stack_frame) {
+        let instruction = stack_frame.instruction_pointer;
+        kill_process(ExitStatus::InvalidOpcode { instruction }, measure);
+    }
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+
+extern "C" fn double_fault_handler(stack_frame: &InterruptStackFrame, _error_code: u64) -> ! {
+    // Nothing that held the serial lock runs again, and the panic handler
+    // prints over serial.
+    unsafe { crate::serial::SERIAL1.force_unlock() };
     panic!("EXEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
+/// Port 0x61 bits of the legacy NMI sources.
+const NMI_PARITY: u8 = 1 << 7;
+const NMI_CHANNEL_CHECK: u8 = 1 << 6;
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+static OTHER_NMIS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of NMIs that were not watchdog kicks.
+pub fn other_nmis() -> u64 {
+    OTHER_NMIS.load(Ordering::Relaxed)
+}
+
+extern "C" fn nmi_handler(stack_frame: &InterruptStackFrame) {
+    use x86_64::instructions::port::Port;
+
+    let _measure = stats::Measure::start(NMI_VECTOR);
+
+    let kicked = watchdog::check(stack_frame);
+    // System control port B reports the legacy NMI sources. They may have
+    // raised their NMI together with a watchdog kick.
+    let reason: u8 = unsafe { Port::new(0x61).read() };
+    if kicked && reason & (NMI_PARITY | NMI_CHANNEL_CHECK) == 0 {
+        return;
+    }
+    OTHER_NMIS.fetch_add(1, Ordering::Relaxed);
+    watchdog::emergency_print(format_args!(
+        "EXCEPTION: NMI (port 0x61 = {:#04x}{}{})\n",
+        reason,
+        if reason & NMI_PARITY != 0 {
+            ", memory parity"
+        } else {
+            ""
+        },
+        if reason & NMI_CHANNEL_CHECK != 0 {
+            ", I/O channel check"
+        } else {
+            ""
+        },
+    ));
+    watchdog::dump_state(stack_frame);
+}
+
+extern "C" fn machine_check_handler(stack_frame: &InterruptStackFrame) -> ! {
+    watchdog::emergency_print(format_args!(
+        "EXCEPTION: MACHINE CHECK ({:?})\n",
+        mce::global_status()
+    ));
+    for error in mce::read_banks() {
+        watchdog::emergency_print(format_args!("  {}\n", error));
+    }
+    mce::clear();
+    watchdog::dump_state(stack_frame);
+    // See the double fault handler.
+    unsafe { crate::serial::SERIAL1.force_unlock() };
+    panic!("EXCEPTION: MACHINE CHECK");
+}
+
+extern "C" fn debug_handler(stack_frame: &InterruptStackFrame) {
+    use x86_64::registers::debug::Dr6;
+
+    let _measure = stats::Measure::start(DEBUG_VECTOR);
+    // Dropped if the interrupted code holds the console.
+    crate::vga_buffer::try_print(format_args!(
+        "EXCEPTION: DEBUG ({:?})\n{:#?}\n",
+        Dr6::read(),
+        stack_frame
+    ));
+}
+
+extern "C" fn timer_interrupt_handler(_stack_frame: &InterruptStackFrame) {
+    {
+        let _measure = stats::Measure::start(InterruptIndex::Timer.as_u8());
+        let now = crate::time::tick();
+        watchdog::pet();
+        crate::task::timer::on_tick(now);
+
+        unsafe {
+            PICS.lock()
+                .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+        }
+    }
+    // Switching threads may not come back here for a while, so only do it
+    // after the EOI and never on top of another interrupt handler.
+    if !stats::in_interrupt() {
+        crate::thread::on_tick();
     }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "C" fn keyboard_interrupt_handler(_stack_frame: &InterruptStackFrame) {
     /* use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1}; */
     /* use spin::Mutex; */
     use x86_64::instructions::port::Port;
 
+    let _measure = stats::Measure::start(InterruptIndex::Keyboard.as_u8());
+
     /* lazy_static! { */
     /*     static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new( */
     /*         Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore) */
@@ -68,6 +258,11 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
 
+    let nested = match InterruptIndex::Keyboard.nesting() {
+        Nesting::Reenable => Some(NestedIrq::enter(InterruptIndex::Keyboard.irq())),
+        _ => None,
+    };
+
     crate::task::keyboard::add_scancode(scancode);
 
     /* if let Ok(Some(key_event)) = keyboard.add_byte(scancode) { */
@@ -79,21 +274,80 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     /* } */
     /* } */
 
+    if nested.is_none() {
+        unsafe {
+            PICS.lock()
+                .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        }
+    }
+}
+
+/// IRQ 7 is also what the master PIC raises when the requesting line went
+/// away before the CPU acknowledged it. Only a real IRQ 7 sets its ISR bit and
+/// needs an end-of-interrupt.
+extern "C" fn spurious1_interrupt_handler(_stack_frame: &InterruptStackFrame) {
+    let _measure = stats::Measure::start(InterruptIndex::Spurious1.as_u8());
+
+    if pic::read_isr() & (1 << 7) == 0 {
+        stats::record_spurious(InterruptIndex::Spurious1);
+        return;
+    }
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Spurious1.as_u8());
+    }
+}
+
+/// Same as IRQ 7 but on the slave PIC. The master still saw a real cascade
+/// interrupt, so a spurious IRQ 15 acknowledges the master only.
+extern "C" fn spurious2_interrupt_handler(_stack_frame: &InterruptStackFrame) {
+    let _measure = stats::Measure::start(InterruptIndex::Spurious2.as_u8());
+
+    if pic::read_isr() & (1 << 15) == 0 {
+        stats::record_spurious(InterruptIndex::Spurious2);
+        pic::notify_end_of_interrupt_master();
+        return;
+    }
     unsafe {
         PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+            .notify_end_of_interrupt(InterruptIndex::Spurious2.as_u8());
     }
 }
 
+/// Sent by another CPU to end a `hlt`; waking up is all it has to do.
+extern "C" fn wakeup_interrupt_handler(_stack_frame: &InterruptStackFrame) {
+    let _measure = stats::Measure::start(apic::WAKEUP_VECTOR);
+    apic::end_of_interrupt();
+}
+
+extern "C" fn tlb_shootdown_handler(_stack_frame: &InterruptStackFrame) {
+    let _measure = stats::Measure::start(apic::TLB_SHOOTDOWN_VECTOR);
+    crate::memory::tlb::handle_shootdown();
+    apic::end_of_interrupt();
+}
+
+/// Spurious local APIC interrupts must not be acknowledged.
+extern "C" fn apic_spurious_interrupt_handler(_stack_frame: &InterruptStackFrame) {
+    let _measure = stats::Measure::start(apic::SPURIOUS_VECTOR);
+}
+
 use crate::hlt_loop;
 use x86_64::structures::idt::PageFaultErrorCode;
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
+extern "C" fn page_fault_handler(stack_frame: &InterruptStackFrame, error_code: u64) {
+    use crate::process::ExitStatus;
     use x86_64::registers::control::Cr2;
 
+    let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+    let measure = stats::Measure::start(PAGE_FAULT_VECTOR);
+    if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        let status = ExitStatus::PageFault {
+            address: Cr2::read(),
+            instruction: stack_frame.instruction_pointer,
+            error_code,
+        };
+        kill_process(status, measure);
+    }
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read());
     println!("Error Code: {:?}", error_code);
@@ -101,24 +355,85 @@ extern "x86-interrupt" fn page_fault_handler(
     hlt_loop();
 }
 
+extern "C" fn general_protection_handler(stack_frame: &InterruptStackFrame, error_code: u64) {
+    let measure = stats::Measure::start(GENERAL_PROTECTION_VECTOR);
+    let instruction = if crate::syscall::is_slow_return(stack_frame.instruction_pointer) {
+        // The frame `iretq` could not return to is on top of the stack, and
+        // the `syscall` two bytes before its return address is to blame.
+        let user_rip = unsafe { *stack_frame.stack_pointer.as_ptr::<u64>() };
+        Some(VirtAddr::new(user_rip - 2))
+    } else if from_user(stack_frame) {
+        Some(stack_frame.instruction_pointer)
+    } else {
+        None
+    };
+    if let Some(instruction) = instruction {
+        let status = ExitStatus::GeneralProtection {
+            instruction,
+            error_code,
+        };
+        kill_process(status, measure);
+    }
+    panic!(
+        "EXCEPTION: GENERAL PROTECTION FAULT ({:#x})\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    Spurious1 = PIC_1_OFFSET + 7,
+    Spurious2 = PIC_2_OFFSET + 7,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub fn as_u8(self) -> u8 {
         self as u8
     }
 
-    fn as_usize(self) -> usize {
+    pub fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+
+    /// The PIC line, 0-15.
+    pub fn irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
+    }
+
+    /// The timer is the most urgent IRQ and runs with interrupts disabled; the
+    /// keyboard handler lets it preempt.
+    pub fn nesting(self) -> Nesting {
+        match self {
+            InterruptIndex::Keyboard => Nesting::Reenable,
+            _ => Nesting::Disabled,
+        }
+    }
 }
 
 #[test_case]
 fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
 }
+
+#[test_case]
+fn test_breakpoint_is_counted() {
+    let before = stats::count(BREAKPOINT_VECTOR);
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(stats::count(BREAKPOINT_VECTOR), before + 1);
+}
+
+#[test_case]
+fn test_spurious_irqs_are_counted() {
+    use core::arch::asm;
+
+    // Raised in software, the IRQs have no in-service bit, just like
+    // spurious ones.
+    let (irq7, irq15) = stats::spurious();
+    unsafe { asm!("int {}", const InterruptIndex::Spurious1 as u8) };
+    assert_eq!(stats::spurious(), (irq7 + 1, irq15));
+    unsafe { asm!("int {}", const InterruptIndex::Spurious2 as u8) };
+    assert_eq!(stats::spurious(), (irq7 + 1, irq15 + 1));
+}
@@ -0,0 +1,141 @@
+//! Assembly entry stubs for the IDT.
+//!
+//! Code in user mode runs with the user's `GS_BASE`, see [`crate::percpu`].
+//! Every stub saves the registers the C ABI lets a function clobber,
+//! `swapgs`es if the interrupted code ran in user mode, calls the handler
+//! with a pointer to the interrupt frame (and the error code, if any) and
+//! `swapgs`es back before `iretq`. Whether the interrupted code ran in user
+//! mode is read off the RPL of the saved CS.
+//!
+//! NMIs, machine checks, debug exceptions and double faults can also hit the
+//! few kernel instructions between a `swapgs` and the `iretq` or `sysretq`
+//! after it, or between `syscall` and its `swapgs`. An `iretq` that cannot
+//! return to user code raises a general protection fault right there. In
+//! that window the saved CS is the kernel's but `GS_BASE` is the user's.
+//! The "paranoid" stubs of these exceptions read `GS_BASE` instead: the
+//! kernel's is never 0 and the user's always is.
+
+/// Defines the stub `$stub`, which calls `$handler`. With `error_code` the
+/// CPU pushes an error code, which is passed as the second argument.
+macro_rules! entry {
+    ($stub:ident => $handler:path) => {
+        $crate::interrupts::entry::entry!(@stub $stub, $handler, 0);
+    };
+    ($stub:ident => $handler:path, error_code) => {
+        $crate::interrupts::entry::entry!(@stub $stub, $handler, 8);
+    };
+    (@stub $stub:ident, $handler:path, $error:literal) => {
+        extern "C" {
+            fn $stub();
+        }
+
+        core::arch::global_asm!(
+            concat!(".global ", stringify!($stub)),
+            concat!(stringify!($stub), ":"),
+            "push rax",
+            "push rcx",
+            "push rdx",
+            "push rsi",
+            "push rdi",
+            "push r8",
+            "push r9",
+            "push r10",
+            "push r11",
+            "test byte ptr [rsp + {frame} + 8], 3",
+            "jz 1f",
+            "swapgs",
+            "1:",
+            "lea rdi, [rsp + {frame}]",
+            "mov rsi, [rsp + 72]",
+            // Without an error code the frame keeps the stack aligned.
+            "sub rsp, {error}",
+            "call {handler}",
+            "add rsp, {error}",
+            // The handler may have enabled interrupts.
+            "cli",
+            "test byte ptr [rsp + {frame} + 8], 3",
+            "jz 1f",
+            "swapgs",
+            "1:",
+            "pop r11",
+            "pop r10",
+            "pop r9",
+            "pop r8",
+            "pop rdi",
+            "pop rsi",
+            "pop rdx",
+            "pop rcx",
+            "pop rax",
+            "add rsp, {error}",
+            "iretq",
+            frame = const 72 + $error,
+            error = const $error,
+            handler = sym $handler,
+        );
+    };
+}
+
+/// Like [`entry!`], but decides on `swapgs` by `GS_BASE` rather than CS.
+macro_rules! paranoid_entry {
+    ($stub:ident => $handler:path) => {
+        $crate::interrupts::entry::paranoid_entry!(@stub $stub, $handler, 0);
+    };
+    ($stub:ident => $handler:path, error_code) => {
+        $crate::interrupts::entry::paranoid_entry!(@stub $stub, $handler, 8);
+    };
+    (@stub $stub:ident, $handler:path, $error:literal) => {
+        extern "C" {
+            fn $stub();
+        }
+
+        core::arch::global_asm!(
+            concat!(".global ", stringify!($stub)),
+            concat!(stringify!($stub), ":"),
+            "push rax",
+            "push rcx",
+            "push rdx",
+            "push rsi",
+            "push rdi",
+            "push r8",
+            "push r9",
+            "push r10",
+            "push r11",
+            // rbx survives the call and remembers the swap.
+            "push rbx",
+            "mov ecx, 0xc0000101",
+            "rdmsr",
+            "xor ebx, ebx",
+            "or eax, edx",
+            "jnz 1f",
+            "swapgs",
+            "mov ebx, 1",
+            "1:",
+            "lea rdi, [rsp + {frame}]",
+            "mov rsi, [rsp + 80]",
+            "sub rsp, 8 - {error}",
+            "call {handler}",
+            "add rsp, 8 - {error}",
+            "test ebx, ebx",
+            "jz 1f",
+            "swapgs",
+            "1:",
+            "pop rbx",
+            "pop r11",
+            "pop r10",
+            "pop r9",
+            "pop r8",
+            "pop rdi",
+            "pop rsi",
+            "pop rdx",
+            "pop rcx",
+            "pop rax",
+            "add rsp, {error}",
+            "iretq",
+            frame = const 80 + $error,
+            error = const $error,
+            handler = sym $handler,
+        );
+    };
+}
+
+pub(crate) use {entry, paranoid_entry};
@@ -0,0 +1,175 @@
+//! Machine check architecture.
+//!
+//! [`init`] turns on error reporting in the machine check banks. When a bank
+//! reports an uncorrected error, the CPU raises a machine check exception;
+//! its handler prints what [`read_banks`] finds and brings the kernel down.
+
+use bitflags::bitflags;
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Msr;
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17a;
+const IA32_MC0_CTL: u32 = 0x400;
+
+const CPUID_MCE: u32 = 1 << 7;
+const CPUID_MCA: u32 = 1 << 14;
+
+bitflags! {
+    pub struct McgStatus: u64 {
+        /// Restart IP valid: execution can resume at the saved RIP.
+        const RIPV = 1 << 0;
+        /// Error IP valid: the saved RIP points at the faulting instruction.
+        const EIPV = 1 << 1;
+        /// Machine check in progress.
+        const MCIP = 1 << 2;
+    }
+}
+
+bitflags! {
+    pub struct McStatus: u64 {
+        const VAL = 1 << 63;
+        const OVER = 1 << 62;
+        const UC = 1 << 61;
+        const EN = 1 << 60;
+        const MISCV = 1 << 59;
+        const ADDRV = 1 << 58;
+        const PCC = 1 << 57;
+    }
+}
+
+/// A logged error read from one machine check bank.
+#[derive(Debug, Clone, Copy)]
+pub struct BankError {
+    pub bank: u8,
+    pub status: u64,
+    pub addr: Option<u64>,
+    pub misc: Option<u64>,
+}
+
+impl BankError {
+    pub fn flags(&self) -> McStatus {
+        McStatus::from_bits_truncate(self.status)
+    }
+
+    /// The architectural MCA error code (bits 15:0).
+    pub fn mca_error_code(&self) -> u16 {
+        self.status as u16
+    }
+
+    /// The model-specific error code (bits 31:16).
+    pub fn model_error_code(&self) -> u16 {
+        (self.status >> 16) as u16
+    }
+
+    /// Whether the error left the processor context corrupted.
+    pub fn is_fatal(&self) -> bool {
+        self.flags().intersects(McStatus::PCC | McStatus::UC)
+    }
+}
+
+impl fmt::Display for BankError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "MC{}: status={:#018x} mca={:#06x} model={:#06x} {:?}",
+            self.bank,
+            self.status,
+            self.mca_error_code(),
+            self.model_error_code(),
+            self.flags()
+        )?;
+        if let Some(addr) = self.addr {
+            write!(f, " addr={:#x}", addr)?;
+        }
+        if let Some(misc) = self.misc {
+            write!(f, " misc={:#x}", misc)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn is_supported() -> bool {
+    let edx = __cpuid(1).edx;
+    edx & (CPUID_MCE | CPUID_MCA) == CPUID_MCE | CPUID_MCA
+}
+
+pub fn bank_count() -> u8 {
+    if !is_supported() {
+        return 0;
+    }
+    let cap = unsafe { Msr::new(IA32_MCG_CAP).read() };
+    cap as u8
+}
+
+pub fn global_status() -> McgStatus {
+    McgStatus::from_bits_truncate(unsafe { Msr::new(IA32_MCG_STATUS).read() })
+}
+
+/// Enables error reporting in every bank, clears stale logs and sets CR4.MCE.
+pub fn init() {
+    if !is_supported() {
+        return;
+    }
+    for bank in 0..bank_count() {
+        // Bank 0 of older Intel family 6 models is controlled by the BIOS
+        // through EBL_CR_POWERON, and the SDM says not to write MC0_CTL.
+        if bank > 0 || !is_early_intel_family_6() {
+            unsafe { Msr::new(ctl_msr(bank)).write(u64::MAX) };
+        }
+        unsafe { Msr::new(status_msr(bank)).write(0) };
+    }
+    unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION)) };
+}
+
+/// Whether the CPU is an Intel family 6 model from before Nehalem.
+fn is_early_intel_family_6() -> bool {
+    let vendor = __cpuid(0);
+    let intel = (vendor.ebx, vendor.edx, vendor.ecx) == (0x756e_6547, 0x4965_6e69, 0x6c65_746e);
+    let signature = __cpuid(1).eax;
+    let family = (signature >> 8) & 0xf;
+    let model = (signature >> 4) & 0xf | (signature >> 12) & 0xf0;
+    intel && family == 6 && model < 0x1a
+}
+
+/// Reads all banks that currently hold a valid error.
+pub fn read_banks() -> impl Iterator<Item = BankError> {
+    (0..bank_count()).filter_map(|bank| {
+        let status = unsafe { Msr::new(status_msr(bank)).read() };
+        let flags = McStatus::from_bits_truncate(status);
+        if !flags.contains(McStatus::VAL) {
+            return None;
+        }
+        let addr = flags
+            .contains(McStatus::ADDRV)
+            .then(|| unsafe { Msr::new(status_msr(bank) + 1).read() });
+        let misc = flags
+            .contains(McStatus::MISCV)
+            .then(|| unsafe { Msr::new(status_msr(bank) + 2).read() });
+        Some(BankError {
+            bank,
+            status,
+            addr,
+            misc,
+        })
+    })
+}
+
+/// Clears the bank logs and MCIP so that a later machine check does not
+/// escalate to a shutdown.
+pub fn clear() {
+    for bank in 0..bank_count() {
+        unsafe { Msr::new(status_msr(bank)).write(0) };
+    }
+    unsafe { Msr::new(IA32_MCG_STATUS).write(0) };
+}
+
+fn ctl_msr(bank: u8) -> u32 {
+    IA32_MC0_CTL + 4 * u32::from(bank)
+}
+
+fn status_msr(bank: u8) -> u32 {
+    ctl_msr(bank) + 1
+}
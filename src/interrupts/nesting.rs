@@ -0,0 +1,78 @@
+//! Nested interrupt handling for the 8259 IRQs.
+//!
+//! By default every IRQ handler runs through an interrupt gate with
+//! interrupts disabled until `iretq`. An IRQ can instead opt into
+//! [`Nesting::Reenable`] so that a long handler cannot delay a more urgent one.
+
+use x86_64::instructions::interrupts;
+
+use super::pic;
+
+/// How the handler of an IRQ interacts with other IRQs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Nesting {
+    /// Interrupt gate, interrupts stay disabled for the whole handler.
+    Disabled,
+    /// Interrupt gate, but the handler calls [`NestedIrq::enter`] after it has
+    /// read the device, which acknowledges the PIC, masks this IRQ and all
+    /// lower-priority ones and then re-enables interrupts.
+    Reenable,
+}
+
+/// 8259 fully nested priority order: IRQ 0 is the most urgent, the slave
+/// lines 8-15 take the place of the cascade line 2.
+const PRIORITY_ORDER: [u8; 15] = [0, 1, 8, 9, 10, 11, 12, 13, 14, 15, 3, 4, 5, 6, 7];
+
+const CASCADE_IRQ: u8 = 2;
+
+fn rank(irq: u8) -> usize {
+    let irq = if irq == CASCADE_IRQ { 8 } else { irq };
+    PRIORITY_ORDER
+        .iter()
+        .position(|&i| i == irq)
+        .expect("invalid IRQ number")
+}
+
+/// Mask of `irq` and every IRQ with lower priority than it.
+///
+/// The cascade line is never masked, so slave IRQs with a higher priority
+/// still reach the CPU.
+pub fn lower_priority_mask(irq: u8) -> u16 {
+    PRIORITY_ORDER[rank(irq)..]
+        .iter()
+        .fold(0, |mask, &i| mask | 1 << i)
+}
+
+/// Guard for the nested part of a [`Nesting::Reenable`] handler.
+///
+/// Dropping it disables interrupts again and restores the PIC masks, so the
+/// handler returns through `iretq` in the same state it was entered.
+pub struct NestedIrq {
+    saved_masks: u16,
+}
+
+impl NestedIrq {
+    /// Must be called from the handler of `irq` with interrupts still
+    /// disabled. Sends the end-of-interrupt for `irq`, so the handler must not
+    /// send another one.
+    pub fn enter(irq: u8) -> Self {
+        debug_assert!(!interrupts::are_enabled());
+
+        let saved_masks = pic::read_masks();
+        unsafe {
+            pic::write_masks(saved_masks | lower_priority_mask(irq));
+            super::PICS
+                .lock()
+                .notify_end_of_interrupt(super::PIC_1_OFFSET + irq);
+        }
+        interrupts::enable();
+        Self { saved_masks }
+    }
+}
+
+impl Drop for NestedIrq {
+    fn drop(&mut self) {
+        interrupts::disable();
+        unsafe { pic::write_masks(self.saved_masks) };
+    }
+}
@@ -0,0 +1,53 @@
+//! Raw 8259 register access that `pic8259::ChainedPics` does not expose.
+//!
+//! Every access holds the [`PICS`](super::PICS) lock, so it cannot come
+//! between the command and data bytes of another one.
+
+use x86_64::instructions::port::Port;
+
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_DATA: u16 = 0xa1;
+
+const OCW3_READ_ISR: u8 = 0x0b;
+const EOI: u8 = 0x20;
+
+/// Reads the in-service registers of both PICs, IRQ 0 in bit 0.
+pub fn read_isr() -> u16 {
+    let mut command_1: Port<u8> = Port::new(PIC_1_COMMAND);
+    let mut command_2: Port<u8> = Port::new(PIC_2_COMMAND);
+    let _pics = super::PICS.lock();
+    unsafe {
+        command_1.write(OCW3_READ_ISR);
+        command_2.write(OCW3_READ_ISR);
+        u16::from(command_1.read()) | u16::from(command_2.read()) << 8
+    }
+}
+
+/// Sends an end-of-interrupt to the master PIC only.
+///
+/// Needed for a spurious IRQ 15: the slave did not set an ISR bit, but the
+/// master did for the cascade line.
+pub fn notify_end_of_interrupt_master() {
+    let _pics = super::PICS.lock();
+    unsafe { Port::new(PIC_1_COMMAND).write(EOI) };
+}
+
+/// Reads the interrupt mask registers of both PICs, IRQ 0 in bit 0.
+pub fn read_masks() -> u16 {
+    let mut data_1: Port<u8> = Port::new(PIC_1_DATA);
+    let mut data_2: Port<u8> = Port::new(PIC_2_DATA);
+    let _pics = super::PICS.lock();
+    unsafe { u16::from(data_1.read()) | u16::from(data_2.read()) << 8 }
+}
+
+/// Writes the interrupt mask registers of both PICs, IRQ 0 in bit 0.
+///
+/// # Safety
+/// Masking the wrong lines can hide interrupts the kernel depends on.
+pub unsafe fn write_masks(masks: u16) {
+    let _pics = super::PICS.lock();
+    Port::new(PIC_1_DATA).write(masks as u8);
+    Port::new(PIC_2_DATA).write((masks >> 8) as u8);
+}
@@ -0,0 +1,192 @@
+use core::arch::x86_64::_rdtsc;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use super::InterruptIndex;
+
+const VECTORS: usize = 256;
+
+struct VectorStats {
+    count: AtomicU64,
+    total_cycles: AtomicU64,
+    min_cycles: AtomicU64,
+    max_cycles: AtomicU64,
+}
+
+impl VectorStats {
+    const fn new() -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            total_cycles: AtomicU64::new(0),
+            min_cycles: AtomicU64::new(u64::MAX),
+            max_cycles: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, cycles: u64) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_cycles.fetch_add(cycles, Ordering::Relaxed);
+        self.min_cycles.fetch_min(cycles, Ordering::Relaxed);
+        self.max_cycles.fetch_max(cycles, Ordering::Relaxed);
+    }
+}
+
+static STATS: [VectorStats; VECTORS] = [const { VectorStats::new() }; VECTORS];
+static SPURIOUS: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+crate::percpu! {
+    static DEPTH: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// Counts one interrupt on `vector` and measures the handler duration in TSC
+/// cycles until it is dropped.
+pub struct Measure {
+    vector: u8,
+    start: u64,
+}
+
+impl Measure {
+    pub fn start(vector: u8) -> Self {
+        DEPTH.get().fetch_add(1, Ordering::Relaxed);
+        Self {
+            vector,
+            start: unsafe { _rdtsc() },
+        }
+    }
+}
+
+impl Drop for Measure {
+    fn drop(&mut self) {
+        let cycles = unsafe { _rdtsc() }.wrapping_sub(self.start);
+        STATS[usize::from(self.vector)].record(cycles);
+        DEPTH.get().fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Whether the current code runs inside a measured interrupt handler on
+/// this CPU.
+pub fn in_interrupt() -> bool {
+    DEPTH.get().load(Ordering::Relaxed) != 0
+}
+
+pub(crate) fn record_spurious(index: InterruptIndex) {
+    let pic = match index {
+        InterruptIndex::Spurious1 => 0,
+        InterruptIndex::Spurious2 => 1,
+        _ => return,
+    };
+    SPURIOUS[pic].fetch_add(1, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VectorSnapshot {
+    pub vector: u8,
+    pub count: u64,
+    pub min_cycles: u64,
+    pub avg_cycles: u64,
+    pub max_cycles: u64,
+}
+
+pub fn snapshot(vector: u8) -> VectorSnapshot {
+    let stats = &STATS[usize::from(vector)];
+    let count = stats.count.load(Ordering::Relaxed);
+    let total = stats.total_cycles.load(Ordering::Relaxed);
+    VectorSnapshot {
+        vector,
+        count,
+        min_cycles: if count == 0 {
+            0
+        } else {
+            stats.min_cycles.load(Ordering::Relaxed)
+        },
+        avg_cycles: total.checked_div(count).unwrap_or(0),
+        max_cycles: stats.max_cycles.load(Ordering::Relaxed),
+    }
+}
+
+pub fn count(vector: u8) -> u64 {
+    STATS[usize::from(vector)].count.load(Ordering::Relaxed)
+}
+
+/// Spurious IRQ 7 and IRQ 15 counts.
+pub fn spurious() -> (u64, u64) {
+    (
+        SPURIOUS[0].load(Ordering::Relaxed),
+        SPURIOUS[1].load(Ordering::Relaxed),
+    )
+}
+
+pub fn reset() {
+    for stats in STATS.iter() {
+        stats.count.store(0, Ordering::Relaxed);
+        stats.total_cycles.store(0, Ordering::Relaxed);
+        stats.min_cycles.store(u64::MAX, Ordering::Relaxed);
+        stats.max_cycles.store(0, Ordering::Relaxed);
+    }
+    for spurious in SPURIOUS.iter() {
+        spurious.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Printable table of all vectors that fired at least once.
+///
+/// ```ignore
+/// println!("{}", interrupts::stats::table());
+/// ```
+pub fn table() -> Table {
+    Table { _private: () }
+}
+
+pub struct Table {
+    _private: (),
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>4} {:<12} {:>10} {:>10} {:>10} {:>10}",
+            "vec", "name", "count", "min", "avg", "max"
+        )?;
+        for vector in 0..VECTORS {
+            let snapshot = snapshot(vector as u8);
+            if snapshot.count == 0 {
+                continue;
+            }
+            writeln!(
+                f,
+                "{:>4} {:<12} {:>10} {:>10} {:>10} {:>10}",
+                snapshot.vector,
+                vector_name(snapshot.vector),
+                snapshot.count,
+                snapshot.min_cycles,
+                snapshot.avg_cycles,
+                snapshot.max_cycles
+            )?;
+        }
+        let (spurious_1, spurious_2) = spurious();
+        write!(
+            f,
+            "spurious: IRQ7 {}, IRQ15 {} (cycles are TSC ticks)",
+            spurious_1, spurious_2
+        )
+    }
+}
+
+fn vector_name(vector: u8) -> &'static str {
+    const EXCEPTIONS: [&str; 21] = [
+        "#DE", "#DB", "NMI", "#BP", "#OF", "#BR", "#UD", "#NM", "#DF", "", "#TS", "#NP", "#SS",
+        "#GP", "#PF", "", "#MF", "#AC", "#MC", "#XM", "#VE",
+    ];
+
+    match vector {
+        v if usize::from(v) < EXCEPTIONS.len() => EXCEPTIONS[usize::from(v)],
+        v if v == InterruptIndex::Timer.as_u8() => "timer",
+        v if v == InterruptIndex::Keyboard.as_u8() => "keyboard",
+        v if v == InterruptIndex::Spurious1.as_u8() => "irq7",
+        v if v == InterruptIndex::Spurious2.as_u8() => "irq15",
+        crate::apic::WAKEUP_VECTOR => "wakeup",
+        crate::apic::TLB_SHOOTDOWN_VECTOR => "tlb shootdown",
+        crate::apic::SPURIOUS_VECTOR => "apic spurious",
+        _ => "",
+    }
+}
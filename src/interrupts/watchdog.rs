@@ -0,0 +1,135 @@
+//! NMI watchdog.
+//!
+//! The timer interrupt pets the watchdog on every tick. A CPU that does
+//! nothing else runs [`run`] and kicks the watched CPU with an NMI every
+//! period; NMIs get through even with interrupts disabled. When a kick finds
+//! that no tick happened since the previous one, the kernel is considered
+//! hung and its state is dumped over serial.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+use uart_16550::SerialPort;
+use x86_64::{structures::idt::InterruptStackFrameValue, VirtAddr};
+
+use crate::apic;
+use crate::serial::COM1;
+use crate::time::Instant;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// APIC ID of the CPU that takes the timer interrupt.
+static WATCHED: AtomicU32 = AtomicU32::new(0);
+static HEARTBEAT: AtomicU64 = AtomicU64::new(0);
+static LAST_SEEN: AtomicU64 = AtomicU64::new(0);
+/// Set right before a kick is sent, so that other NMIs are not mistaken for
+/// one.
+static KICKED: AtomicBool = AtomicBool::new(false);
+static HANGS: AtomicU64 = AtomicU64::new(0);
+
+/// Starts watching the executing CPU, which must be the one that takes the
+/// timer interrupt. Needs [`apic::init`].
+pub fn enable() {
+    WATCHED.store(apic::id(), Ordering::Relaxed);
+    LAST_SEEN.store(HEARTBEAT.load(Ordering::Relaxed), Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Release);
+}
+
+pub fn disable() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Number of times the watchdog found the kernel hung.
+pub fn hangs() -> u64 {
+    HANGS.load(Ordering::Relaxed)
+}
+
+pub(crate) fn pet() {
+    HEARTBEAT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Kicks the watched CPU every `period` while the watchdog is enabled.
+/// Meant to be the entry of an AP that has nothing else to do.
+pub fn run(period: Duration) -> ! {
+    loop {
+        let deadline = Instant::now() + period;
+        while Instant::now() < deadline {
+            core::hint::spin_loop();
+        }
+        if is_enabled() {
+            kick();
+        }
+    }
+}
+
+/// Sends the watched CPU an NMI that makes it check for a tick.
+pub fn kick() {
+    KICKED.store(true, Ordering::Release);
+    apic::send_nmi(WATCHED.load(Ordering::Relaxed));
+}
+
+/// Called from the NMI handler. Returns `true` if the NMI was a kick;
+/// other NMIs are left to the caller.
+pub(crate) fn check(stack_frame: &InterruptStackFrameValue) -> bool {
+    if apic::id() != WATCHED.load(Ordering::Relaxed) || !KICKED.swap(false, Ordering::AcqRel) {
+        return false;
+    }
+    if !is_enabled() {
+        return true;
+    }
+    let heartbeat = HEARTBEAT.load(Ordering::Relaxed);
+    if LAST_SEEN.swap(heartbeat, Ordering::Relaxed) == heartbeat {
+        HANGS.fetch_add(1, Ordering::Relaxed);
+        emergency_print(format_args!(
+            "WATCHDOG: no timer tick since last NMI (tick {}), kernel looks hung\n",
+            heartbeat
+        ));
+        dump_state(stack_frame);
+    }
+    true
+}
+
+/// Dumps the interrupted context and the top of its stack, as far as the
+/// stack is mapped.
+pub fn dump_state(stack_frame: &InterruptStackFrameValue) {
+    use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+
+    emergency_print(format_args!("{:#?}\n", stack_frame));
+    emergency_print(format_args!(
+        "CR0={:?}\nCR2={:?}\nCR3={:?}\nCR4={:?}\n",
+        Cr0::read(),
+        Cr2::read(),
+        Cr3::read(),
+        Cr4::read()
+    ));
+
+    let stack_pointer = stack_frame.stack_pointer.as_u64();
+    for i in 0..8 {
+        let offset = i * core::mem::size_of::<u64>() as u64;
+        let addr = match stack_pointer.checked_add(offset).map(VirtAddr::try_new) {
+            Some(Ok(addr)) if addr.is_aligned(8u64) => addr,
+            _ => break,
+        };
+        if crate::memory::effective_flags(addr).is_none() {
+            emergency_print(format_args!("  [rsp+{:#04x}] not mapped\n", offset));
+            break;
+        }
+        let value = unsafe { addr.as_ptr::<u64>().read_volatile() };
+        emergency_print(format_args!("  [rsp+{:#04x}] {:#018x}\n", offset, value));
+    }
+}
+
+/// Prints over serial without taking the serial lock, so it works even if
+/// the interrupted context or another CPU holds it. The holder keeps its
+/// lock and may go on writing, so the output can interleave with its own.
+pub(crate) fn emergency_print(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    // SAFETY: `SERIAL1` initialized the port. Writing a byte only polls the
+    // line status and never changes the port's configuration.
+    let mut serial = unsafe { SerialPort::new(COM1) };
+    let _ = serial.write_fmt(args);
+}
@@ -2,7 +2,6 @@
 #![cfg_attr(test, no_main)]
 #![feature(custom_test_frameworks)]
 #![feature(naked_functions)]
-#![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
 #![feature(const_mut_refs)]
 #![test_runner(crate::test_runner)]
@@ -12,13 +11,24 @@ extern crate alloc;
 use core::arch::asm;
 use core::panic::PanicInfo;
 
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod elf;
 pub mod interrupts;
 pub mod memory;
+pub mod percpu;
+pub mod process;
 //pub mod naked_interrupts;
 pub mod gdt;
 pub mod serial;
+pub mod smp;
+pub mod sync;
+pub mod syscall;
 pub mod task;
+pub mod thread;
+pub mod time;
+pub mod user;
 pub mod vga_buffer;
 
 pub trait Testable {
@@ -47,6 +57,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    task::recover::try_recover(info);
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
     exit_qemu(QemuExitCode::Failed);
@@ -91,10 +102,14 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
 }
 
 pub fn init() {
+    percpu::init(0);
     gdt::init();
+    syscall::init();
     interrupts::init_idt();
+    interrupts::mce::init();
     //naked_interrupts::init();
     unsafe { interrupts::PICS.lock().initialize() };
+    time::init();
     x86_64::instructions::interrupts::enable();
 }
 
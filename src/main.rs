@@ -8,13 +8,16 @@
 extern crate alloc;
 //use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
 use blog_os::println;
-use blog_os::task::{executor::Executor, keyboard, Task};
+use blog_os::task::{executor::Executor, keyboard};
 use bootloader::{entry_point, BootInfo};
 //use core::arch::asm;
 use core::panic::PanicInfo;
+use core::time::Duration;
 
 entry_point!(kernel_main);
 
+const WATCHDOG_PERIOD: Duration = Duration::from_secs(1);
+
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
     use blog_os::allocator;
     use blog_os::memory;
@@ -29,6 +32,16 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    blog_os::apic::init(&mut mapper, &mut frame_allocator).expect("local APIC mapping failed");
+    blog_os::thread::init();
+    match blog_os::smp::init(&mut mapper, &mut frame_allocator, phys_mem_offset, ap_main) {
+        Ok(cpus) => println!("{} CPUs online", cpus),
+        Err(err) => println!("running on the boot CPU only: {}", err),
+    }
+    if blog_os::smp::online_cpus() > 1 {
+        blog_os::interrupts::watchdog::enable();
+    }
+    memory::install(mapper, frame_allocator);
 
     /*     println!("check heap"); */
     /* let heap_value = Box::new(41); */
@@ -61,11 +74,19 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     test_main();
 
     let mut executor = Executor::new();
-    executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.isolate_panics(true);
+    executor.spawn(example_task());
+    executor.spawn(keyboard::print_keypresses());
     executor.run();
 }
 
+fn ap_main(cpu: usize) -> ! {
+    if cpu == 1 {
+        blog_os::interrupts::watchdog::run(WATCHDOG_PERIOD);
+    }
+    blog_os::hlt_loop();
+}
+
 async fn async_number() -> u32 {
     42
 }
@@ -78,6 +99,7 @@ async fn example_task() {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    blog_os::task::recover::try_recover(info);
     println!("{}", info);
     blog_os::hlt_loop();
 }
@@ -85,6 +107,7 @@ fn panic(info: &PanicInfo) -> ! {
 #[cfg(test)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    blog_os::task::recover::try_recover(info);
     println!("{info}");
     blog_os::hlt_loop();
 }
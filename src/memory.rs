@@ -1,14 +1,25 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
 use x86_64::{
+    registers::control::Cr3,
     structures::paging::{
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+        mapper::{FlagUpdateError, UnmapError},
+        page::PageRangeInclusive,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
 
-unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
-    use x86_64::registers::control::Cr3;
+pub mod address_space;
+pub mod tlb;
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// Start address of the kernel's level 4 table.
+static KERNEL_PAGE_TABLE: AtomicU64 = AtomicU64::new(0);
 
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
     let (level_4_table_frame, _) = Cr3::read();
 
     let phys = level_4_table_frame.start_address();
@@ -57,10 +68,83 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)-> Opti
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    let (level_4_table_frame, _) = Cr3::read();
+    KERNEL_PAGE_TABLE.store(
+        level_4_table_frame.start_address().as_u64(),
+        Ordering::Relaxed,
+    );
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// Where all physical memory is mapped. Valid after [`init`].
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/// The level 4 table that [`init`] found active.
+pub fn kernel_page_table() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_PAGE_TABLE.load(Ordering::Relaxed)))
+}
+
+/// The flags of the page containing `addr` in the active address space, or
+/// `None` if it is not mapped. `USER_ACCESSIBLE` and `WRITABLE` are only
+/// set if the entries at every level have them. Always `None` before
+/// [`init`].
+pub fn effective_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    if KERNEL_PAGE_TABLE.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    let inherited = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+    let (mut frame, _) = Cr3::read();
+    let mut allowed = inherited;
+    let indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    for (level, &index) in indexes.iter().enumerate() {
+        let table: &PageTable =
+            unsafe { &*(physical_memory_offset() + frame.start_address().as_u64()).as_ptr() };
+        let entry = &table[index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        allowed &= flags;
+        let huge = (level == 1 || level == 2) && flags.contains(PageTableFlags::HUGE_PAGE);
+        if level == 3 || huge {
+            return Some((flags - inherited) | allowed);
+        }
+        frame = PhysFrame::containing_address(entry.addr());
+    }
+    unreachable!()
+}
+
+/// The page table and frame allocator that the kernel maps memory with
+/// after boot. A `spin::Mutex` rather than an `IrqSafeMutex`: the TLB
+/// shootdowns done while holding it need the other CPUs to take interrupts.
+static KERNEL_MEMORY: Mutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> =
+    Mutex::new(None);
+
+/// Hands the page table and frame allocator over for mapping memory at run
+/// time, e.g. for system calls.
+pub fn install(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    *KERNEL_MEMORY.lock() = Some((mapper, frame_allocator));
+}
+
+/// Calls `f` with the page table and frame allocator from [`install`], or
+/// returns `None` if they have not been installed.
+pub fn with_kernel_memory<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R,
+) -> Option<R> {
+    let mut memory = KERNEL_MEMORY.lock();
+    let (mapper, frame_allocator) = memory.as_mut()?;
+    Some(f(mapper, frame_allocator))
+}
+
 pub fn create_example_mapping(
     page: Page,
     mapper: &mut OffsetPageTable,
@@ -78,6 +162,45 @@ pub fn create_example_mapping(
     map_to_result.expect("map_to failed").flush();
 }
 
+/// Unmaps `page` and invalidates it on all CPUs. Returns the frame it was
+/// mapped to.
+pub fn unmap(mapper: &mut impl Mapper<Size4KiB>, page: Page) -> Result<PhysFrame, UnmapError> {
+    let (frame, flush) = mapper.unmap(page)?;
+    flush.ignore();
+    tlb::shootdown(page);
+    Ok(frame)
+}
+
+/// Unmaps all `pages` with a single shootdown. Stops at the first page that
+/// cannot be unmapped; the pages before it stay unmapped.
+pub fn unmap_range(
+    mapper: &mut impl Mapper<Size4KiB>,
+    pages: PageRangeInclusive,
+) -> Result<(), UnmapError> {
+    let mut shootdown = tlb::Shootdown::new();
+    for page in pages {
+        let (_, flush) = mapper.unmap(page)?;
+        flush.ignore();
+        shootdown.add(page);
+    }
+    shootdown.finish();
+    Ok(())
+}
+
+/// Changes the flags of `page` and invalidates it on all CPUs.
+///
+/// # Safety
+/// Same as [`Mapper::update_flags`].
+pub unsafe fn update_flags(
+    mapper: &mut impl Mapper<Size4KiB>,
+    page: Page,
+    flags: PageTableFlags,
+) -> Result<(), FlagUpdateError> {
+    mapper.update_flags(page, flags)?.ignore();
+    tlb::shootdown(page);
+    Ok(())
+}
+
 pub struct EmptyFrameAllocator;
 
 unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
@@ -86,9 +209,14 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
+/// Hands out the usable frames of the memory map in order, and frames given
+/// back before those. Freed frames are linked through their first word,
+/// which needs [`init`].
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    free: Option<PhysFrame>,
+    free_count: usize,
 }
 
 impl BootInfoFrameAllocator {
@@ -100,8 +228,16 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            free: None,
+            free_count: 0,
         }
     }
+
+    /// Number of frames handed out and not given back.
+    pub fn frames_in_use(&self) -> usize {
+        self.next - self.free_count
+    }
+
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
         let regions = self.memory_map.iter();
         let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
@@ -111,10 +247,35 @@ impl BootInfoFrameAllocator {
     }
 }
 
+/// Ends the list of freed frames.
+const NO_FRAME: u64 = u64::MAX;
+
+fn free_link(frame: PhysFrame) -> *mut u64 {
+    (physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr()
+}
+
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
+        if let Some(frame) = self.free {
+            let next = unsafe { free_link(frame).read() };
+            self.free =
+                (next != NO_FRAME).then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+            self.free_count -= 1;
+            return Some(frame);
+        }
+        let frame = self.usable_frames().nth(self.next)?;
         self.next += 1;
-        frame
+        Some(frame)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let next = self
+            .free
+            .map_or(NO_FRAME, |next| next.start_address().as_u64());
+        free_link(frame).write(next);
+        self.free = Some(frame);
+        self.free_count += 1;
     }
 }
@@ -0,0 +1,241 @@
+//! User address spaces.
+//!
+//! An [`AddressSpace`] has a level 4 table of its own, which starts out as a
+//! copy of the kernel's so that the kernel stays mapped. The level 4 slots
+//! from [`USER_SPACE_START`] to [`USER_SPACE_END`] are left empty and only
+//! user pages go there, so the tables below shared slots are never changed
+//! through an address space. Kernel mappings in slots taken after the
+//! address space was created are not visible in it.
+//!
+//! Dropping an address space frees its level 4 table and every frame
+//! below its user slots, page tables included.
+
+use core::{fmt, mem::ManuallyDrop};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::MapToError, page::PageRangeInclusive, FrameAllocator, FrameDeallocator, Mapper,
+        OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{kernel_page_table, physical_memory_offset, with_kernel_memory};
+use crate::user::{USER_SPACE_END, USER_SPACE_START};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// [`install`](super::install) has not been called.
+    NoKernelMemory,
+    OutOfMemory,
+    /// The page is not between [`USER_SPACE_START`] and [`USER_SPACE_END`].
+    NotUserPage(Page),
+    AlreadyMapped(Page),
+    NotMapped(Page),
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::NoKernelMemory => write!(f, "kernel memory is not installed"),
+            MapError::OutOfMemory => write!(f, "out of physical memory"),
+            MapError::NotUserPage(page) => {
+                write!(
+                    f,
+                    "{:?} is not available to user code",
+                    page.start_address()
+                )
+            }
+            MapError::AlreadyMapped(page) => {
+                write!(f, "{:?} is already mapped", page.start_address())
+            }
+            MapError::NotMapped(page) => write!(f, "{:?} is not mapped", page.start_address()),
+        }
+    }
+}
+
+/// A level 4 page table for user code. Must not be active on any CPU when
+/// it is dropped.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an address space with only the kernel mapped.
+    pub fn new() -> Result<Self, MapError> {
+        with_kernel_memory(|kernel, frame_allocator| {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapError::OutOfMemory)?;
+            let table = unsafe { &mut *table_ptr(frame) };
+            for (slot, (entry, kernel_entry)) in table
+                .iter_mut()
+                .zip(kernel.level_4_table().iter())
+                .enumerate()
+            {
+                if is_user_slot(slot) {
+                    entry.set_unused();
+                } else {
+                    *entry = kernel_entry.clone();
+                }
+            }
+            Ok(AddressSpace {
+                level_4_frame: frame,
+            })
+        })
+        .unwrap_or(Err(MapError::NoKernelMemory))
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe {
+            OffsetPageTable::new(
+                &mut *table_ptr(self.level_4_frame),
+                physical_memory_offset(),
+            )
+        }
+    }
+
+    /// Maps `pages` to zeroed frames, user accessible and with `flags`.
+    /// Pages mapped before an error stay mapped.
+    pub fn map(
+        &mut self,
+        pages: PageRangeInclusive,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError> {
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let table_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        with_kernel_memory(|_, frame_allocator| {
+            for page in pages {
+                if !is_user_slot(usize::from(page.p4_index())) {
+                    return Err(MapError::NotUserPage(page));
+                }
+                let frame = frame_allocator
+                    .allocate_frame()
+                    .ok_or(MapError::OutOfMemory)?;
+                let zero: *mut u8 =
+                    (physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr();
+                unsafe { zero.write_bytes(0, 4096) };
+                let result = unsafe {
+                    self.mapper().map_to_with_table_flags(
+                        page,
+                        frame,
+                        flags,
+                        table_flags,
+                        frame_allocator,
+                    )
+                };
+                let err = match result {
+                    // The page was not present, so no TLB has it.
+                    Ok(flush) => {
+                        flush.ignore();
+                        continue;
+                    }
+                    Err(MapToError::FrameAllocationFailed) => MapError::OutOfMemory,
+                    Err(_) => MapError::AlreadyMapped(page),
+                };
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                return Err(err);
+            }
+            Ok(())
+        })
+        .unwrap_or(Err(MapError::NoKernelMemory))
+    }
+
+    /// Copies `bytes` to `addr`, whatever the flags of the pages there.
+    pub fn write(&mut self, addr: VirtAddr, bytes: &[u8]) -> Result<(), MapError> {
+        let mut addr = addr;
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            let page = Page::<Size4KiB>::containing_address(addr);
+            let phys = self
+                .mapper()
+                .translate_addr(addr)
+                .ok_or(MapError::NotMapped(page))?;
+            let len = bytes.len().min(4096 - usize::from(addr.page_offset()));
+            let dest: *mut u8 = (physical_memory_offset() + phys.as_u64()).as_mut_ptr();
+            unsafe { dest.copy_from_nonoverlapping(bytes.as_ptr(), len) };
+            addr += len;
+            bytes = &bytes[len..];
+        }
+        Ok(())
+    }
+
+    /// Runs `f` with this address space active on the executing CPU.
+    pub fn enter<R>(&self, f: impl FnOnce() -> R) -> R {
+        let (previous, flags) = Cr3::read();
+        unsafe { Cr3::write(self.level_4_frame, flags) };
+        let result = f();
+        unsafe { Cr3::write(previous, flags) };
+        result
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        with_kernel_memory(|_, frame_allocator| {
+            let level_4_table = unsafe { &*table_ptr(self.level_4_frame) };
+            for (slot, entry) in level_4_table.iter().enumerate() {
+                if is_user_slot(slot) && !entry.is_unused() {
+                    unsafe { free_table(entry.addr(), 3, frame_allocator) };
+                }
+            }
+            unsafe { frame_allocator.deallocate_frame(self.level_4_frame) };
+        })
+        .expect("address space outlived kernel memory");
+    }
+}
+
+/// Frees the level `level` table at `table` and everything mapped below it.
+///
+/// # Safety
+/// Nothing may use the table or the frames below it any more.
+unsafe fn free_table(
+    table: PhysAddr,
+    level: u8,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let frame = PhysFrame::containing_address(table);
+    for entry in (*table_ptr(frame)).iter() {
+        if entry.is_unused() {
+            continue;
+        }
+        if level == 1 {
+            frame_allocator.deallocate_frame(PhysFrame::containing_address(entry.addr()));
+        } else {
+            // `map` only creates 4 KiB pages, so there are no huge ones.
+            free_table(entry.addr(), level - 1, frame_allocator);
+        }
+    }
+    frame_allocator.deallocate_frame(frame);
+}
+
+/// Maps `pages` like [`AddressSpace::map`] in the active address space.
+/// Fails with [`MapError::NotUserPage`] in the kernel's.
+pub fn map_active(pages: PageRangeInclusive, flags: PageTableFlags) -> Result<(), MapError> {
+    let (frame, _) = Cr3::read();
+    if frame == kernel_page_table() {
+        return Err(MapError::NotUserPage(pages.start));
+    }
+    // Owned by whoever made it active, so it must not be dropped here.
+    let mut active = ManuallyDrop::new(AddressSpace {
+        level_4_frame: frame,
+    });
+    active.map(pages, flags)
+}
+
+/// Whether level 4 slot `slot` is reserved for user memory.
+fn is_user_slot(slot: usize) -> bool {
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(USER_SPACE_START)).p4_index();
+    let end = Page::<Size4KiB>::containing_address(VirtAddr::new(USER_SPACE_END - 1)).p4_index();
+    (usize::from(first)..=usize::from(end)).contains(&slot)
+}
+
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    (physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr()
+}
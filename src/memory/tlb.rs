@@ -0,0 +1,171 @@
+//! TLB shootdown.
+//!
+//! `invlpg` only reaches the TLB of the executing CPU, while every CPU runs
+//! on the same page tables. A [`Shootdown`] collects the pages whose mapping
+//! changed, invalidates them locally right away and, when finished, sends an
+//! IPI to every other online CPU and waits until each has invalidated them
+//! too.
+//!
+//! Waiting for the other CPUs needs them to take interrupts, so a shootdown
+//! must not be started while holding a lock other CPUs spin on with
+//! interrupts disabled.
+
+use core::{
+    hint,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use spin::Mutex;
+use x86_64::{
+    instructions::{interrupts, tlb},
+    structures::paging::Page,
+    VirtAddr,
+};
+
+use crate::{apic, percpu};
+
+/// Most pages a shootdown invalidates one by one; larger batches flush the
+/// whole TLB instead.
+pub const MAX_BATCH: usize = 16;
+
+const FLUSH_ALL: usize = usize::MAX;
+
+/// The request in flight, read by the targets in their IPI handler.
+struct Mailbox {
+    pages: [AtomicU64; MAX_BATCH],
+    /// Number of valid `pages`, or [`FLUSH_ALL`].
+    len: AtomicUsize,
+    /// Bit `i` is set while CPU `i` has not handled the request yet.
+    pending: AtomicU64,
+}
+
+static MAILBOX: Mailbox = Mailbox {
+    pages: [const { AtomicU64::new(0) }; MAX_BATCH],
+    len: AtomicUsize::new(0),
+    pending: AtomicU64::new(0),
+};
+
+/// Held while a request is in flight.
+static SENDER: Mutex<()> = Mutex::new(());
+
+static SHOOTDOWNS: AtomicU64 = AtomicU64::new(0);
+
+/// Invalidates pages on all CPUs; the remote part happens in [`finish`] or
+/// on drop.
+///
+/// [`finish`]: Shootdown::finish
+pub struct Shootdown {
+    pages: [VirtAddr; MAX_BATCH],
+    len: usize,
+}
+
+impl Shootdown {
+    pub fn new() -> Self {
+        Self {
+            pages: [VirtAddr::zero(); MAX_BATCH],
+            len: 0,
+        }
+    }
+
+    /// Invalidates `page` on this CPU and queues it for the others.
+    pub fn add(&mut self, page: Page) {
+        let addr = page.start_address();
+        tlb::flush(addr);
+        if self.len < MAX_BATCH {
+            self.pages[self.len] = addr;
+            self.len += 1;
+        } else {
+            self.len = FLUSH_ALL;
+        }
+    }
+
+    /// Invalidates the whole TLB on all CPUs, except for global pages.
+    pub fn add_all(&mut self) {
+        tlb::flush_all();
+        self.len = FLUSH_ALL;
+    }
+
+    /// Invalidates the queued pages on the other CPUs and waits until they
+    /// are done.
+    pub fn finish(mut self) {
+        self.send();
+    }
+
+    fn send(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+        let me = percpu::cpu_index();
+        let targets = (0..percpu::MAX_CPUS)
+            .filter(|&cpu| cpu != me && percpu::cpu(cpu).is_online())
+            .fold(0u64, |mask, cpu| mask | 1 << cpu);
+        if targets == 0 || !apic::is_initialized() {
+            return;
+        }
+
+        // Another CPU may be waiting for us to handle its request.
+        let _sender = loop {
+            if let Some(guard) = SENDER.try_lock() {
+                break guard;
+            }
+            interrupts::without_interrupts(handle_shootdown);
+            hint::spin_loop();
+        };
+        if self.len != FLUSH_ALL {
+            for (slot, page) in MAILBOX.pages.iter().zip(&self.pages[..self.len]) {
+                slot.store(page.as_u64(), Ordering::Relaxed);
+            }
+        }
+        MAILBOX.len.store(self.len, Ordering::Relaxed);
+        MAILBOX.pending.store(targets, Ordering::Release);
+        for cpu in (0..percpu::MAX_CPUS).filter(|cpu| targets & 1 << cpu != 0) {
+            apic::send_ipi(percpu::cpu(cpu).apic_id(), apic::TLB_SHOOTDOWN_VECTOR);
+        }
+        while MAILBOX.pending.load(Ordering::Acquire) != 0 {
+            hint::spin_loop();
+        }
+        SHOOTDOWNS.fetch_add(1, Ordering::Relaxed);
+        self.len = 0;
+    }
+}
+
+impl Default for Shootdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Shootdown {
+    fn drop(&mut self) {
+        self.send();
+    }
+}
+
+/// Invalidates `page` on all CPUs.
+pub fn shootdown(page: Page) {
+    let mut shootdown = Shootdown::new();
+    shootdown.add(page);
+    shootdown.finish();
+}
+
+/// Number of shootdowns that had to interrupt other CPUs.
+pub fn shootdowns() -> u64 {
+    SHOOTDOWNS.load(Ordering::Relaxed)
+}
+
+/// Handles the request in flight if it is addressed to this CPU. Called
+/// from the shootdown IPI handler.
+pub(crate) fn handle_shootdown() {
+    let me = 1 << percpu::cpu_index();
+    if MAILBOX.pending.load(Ordering::Acquire) & me == 0 {
+        return;
+    }
+    match MAILBOX.len.load(Ordering::Relaxed) {
+        FLUSH_ALL => tlb::flush_all(),
+        len => {
+            for page in &MAILBOX.pages[..len] {
+                tlb::flush(VirtAddr::new(page.load(Ordering::Relaxed)));
+            }
+        }
+    }
+    MAILBOX.pending.fetch_and(!me, Ordering::Release);
+}
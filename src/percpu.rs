@@ -0,0 +1,274 @@
+//! Per-CPU data.
+//!
+//! Every CPU has a [`CpuLocal`] block, and the `GS_BASE` register of the CPU
+//! points at it while it runs kernel code. Entry paths from user mode must
+//! `swapgs` first to get the kernel's `GS_BASE` back, and `swapgs` again
+//! before they return.
+//!
+//! Variables declared with [`percpu!`](crate::percpu) have one instance per
+//! CPU.
+
+use core::{
+    arch::asm,
+    mem, ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+};
+use x86_64::{
+    instructions::interrupts,
+    registers::model_specific::{GsBase, KernelGsBase},
+    structures::tss::TaskStateSegment,
+    VirtAddr,
+};
+
+/// Most CPUs the kernel supports.
+pub const MAX_CPUS: usize = 16;
+
+const NONE: u64 = u64::MAX;
+
+/// Offset of [`CpuLocal::kernel_stack`], for `gs`-relative accesses.
+pub(crate) const KERNEL_STACK_OFFSET: usize = mem::offset_of!(CpuLocal, kernel_stack);
+/// Offset of the user stack scratch slot.
+pub(crate) const USER_STACK_OFFSET: usize = mem::offset_of!(CpuLocal, user_stack);
+
+/// Declares per-CPU variables.
+///
+/// ```ignore
+/// percpu! {
+///     static IRQS: AtomicU64 = AtomicU64::new(0);
+/// }
+///
+/// IRQS.get().fetch_add(1, Ordering::Relaxed);
+/// ```
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)+) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::percpu::PerCpu<$ty> =
+                $crate::percpu::PerCpu::new([const { $init }; $crate::percpu::MAX_CPUS]);
+        )+
+    };
+}
+
+/// A variable with one instance per CPU, see [`percpu!`](crate::percpu).
+pub struct PerCpu<T> {
+    slots: [T; MAX_CPUS],
+}
+
+// SAFETY: a CPU only reaches its own instance, and only with interrupts
+// disabled unless `T` is `Sync`.
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    #[doc(hidden)]
+    pub const fn new(slots: [T; MAX_CPUS]) -> Self {
+        Self { slots }
+    }
+
+    /// Calls `f` with the instance of the executing CPU. Interrupts are
+    /// disabled meanwhile, so handlers on this CPU cannot get at it.
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        interrupts::without_interrupts(|| f(&self.slots[cpu_index()]))
+    }
+}
+
+impl<T: Sync> PerCpu<T> {
+    /// The instance of the executing CPU, for types that can be shared.
+    pub fn get(&self) -> &T {
+        &self.slots[cpu_index()]
+    }
+}
+
+/// The data block of a CPU.
+#[repr(C)]
+pub struct CpuLocal {
+    /// Address of this block, so that `gs:[0]` yields it.
+    this: AtomicU64,
+    /// Stack pointer to switch to on entry from user mode.
+    kernel_stack: AtomicU64,
+    /// Scratch slot for the user stack pointer on entry from user mode.
+    user_stack: AtomicU64,
+    index: AtomicUsize,
+    apic_id: AtomicU32,
+    online: AtomicBool,
+    tss: AtomicPtr<TaskStateSegment>,
+    current_thread: AtomicU64,
+    current_task: AtomicU64,
+    /// Task-local values of the running thread, see [`crate::task::local`].
+    task_locals: AtomicUsize,
+    /// Panic recovery point of the running thread, see
+    /// [`crate::task::recover`].
+    landing: AtomicUsize,
+}
+
+impl CpuLocal {
+    const fn new() -> Self {
+        Self {
+            this: AtomicU64::new(0),
+            kernel_stack: AtomicU64::new(0),
+            user_stack: AtomicU64::new(0),
+            index: AtomicUsize::new(0),
+            apic_id: AtomicU32::new(0),
+            online: AtomicBool::new(false),
+            tss: AtomicPtr::new(ptr::null_mut()),
+            current_thread: AtomicU64::new(NONE),
+            current_task: AtomicU64::new(NONE),
+            task_locals: AtomicUsize::new(0),
+            landing: AtomicUsize::new(0),
+        }
+    }
+
+    /// Index of the CPU: 0 for the boot CPU, then in start-up order.
+    pub fn index(&self) -> usize {
+        self.index.load(Ordering::Relaxed)
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    /// Whether the CPU runs with its local APIC enabled and takes IPIs.
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn set_online(&self) {
+        self.online.store(true, Ordering::Release);
+    }
+
+    pub fn tss(&self) -> Option<&'static TaskStateSegment> {
+        unsafe { self.tss.load(Ordering::Acquire).as_ref() }
+    }
+
+    pub(crate) fn set_tss(&self, tss: &'static TaskStateSegment) {
+        self.tss
+            .store(tss as *const _ as *mut TaskStateSegment, Ordering::Release);
+        self.kernel_stack
+            .store(tss.privilege_stack_table[0].as_u64(), Ordering::Relaxed);
+    }
+
+    /// Top of the stack that interrupts and system calls from user mode
+    /// switch to.
+    pub fn kernel_stack(&self) -> VirtAddr {
+        VirtAddr::new(self.kernel_stack.load(Ordering::Relaxed))
+    }
+
+    /// Sets the stack for entries from user mode, here and as the ring 0
+    /// stack in the TSS. Only for the executing CPU.
+    pub(crate) fn set_kernel_stack(&self, top: VirtAddr) {
+        self.kernel_stack.store(top.as_u64(), Ordering::Relaxed);
+        let tss = self.tss.load(Ordering::Acquire);
+        if !tss.is_null() {
+            // The CPU only reads the ring 0 stack on entries from user
+            // mode, and this CPU is in kernel mode.
+            unsafe { ptr::addr_of_mut!((*tss).privilege_stack_table[0]).write_volatile(top) };
+        }
+    }
+
+    /// ID of the kernel thread running on this CPU.
+    pub fn current_thread(&self) -> Option<u64> {
+        from_id(self.current_thread.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set_current_thread(&self, id: Option<u64>) {
+        self.current_thread
+            .store(id.unwrap_or(NONE), Ordering::Relaxed);
+    }
+
+    /// ID of the async task being polled on this CPU.
+    pub fn current_task(&self) -> Option<u64> {
+        from_id(self.current_task.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set_current_task(&self, id: Option<u64>) {
+        self.current_task
+            .store(id.unwrap_or(NONE), Ordering::Relaxed);
+    }
+
+    /// Task-local values of the running thread. Saved and restored by the
+    /// scheduler on every thread switch.
+    pub(crate) fn task_locals(&self) -> usize {
+        self.task_locals.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_task_locals(&self, head: usize) {
+        self.task_locals.store(head, Ordering::Relaxed);
+    }
+
+    /// Panic recovery point of the running thread. Saved and restored by
+    /// the scheduler on every thread switch.
+    pub(crate) fn landing(&self) -> usize {
+        self.landing.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_landing(&self, landing: usize) {
+        self.landing.store(landing, Ordering::Relaxed);
+    }
+}
+
+fn from_id(id: u64) -> Option<u64> {
+    (id != NONE).then_some(id)
+}
+
+static CPUS: [CpuLocal; MAX_CPUS] = [const { CpuLocal::new() }; MAX_CPUS];
+
+/// Set once the boot CPU's `GS_BASE` is valid. Until then every access
+/// goes to block 0.
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Points `GS_BASE` of the executing CPU at the block for CPU `index`. Must
+/// be the first thing a CPU does, before it takes any lock.
+pub fn init(index: usize) {
+    assert!(index < MAX_CPUS, "CPU index {} out of range", index);
+    let block = &CPUS[index];
+    block
+        .this
+        .store(block as *const CpuLocal as u64, Ordering::Relaxed);
+    block.index.store(index, Ordering::Relaxed);
+    block.apic_id.store(crate::apic::id(), Ordering::Relaxed);
+    GsBase::write(VirtAddr::from_ptr(block));
+    KernelGsBase::write(VirtAddr::zero());
+    READY.store(true, Ordering::Release);
+}
+
+/// The data block of the executing CPU.
+pub fn current() -> &'static CpuLocal {
+    if !READY.load(Ordering::Acquire) {
+        return &CPUS[0];
+    }
+    let block: *const CpuLocal;
+    unsafe {
+        asm!(
+            "mov {}, gs:[0]",
+            out(reg) block,
+            options(nostack, readonly, preserves_flags)
+        );
+        &*block
+    }
+}
+
+/// Index of the executing CPU, see [`CpuLocal::index`].
+pub fn cpu_index() -> usize {
+    current().index()
+}
+
+/// The data block of CPU `index`.
+pub fn cpu(index: usize) -> &'static CpuLocal {
+    &CPUS[index]
+}
+
+#[test_case]
+fn test_boot_cpu_block() {
+    use core::cell::Cell;
+
+    crate::percpu! {
+        static COUNTER: Cell<u32> = Cell::new(0);
+    }
+
+    let cpu = current();
+    assert_eq!(cpu.index(), 0);
+    assert!(ptr::eq(cpu, self::cpu(0)));
+    assert!(cpu.tss().is_some());
+    COUNTER.with(|counter| counter.set(counter.get() + 1));
+    assert_eq!(COUNTER.with(Cell::get), 1);
+}
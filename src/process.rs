@@ -0,0 +1,404 @@
+//! Processes: user programs with their own address space.
+//!
+//! [`spawn`] starts a loaded [`Program`] on a thread of its own. A process
+//! that has finished stays in the table as a zombie with its
+//! [`ExitStatus`] until its parent reaps it with [`wait`] or [`wait_any`].
+//! Children of a finished process are handed to the kernel, which is the
+//! parent of every process spawned outside a process and has the PID
+//! [`Pid::KERNEL`]. User code starts and reaps children with the
+//! [`SPAWN`](crate::syscall::SPAWN) and [`WAIT`](crate::syscall::WAIT)
+//! system calls, and writes through its handles with
+//! [`WRITE`](crate::syscall::WRITE).
+//!
+//! A process whose user code raises a page fault, general protection fault,
+//! divide error, invalid opcode or breakpoint exception is killed; the
+//! kernel keeps running.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::fmt;
+use x86_64::{structures::idt::PageFaultErrorCode, VirtAddr};
+
+use crate::elf::Program;
+use crate::sync::IrqSafeMutex;
+use crate::thread::{self, ThreadId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    /// The kernel, which is not in the process table.
+    pub const KERNEL: Pid = Pid(0);
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+
+    pub(crate) fn from_u64(pid: u64) -> Self {
+        Pid(pid)
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The program made the exit system call with this code.
+    Exited(u64),
+    /// Killed for a page fault in user mode.
+    PageFault {
+        address: VirtAddr,
+        instruction: VirtAddr,
+        error_code: PageFaultErrorCode,
+    },
+    /// Killed for a general protection fault in user mode.
+    GeneralProtection {
+        instruction: VirtAddr,
+        error_code: u64,
+    },
+    /// Killed for dividing by zero, or a quotient too large.
+    DivideError { instruction: VirtAddr },
+    /// Killed for an undefined or invalid instruction.
+    InvalidOpcode { instruction: VirtAddr },
+    /// Killed for a breakpoint; `instruction` is the one after the `int3`.
+    Breakpoint { instruction: VirtAddr },
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExitStatus::Exited(code) => write!(f, "exited with code {}", code),
+            ExitStatus::PageFault {
+                address,
+                instruction,
+                error_code,
+            } => write!(
+                f,
+                "page fault at {:?} by instruction at {:?} ({:?})",
+                address, instruction, error_code
+            ),
+            ExitStatus::GeneralProtection {
+                instruction,
+                error_code,
+            } => write!(
+                f,
+                "general protection fault by instruction at {:?} (error code {:#x})",
+                instruction, error_code
+            ),
+            ExitStatus::DivideError { instruction } => {
+                write!(f, "divide error by instruction at {:?}", instruction)
+            }
+            ExitStatus::InvalidOpcode { instruction } => {
+                write!(f, "invalid opcode at {:?}", instruction)
+            }
+            ExitStatus::Breakpoint { instruction } => {
+                write!(f, "breakpoint before {:?}", instruction)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitError {
+    /// The process does not exist or is not a child of the caller.
+    NotAChild,
+    /// The caller has no children to wait for.
+    NoChildren,
+}
+
+impl fmt::Display for WaitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WaitError::NotAChild => write!(f, "not a child process"),
+            WaitError::NoChildren => write!(f, "no child processes"),
+        }
+    }
+}
+
+/// Something a process can access through a handle number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Handle {
+    Console,
+}
+
+/// Handle numbers every process starts with: standard output and error.
+pub const INITIAL_HANDLES: [u64; 2] = [1, 2];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    /// Finished, but not yet reaped by the parent.
+    Zombie(ExitStatus),
+}
+
+#[derive(Debug)]
+pub struct Process {
+    pid: Pid,
+    parent: Pid,
+    state: State,
+    /// `None` once the process has finished.
+    program: Option<Arc<Program>>,
+    threads: Vec<ThreadId>,
+    handles: BTreeMap<u64, Handle>,
+    /// Why the process was killed, while its thread is on its way out.
+    killed: Option<ExitStatus>,
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn parent(&self) -> Pid {
+        self.parent
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// The program the process runs, and with it its address space.
+    pub fn program(&self) -> Option<&Program> {
+        self.program.as_deref()
+    }
+
+    pub fn threads(&self) -> &[ThreadId] {
+        &self.threads
+    }
+
+    pub fn handles(&self) -> &BTreeMap<u64, Handle> {
+        &self.handles
+    }
+
+    /// Makes `handle` accessible under the lowest free number and returns
+    /// the number.
+    pub fn open(&mut self, handle: Handle) -> u64 {
+        let number = (0..)
+            .find(|number| !self.handles.contains_key(number))
+            .unwrap();
+        self.handles.insert(number, handle);
+        number
+    }
+
+    pub fn close(&mut self, number: u64) -> Option<Handle> {
+        self.handles.remove(&number)
+    }
+}
+
+struct Table {
+    processes: BTreeMap<Pid, Process>,
+    /// The process of every thread that runs one.
+    threads: BTreeMap<ThreadId, Pid>,
+    /// Threads in [`wait`] or [`wait_any`], by the process they wait as.
+    waiters: Vec<(Pid, ThreadId)>,
+    next_pid: u64,
+}
+
+static TABLE: IrqSafeMutex<Table> = IrqSafeMutex::new(Table {
+    processes: BTreeMap::new(),
+    threads: BTreeMap::new(),
+    waiters: Vec::new(),
+    next_pid: 1,
+});
+
+impl Table {
+    fn current(&self) -> Pid {
+        thread::try_current()
+            .and_then(|thread| self.threads.get(&thread).copied())
+            .unwrap_or(Pid::KERNEL)
+    }
+
+    fn wake_waiters(&mut self, parent: Pid) {
+        self.waiters.retain(|&(pid, thread)| {
+            if pid == parent {
+                thread::unpark(thread);
+            }
+            pid != parent
+        });
+    }
+
+    /// Turns `pid` into a zombie and hands its children to the kernel.
+    fn finish(&mut self, pid: Pid, status: ExitStatus) {
+        let process = self.processes.get_mut(&pid).expect("process vanished");
+        process.state = State::Zombie(status);
+        process.program = None;
+        process.handles.clear();
+        let parent = process.parent;
+
+        let mut orphans = false;
+        for child in self.processes.values_mut() {
+            if child.parent == pid {
+                child.parent = Pid::KERNEL;
+                orphans = true;
+            }
+        }
+        self.wake_waiters(parent);
+        if orphans {
+            self.wake_waiters(Pid::KERNEL);
+        }
+    }
+
+    /// Removes a zombie child of `parent`, `pid` or any. `Ok(None)` means
+    /// there are children, but none has finished.
+    fn reap(
+        &mut self,
+        parent: Pid,
+        pid: Option<Pid>,
+    ) -> Result<Option<(Pid, ExitStatus)>, WaitError> {
+        let mut children = self
+            .processes
+            .values()
+            .filter(|process| process.parent == parent && pid.is_none_or(|pid| process.pid == pid))
+            .peekable();
+        if children.peek().is_none() {
+            return Err(match pid {
+                Some(_) => WaitError::NotAChild,
+                None => WaitError::NoChildren,
+            });
+        }
+        let zombie = children.find_map(|process| match process.state {
+            State::Zombie(status) => Some((process.pid, status)),
+            State::Running => None,
+        });
+        if let Some((pid, _)) = zombie {
+            self.processes.remove(&pid);
+        }
+        Ok(zombie)
+    }
+}
+
+/// Starts `program` as a child of the current process.
+///
+/// # Panics
+/// Panics if [`thread::init`] has not been called.
+pub fn spawn(program: Program) -> Pid {
+    let program = Arc::new(program);
+    let pid = {
+        let mut table = TABLE.lock();
+        let pid = Pid(table.next_pid);
+        table.next_pid += 1;
+        let parent = table.current();
+        table.processes.insert(
+            pid,
+            Process {
+                pid,
+                parent,
+                state: State::Running,
+                program: Some(program.clone()),
+                threads: Vec::new(),
+                handles: INITIAL_HANDLES
+                    .iter()
+                    .map(|&number| (number, Handle::Console))
+                    .collect(),
+                killed: None,
+            },
+        );
+        pid
+    };
+    // Detached; the process table tracks the thread.
+    thread::spawn(move || {
+        attach(pid);
+        let code = program.run();
+        drop(program);
+        detach(pid, ExitStatus::Exited(code));
+    });
+    pid
+}
+
+fn attach(pid: Pid) {
+    let thread = thread::current();
+    let mut table = TABLE.lock();
+    table.threads.insert(thread, pid);
+    let process = table.processes.get_mut(&pid).expect("process vanished");
+    process.threads.push(thread);
+}
+
+/// Called by the thread of `pid` when it is done. The process finishes
+/// with `status`, unless it was killed.
+fn detach(pid: Pid, status: ExitStatus) {
+    let thread = thread::current();
+    let mut table = TABLE.lock();
+    table.threads.remove(&thread);
+    let process = table.processes.get_mut(&pid).expect("process vanished");
+    process.threads.retain(|&id| id != thread);
+    let status = process.killed.take().unwrap_or(status);
+    if !process.threads.is_empty() {
+        return;
+    }
+    // The address space goes before the parent can reap the process. Freeing
+    // it takes the kernel memory lock, which must not be taken with the
+    // table locked.
+    let program = process.program.take();
+    drop(table);
+    drop(program);
+    TABLE.lock().finish(pid, status);
+}
+
+/// Marks the process of the current thread as killed with `status`.
+/// Returns its PID, or `None` if the thread does not run a process. The
+/// caller must then leave user mode through
+/// [`return_to_kernel`](crate::user::return_to_kernel).
+pub(crate) fn kill_current(status: ExitStatus) -> Option<Pid> {
+    let thread = thread::try_current()?;
+    let mut table = TABLE.lock();
+    let pid = *table.threads.get(&thread)?;
+    let process = table.processes.get_mut(&pid)?;
+    process.killed.get_or_insert(status);
+    Some(pid)
+}
+
+/// The process of the running thread, [`Pid::KERNEL`] outside of one.
+pub fn current() -> Pid {
+    TABLE.lock().current()
+}
+
+/// The handle `number` of the current process. Code outside a process has
+/// the [`INITIAL_HANDLES`], on the console.
+pub fn handle(number: u64) -> Option<Handle> {
+    let table = TABLE.lock();
+    match table.processes.get(&table.current()) {
+        Some(process) => process.handles.get(&number).cloned(),
+        None => INITIAL_HANDLES.contains(&number).then_some(Handle::Console),
+    }
+}
+
+/// Runs `f` on the process `pid`, if it is in the table.
+pub fn with_process<R>(pid: Pid, f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    TABLE.lock().processes.get_mut(&pid).map(f)
+}
+
+/// The PIDs of all processes in the table, zombies included.
+pub fn pids() -> Vec<Pid> {
+    TABLE.lock().processes.keys().copied().collect()
+}
+
+/// Blocks until the child `pid` of the current process has finished, reaps
+/// it and returns its exit status.
+pub fn wait(pid: Pid) -> Result<ExitStatus, WaitError> {
+    wait_for(Some(pid)).map(|(_, status)| status)
+}
+
+/// Blocks until any child of the current process has finished, reaps it
+/// and returns its PID and exit status.
+pub fn wait_any() -> Result<(Pid, ExitStatus), WaitError> {
+    wait_for(None)
+}
+
+fn wait_for(pid: Option<Pid>) -> Result<(Pid, ExitStatus), WaitError> {
+    let thread = thread::current();
+    loop {
+        {
+            let mut table = TABLE.lock();
+            let parent = table.current();
+            if let Some(reaped) = table.reap(parent, pid)? {
+                table.waiters.retain(|&(_, waiter)| waiter != thread);
+                return Ok(reaped);
+            }
+            table.waiters.push((parent, thread));
+        }
+        thread::park();
+    }
+}
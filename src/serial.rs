@@ -1,26 +1,26 @@
+use crate::sync::IrqSafeMutex;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use uart_16550::SerialPort;
 
+/// I/O port base of the first serial port.
+pub(crate) const COM1: u16 = 0x3F8;
+
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+    pub static ref SERIAL1: IrqSafeMutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqSafeMutex::new(serial_port)
     };
 }
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
-    });
+    SERIAL1
+        .lock()
+        .write_fmt(args)
+        .expect("Printing to serial failed");
 }
 
 #[macro_export]
@@ -0,0 +1,208 @@
+//! Starting the application processors (APs).
+//!
+//! [`init`] finds the CPUs in the ACPI MADT and wakes every AP with an
+//! INIT-SIPI-SIPI sequence, one at a time. Each AP runs the trampoline into
+//! long mode, loads a GDT and TSS of its own and the shared IDT, enables its
+//! local APIC and then calls the entry function it was started with.
+//!
+//! Device interrupts, the clock and kernel threads stay on the boot CPU.
+
+use alloc::alloc::{alloc, dealloc, handle_alloc_error, Layout};
+use conquer_once::spin::OnceCell;
+use core::{
+    fmt, ptr,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::acpi::{self, AcpiError};
+use crate::time::Instant;
+use crate::{apic, gdt, interrupts, percpu, syscall};
+
+mod trampoline;
+
+use trampoline::{TrampolineData, TRAMPOLINE_ADDR};
+
+/// Kernel stack size of each AP. The stacks live on the heap and have no
+/// guard page.
+pub const AP_STACK_SIZE: usize = 4096 * 4;
+
+const INIT_DELAY: Duration = Duration::from_millis(10);
+const STARTUP_DELAY: Duration = Duration::from_micros(200);
+const START_TIMEOUT: Duration = Duration::from_millis(100);
+
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+/// Set by the AP being started once it no longer needs the trampoline.
+static STARTED: AtomicBool = AtomicBool::new(false);
+static AP_ENTRY: OnceCell<fn(usize) -> !> = OnceCell::uninit();
+
+#[derive(Debug)]
+pub enum SmpError {
+    Acpi(AcpiError),
+    Map(MapToError<Size4KiB>),
+}
+
+impl fmt::Display for SmpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SmpError::Acpi(err) => write!(f, "{}", err),
+            SmpError::Map(err) => write!(f, "mapping the AP trampoline failed: {:?}", err),
+        }
+    }
+}
+
+/// Number of CPUs running, including the boot CPU.
+pub fn online_cpus() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Starts all APs listed in the MADT. Each calls `entry` with its CPU
+/// index, counting from 1, and interrupts enabled. Returns the number of
+/// CPUs online afterwards; APs that do not respond are left out. One that
+/// responds too late halts in the trampoline without touching anything.
+///
+/// Needs the heap and [`apic::init`]. May only be called once.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    physical_memory_offset: VirtAddr,
+    entry: fn(usize) -> !,
+) -> Result<usize, SmpError> {
+    assert!(apic::is_initialized(), "smp::init needs the local APIC");
+    AP_ENTRY.init_once(|| entry);
+    let processors = acpi::processors(physical_memory_offset).map_err(SmpError::Acpi)?;
+
+    let data = install_trampoline(mapper, frame_allocator, physical_memory_offset)?;
+    let (p4, _) = Cr3::read();
+    let cr3 = p4.start_address().as_u64();
+    assert!(cr3 < 1 << 32, "page tables above 4 GiB");
+
+    let bsp = apic::id();
+    let layout = Layout::from_size_align(AP_STACK_SIZE, 16).unwrap();
+    let mut next_cpu = 1;
+    // A stack stays here until an AP claims the block that points at it.
+    let mut stack = None;
+    for processor in processors.iter().filter(|p| p.apic_id != bsp) {
+        if next_cpu == percpu::MAX_CPUS {
+            break;
+        }
+        let base = *stack.get_or_insert_with(|| {
+            let base = unsafe { alloc(layout) };
+            if base.is_null() {
+                handle_alloc_error(layout);
+            }
+            base
+        });
+        let claim = unsafe {
+            data.write_volatile(TrampolineData {
+                cr3,
+                stack_top: base as u64 + AP_STACK_SIZE as u64,
+                entry: ap_start as extern "C" fn(u64) -> ! as usize as u64,
+                cpu: next_cpu as u64,
+                claim: AtomicU64::new(0),
+            });
+            &(*data).claim
+        };
+        claim.store(1, Ordering::Release);
+        if start_ap(processor.apic_id, claim) {
+            next_cpu += 1;
+            stack = None;
+        }
+    }
+    if let Some(base) = stack {
+        unsafe { dealloc(base, layout) };
+    }
+    Ok(online_cpus())
+}
+
+/// Copies the trampoline to [`TRAMPOLINE_ADDR`] and returns its parameter
+/// block.
+fn install_trampoline(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    physical_memory_offset: VirtAddr,
+) -> Result<*mut TrampolineData, SmpError> {
+    use trampoline::{ap_trampoline_data, ap_trampoline_end, ap_trampoline_start};
+
+    let start = ptr::addr_of!(ap_trampoline_start);
+    let len = ptr::addr_of!(ap_trampoline_end) as usize - start as usize;
+    let data_offset = ptr::addr_of!(ap_trampoline_data) as usize - start as usize;
+    assert!(len <= 4096, "AP trampoline larger than a page");
+
+    // The AP keeps executing the trampoline right after it enables paging.
+    let frame = PhysFrame::containing_address(PhysAddr::new(TRAMPOLINE_ADDR));
+    match unsafe { mapper.identity_map(frame, PageTableFlags::PRESENT, frame_allocator) } {
+        Ok(flush) => flush.flush(),
+        Err(MapToError::PageAlreadyMapped(_)) => {}
+        Err(err) => return Err(SmpError::Map(err)),
+    }
+
+    let copy: *mut u8 = (physical_memory_offset + TRAMPOLINE_ADDR).as_mut_ptr();
+    unsafe {
+        ptr::copy_nonoverlapping(start, copy, len);
+        Ok(copy.add(data_offset).cast())
+    }
+}
+
+/// Sends the INIT-SIPI-SIPI sequence and waits for the AP to come up.
+///
+/// On a timeout the block is withdrawn by swapping `claim` back to zero. If
+/// the AP got there first it is already running on the block's stack and
+/// index, so it is waited for instead of being given up on.
+fn start_ap(apic_id: u32, claim: &AtomicU64) -> bool {
+    STARTED.store(false, Ordering::Release);
+    apic::send_init(apic_id);
+    delay(INIT_DELAY);
+    for _ in 0..2 {
+        apic::send_startup(apic_id, (TRAMPOLINE_ADDR >> 12) as u8);
+        delay(STARTUP_DELAY);
+        if STARTED.load(Ordering::Acquire) {
+            return true;
+        }
+    }
+    let deadline = Instant::now() + START_TIMEOUT;
+    while Instant::now() < deadline {
+        if STARTED.load(Ordering::Acquire) {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    if claim.swap(0, Ordering::AcqRel) != 0 {
+        return false;
+    }
+    while !STARTED.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    true
+}
+
+fn delay(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// First Rust code an AP runs, on its own stack.
+extern "C" fn ap_start(cpu: u64) -> ! {
+    percpu::init(cpu as usize);
+    gdt::init_ap();
+    syscall::init();
+    interrupts::init_idt();
+    interrupts::mce::init();
+    apic::init_cpu();
+
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+    STARTED.store(true, Ordering::Release);
+
+    let entry = *AP_ENTRY.get().expect("AP started without an entry");
+    x86_64::instructions::interrupts::enable();
+    entry(cpu as usize)
+}
@@ -0,0 +1,127 @@
+//! Real-mode entry code of the application processors.
+//!
+//! A startup IPI starts an AP in 16-bit real mode at a page below 1 MiB.
+//! [`ap_trampoline_start`]..[`ap_trampoline_end`] is copied to
+//! [`TRAMPOLINE_ADDR`], switches through protected mode into long mode with
+//! the kernel's page tables, and calls the entry point stored in the
+//! [`TrampolineData`] block with the stack stored there.
+//!
+//! An AP first claims the block by swapping [`TrampolineData::claim`] to
+//! zero. One that finds it zero arrived after the boot CPU gave up on it, or
+//! after another AP took the block, and halts in real mode for good.
+
+use core::{arch::global_asm, sync::atomic::AtomicU64};
+
+/// Physical (and identity-mapped virtual) address the trampoline runs at.
+pub const TRAMPOLINE_ADDR: u64 = 0x8000;
+
+/// Parameters for the next AP, at `ap_trampoline_data` in the copy.
+#[repr(C)]
+pub struct TrampolineData {
+    /// Must be below 4 GiB; loaded while still in 32-bit mode.
+    pub cr3: u64,
+    pub stack_top: u64,
+    pub entry: u64,
+    /// Passed to `entry` as its first argument.
+    pub cpu: u64,
+    /// Non-zero while the block is up for grabs. Set last by the boot CPU.
+    pub claim: AtomicU64,
+}
+
+extern "C" {
+    pub static ap_trampoline_start: u8;
+    pub static ap_trampoline_data: u8;
+    pub static ap_trampoline_end: u8;
+}
+
+global_asm!(
+    ".pushsection .rodata.ap_trampoline, \"a\"",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_data",
+    ".global ap_trampoline_end",
+    ".code16",
+    "ap_trampoline_start:",
+    "cli",
+    "cld",
+    "xor ax, ax",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "xor eax, eax",
+    "xchg eax, dword ptr [AP_CLAIM]",
+    "test eax, eax",
+    "jnz ap_claimed",
+    "ap_park:",
+    "hlt",
+    "jmp ap_park",
+    "ap_claimed:",
+    "lgdt [AP_GDT_PTR]",
+    "mov eax, cr0",
+    "or eax, 1",
+    "mov cr0, eax",
+    // jmp far 0x08:ap_protected, with a 32-bit offset
+    ".byte 0x66, 0xea",
+    ".long ap_protected - ap_trampoline_start + {base}",
+    ".word 0x08",
+    ".code32",
+    "ap_protected:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    // PAE
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov eax, [AP_CR3]",
+    "mov cr3, eax",
+    // EFER: long mode and no-execute, which the kernel's page tables use
+    "mov ecx, 0xc0000080",
+    "rdmsr",
+    "or eax, (1 << 8) | (1 << 11)",
+    "wrmsr",
+    // paging and write protection
+    "mov eax, cr0",
+    "or eax, (1 << 31) | (1 << 16)",
+    "mov cr0, eax",
+    // jmp far 0x18:ap_long_mode
+    ".byte 0xea",
+    ".long ap_long_mode - ap_trampoline_start + {base}",
+    ".word 0x18",
+    ".code64",
+    "ap_long_mode:",
+    "xor ax, ax",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov rsp, [AP_STACK_TOP]",
+    "mov rdi, [AP_CPU]",
+    "mov rax, [AP_ENTRY]",
+    "call rax",
+    "ud2",
+    ".balign 8",
+    "ap_trampoline_data:",
+    "ap_cr3: .quad 0",
+    "ap_stack_top: .quad 0",
+    "ap_entry: .quad 0",
+    "ap_cpu: .quad 0",
+    "ap_claim: .quad 0",
+    "ap_gdt:",
+    ".quad 0",
+    ".quad 0x00cf9a000000ffff", // 32-bit code
+    ".quad 0x00cf92000000ffff", // data
+    ".quad 0x00af9a000000ffff", // 64-bit code
+    "ap_gdt_ptr:",
+    ".word ap_gdt_ptr - ap_gdt - 1",
+    ".long ap_gdt - ap_trampoline_start + {base}",
+    "ap_trampoline_end:",
+    // absolute addresses in the copy
+    ".set AP_GDT_PTR, ap_gdt_ptr - ap_trampoline_start + {base}",
+    ".set AP_CR3, ap_cr3 - ap_trampoline_start + {base}",
+    ".set AP_STACK_TOP, ap_stack_top - ap_trampoline_start + {base}",
+    ".set AP_ENTRY, ap_entry - ap_trampoline_start + {base}",
+    ".set AP_CPU, ap_cpu - ap_trampoline_start + {base}",
+    ".set AP_CLAIM, ap_claim - ap_trampoline_start + {base}",
+    ".popsection",
+    base = const TRAMPOLINE_ADDR,
+);
@@ -0,0 +1,249 @@
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    ops::{Deref, DerefMut},
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+use x86_64::instructions::interrupts;
+
+const NO_OWNER: usize = usize::MAX;
+
+/// Most locks a CPU tracks as held at once, see [`held`].
+const MAX_HELD: usize = 16;
+
+/// A spinlock that keeps interrupts disabled while it is held.
+///
+/// State shared with interrupt handlers must use this instead of
+/// `spin::Mutex`: otherwise a handler that takes the lock while the
+/// interrupted code holds it spins forever. Locking it twice on the same CPU
+/// is always a deadlock and panics instead.
+pub struct IrqSafeMutex<T: ?Sized> {
+    raw: RawLock,
+    data: UnsafeCell<T>,
+}
+
+// SAFETY: the lock hands out access to `data` to one holder at a time.
+unsafe impl<T: ?Sized + Send> Sync for IrqSafeMutex<T> {}
+unsafe impl<T: ?Sized + Send> Send for IrqSafeMutex<T> {}
+
+/// The part of an [`IrqSafeMutex`] that does not depend on `T`, so that
+/// the locks a CPU holds can be tracked.
+struct RawLock {
+    owner: AtomicUsize,
+    locked: AtomicBool,
+}
+
+impl RawLock {
+    fn try_acquire(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn release(&self) {
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+pub struct IrqSafeMutexGuard<'a, T: ?Sized> {
+    mutex: &'a IrqSafeMutex<T>,
+    interrupts_were_enabled: bool,
+}
+
+impl<T> IrqSafeMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            raw: RawLock {
+                owner: AtomicUsize::new(NO_OWNER),
+                locked: AtomicBool::new(false),
+            },
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqSafeMutex<T> {
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, T> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        let cpu = current_cpu();
+        if self.raw.owner.load(Ordering::Relaxed) == cpu {
+            // Release the lock so that the panic handler can still print when
+            // the recursion was on the console lock itself.
+            unsafe { self.force_unlock() };
+            panic!(
+                "IrqSafeMutex<{}> locked recursively on CPU {}",
+                core::any::type_name::<T>(),
+                cpu
+            );
+        }
+
+        while !self.raw.try_acquire() {
+            core::hint::spin_loop();
+        }
+        self.acquired(cpu, interrupts_were_enabled)
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<'_, T>> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        if self.raw.try_acquire() {
+            Some(self.acquired(current_cpu(), interrupts_were_enabled))
+        } else {
+            if interrupts_were_enabled {
+                interrupts::enable();
+            }
+            None
+        }
+    }
+
+    fn acquired(&self, cpu: usize, interrupts_were_enabled: bool) -> IrqSafeMutexGuard<'_, T> {
+        self.raw.owner.store(cpu, Ordering::Relaxed);
+        HELD.get().push(&self.raw);
+        IrqSafeMutexGuard {
+            mutex: self,
+            interrupts_were_enabled,
+        }
+    }
+
+    /// Forcibly releases the lock.
+    ///
+    /// # Safety
+    /// The current holder must never touch the data again, e.g. because it
+    /// was interrupted by an NMI and the system is going down.
+    pub unsafe fn force_unlock(&self) {
+        self.raw.release();
+    }
+}
+
+impl<T: Default> Default for IrqSafeMutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for IrqSafeMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f
+                .debug_struct("IrqSafeMutex")
+                .field("data", &&*guard)
+                .finish(),
+            None => f.write_str("IrqSafeMutex { <locked> }"),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for IrqSafeMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSafeMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSafeMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        HELD.get().remove(&self.mutex.raw);
+        self.mutex.raw.release();
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// The locks a CPU holds, in the order it took them. Interrupts are
+/// disabled while a lock is held, so they are all held by the code running
+/// on the CPU and not by a preempted thread.
+struct HeldLocks {
+    locks: [AtomicPtr<RawLock>; MAX_HELD],
+    /// May exceed `MAX_HELD`; the locks beyond it are not tracked.
+    len: AtomicUsize,
+}
+
+impl HeldLocks {
+    fn push(&self, lock: &RawLock) {
+        let len = self.len.fetch_add(1, Ordering::Relaxed);
+        if let Some(slot) = self.locks.get(len) {
+            slot.store(lock as *const RawLock as *mut RawLock, Ordering::Relaxed);
+        }
+    }
+
+    /// Guards can be dropped in any order, so `lock` need not be the last.
+    fn remove(&self, lock: &RawLock) {
+        let len = self.len.load(Ordering::Relaxed);
+        let tracked = len.min(MAX_HELD);
+        let lock = lock as *const RawLock as *mut RawLock;
+        if let Some(index) =
+            (0..tracked).rposition(|i| self.locks[i].load(Ordering::Relaxed) == lock)
+        {
+            for i in index..tracked - 1 {
+                let next = self.locks[i + 1].load(Ordering::Relaxed);
+                self.locks[i].store(next, Ordering::Relaxed);
+            }
+        }
+        // An untracked lock was released if none matched.
+        self.len.store(len - 1, Ordering::Relaxed);
+    }
+}
+
+crate::percpu! {
+    static HELD: HeldLocks = HeldLocks {
+        locks: [const { AtomicPtr::new(ptr::null_mut()) }; MAX_HELD],
+        len: AtomicUsize::new(0),
+    };
+}
+
+/// Number of [`IrqSafeMutex`]es the executing CPU holds, to be passed to
+/// [`release_held`].
+pub(crate) fn held() -> usize {
+    HELD.get().len.load(Ordering::Relaxed)
+}
+
+/// Releases the locks taken after [`held`] returned `depth`, whose guards
+/// were abandoned by a recovered panic.
+///
+/// # Safety
+/// The abandoned holders must never touch the data again.
+pub(crate) unsafe fn release_held(depth: usize) {
+    let held = HELD.get();
+    let len = held.len.load(Ordering::Relaxed);
+    for i in (depth..len.min(MAX_HELD)).rev() {
+        let lock = held.locks[i].load(Ordering::Relaxed);
+        (*lock).release();
+    }
+    held.len.store(depth.min(len), Ordering::Relaxed);
+}
+
+fn current_cpu() -> usize {
+    crate::percpu::cpu_index()
+}
+
+#[test_case]
+fn test_lock_disables_interrupts() {
+    let mutex = IrqSafeMutex::new(0);
+    assert!(interrupts::are_enabled());
+    {
+        let mut value = mutex.lock();
+        *value += 1;
+        assert!(!interrupts::are_enabled());
+        assert!(mutex.try_lock().is_none());
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(*mutex.lock(), 1);
+}
@@ -0,0 +1,327 @@
+//! System calls through `syscall` and `sysretq`.
+//!
+//! User code puts the call number in `rax` and the arguments in `rdi`,
+//! `rsi`, `rdx`, `r10`, `r8` and `r9`. The result comes back in `rax`: a
+//! value, or a [`SyscallError`] as its negated code. `rcx` and `r11` are
+//! clobbered, all other registers are preserved.
+//!
+//! | number | call | arguments | result |
+//! |---|---|---|---|
+//! | 0 | [`WRITE`] | handle, text, length | bytes written |
+//! | 1 | [`EXIT`] | exit code | does not return |
+//! | 2 | [`YIELD`] | | 0 |
+//! | 3 | [`SLEEP`] | milliseconds | 0 |
+//! | 4 | [`TIME`] | | nanoseconds since boot |
+//! | 5 | [`MMAP`] | address, length, [`MMAP_WRITE`] | address |
+//! | 6 | [`SPAWN`] | ELF image, length | PID of the child |
+//! | 7 | [`WAIT`] | PID of a child | its exit code |
+
+use core::{fmt, slice, str, time::Duration};
+use x86_64::{
+    registers::{
+        control::Cr3,
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+        Size4KiB,
+    },
+    VirtAddr,
+};
+
+use crate::elf::{self, ElfError};
+use crate::memory::address_space::{self, MapError};
+use crate::process::{self, ExitStatus, Handle, Pid, WaitError};
+use crate::time::Instant;
+use crate::user::{self, USER_SPACE_END, USER_SPACE_START};
+use crate::{gdt, memory, thread};
+
+mod entry;
+
+/// Writes UTF-8 text to a handle of the process.
+pub const WRITE: u64 = 0;
+/// Leaves user mode; [`user::enter_user_mode`] returns the exit code.
+pub const EXIT: u64 = 1;
+/// Lets other threads run.
+pub const YIELD: u64 = 2;
+pub const SLEEP: u64 = 3;
+pub const TIME: u64 = 4;
+/// Maps zeroed memory at a page-aligned address in user memory.
+pub const MMAP: u64 = 5;
+
+/// Starts an ELF executable as a child of the process, without arguments.
+pub const SPAWN: u64 = 6;
+/// Waits for a child to exit and reaps it.
+pub const WAIT: u64 = 7;
+
+/// Flag for [`MMAP`]: make the memory writable.
+pub const MMAP_WRITE: u64 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum SyscallError {
+    NoSuchCall = 1,
+    /// A buffer is not in mapped user memory.
+    BadAddress = 2,
+    InvalidArgument = 3,
+    OutOfMemory = 4,
+    /// The process has no such handle, or cannot use it like that.
+    BadHandle = 5,
+    /// The PID is not that of a child of the process.
+    NotAChild = 6,
+    /// The child was killed rather than exiting.
+    ChildKilled = 7,
+}
+
+impl SyscallError {
+    /// The value user code sees in `rax`.
+    pub fn code(self) -> u64 {
+        (self as u64).wrapping_neg()
+    }
+
+    /// Splits a system call's result into value and error.
+    pub fn decode(result: u64) -> Result<u64, SyscallError> {
+        match result.wrapping_neg() {
+            1 => Err(SyscallError::NoSuchCall),
+            2 => Err(SyscallError::BadAddress),
+            3 => Err(SyscallError::InvalidArgument),
+            4 => Err(SyscallError::OutOfMemory),
+            5 => Err(SyscallError::BadHandle),
+            6 => Err(SyscallError::NotAChild),
+            7 => Err(SyscallError::ChildKilled),
+            _ => Ok(result),
+        }
+    }
+}
+
+impl From<MapError> for SyscallError {
+    fn from(err: MapError) -> Self {
+        match err {
+            MapError::NoKernelMemory | MapError::OutOfMemory => SyscallError::OutOfMemory,
+            _ => SyscallError::InvalidArgument,
+        }
+    }
+}
+
+impl From<ElfError> for SyscallError {
+    fn from(err: ElfError) -> Self {
+        match err {
+            ElfError::Map(err) => err.into(),
+            _ => SyscallError::InvalidArgument,
+        }
+    }
+}
+
+impl fmt::Display for SyscallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyscallError::NoSuchCall => write!(f, "no such system call"),
+            SyscallError::BadAddress => write!(f, "bad address"),
+            SyscallError::InvalidArgument => write!(f, "invalid argument"),
+            SyscallError::OutOfMemory => write!(f, "out of memory"),
+            SyscallError::BadHandle => write!(f, "bad handle"),
+            SyscallError::NotAChild => write!(f, "not a child process"),
+            SyscallError::ChildKilled => write!(f, "child process was killed"),
+        }
+    }
+}
+
+type Args = [u64; 6];
+type Handler = fn(Args) -> Result<u64, SyscallError>;
+
+/// Handlers by call number.
+static HANDLERS: [Handler; 8] = [
+    sys_write, sys_exit, sys_yield, sys_sleep, sys_time, sys_mmap, sys_spawn, sys_wait,
+];
+
+/// The user registers saved by the entry stub.
+#[repr(C)]
+struct SyscallFrame {
+    r9: u64,
+    r8: u64,
+    r10: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    number: u64,
+    /// `rcx`, `r11` and `rsp`: where and how the user code continues.
+    _user: [u64; 3],
+}
+
+/// Enables `syscall` on the executing CPU. The GDT must be loaded.
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.code_selector,
+        selectors.data_selector,
+    )
+    .expect("GDT layout does not suit sysret");
+    LStar::write(VirtAddr::new(
+        entry::syscall_entry as unsafe extern "C" fn() as usize as u64,
+    ));
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+/// Whether `instruction` is where a system call returns to user code
+/// through `iretq`. A fault there belongs to the user code.
+pub(crate) fn is_slow_return(instruction: VirtAddr) -> bool {
+    instruction.as_u64() == entry::syscall_iret as unsafe extern "C" fn() as usize as u64
+}
+
+extern "C" fn dispatch(frame: &SyscallFrame) -> u64 {
+    let args = [
+        frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+    ];
+    let result = match HANDLERS.get(frame.number as usize) {
+        Some(handler) => handler(args),
+        None => Err(SyscallError::NoSuchCall),
+    };
+    result.unwrap_or_else(SyscallError::code)
+}
+
+/// The `len` bytes at `addr`, if they are all in mapped user memory.
+fn user_bytes(addr: u64, len: u64) -> Result<&'static [u8], SyscallError> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    let end = addr
+        .checked_add(len)
+        .filter(|&end| end <= USER_SPACE_END)
+        .ok_or(SyscallError::BadAddress)?;
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(VirtAddr::new(addr)),
+        Page::containing_address(VirtAddr::new(end - 1)),
+    );
+    let accessible = pages.into_iter().all(|page| {
+        memory::effective_flags(page.start_address())
+            .is_some_and(|flags| flags.contains(PageTableFlags::USER_ACCESSIBLE))
+    });
+    if !accessible {
+        return Err(SyscallError::BadAddress);
+    }
+    Ok(unsafe { slice::from_raw_parts(addr as *const u8, len as usize) })
+}
+
+fn sys_write(args: Args) -> Result<u64, SyscallError> {
+    let [handle, addr, len, ..] = args;
+    match process::handle(handle).ok_or(SyscallError::BadHandle)? {
+        Handle::Console => {}
+    }
+    let text = str::from_utf8(user_bytes(addr, len)?).map_err(|_| SyscallError::InvalidArgument)?;
+    crate::print!("{}", text);
+    Ok(len)
+}
+
+fn sys_exit(args: Args) -> Result<u64, SyscallError> {
+    unsafe { user::return_to_kernel(args[0]) }
+}
+
+fn sys_yield(_args: Args) -> Result<u64, SyscallError> {
+    if thread::try_current().is_some() {
+        thread::yield_now();
+    }
+    Ok(0)
+}
+
+fn sys_sleep(args: Args) -> Result<u64, SyscallError> {
+    let duration = Duration::from_millis(args[0]);
+    let deadline = Instant::now()
+        .checked_add(duration)
+        .ok_or(SyscallError::InvalidArgument)?;
+    if thread::try_current().is_some() {
+        thread::sleep(duration);
+    } else {
+        while Instant::now() < deadline {
+            x86_64::instructions::hlt();
+        }
+    }
+    Ok(0)
+}
+
+fn sys_time(_args: Args) -> Result<u64, SyscallError> {
+    Ok(Instant::now().as_nanos())
+}
+
+fn sys_mmap(args: Args) -> Result<u64, SyscallError> {
+    let [addr, len, flags, ..] = args;
+    let end = addr.checked_add(len).filter(|&end| end <= USER_SPACE_END);
+    let end = match end {
+        Some(end)
+            if addr >= USER_SPACE_START
+                && addr % 4096 == 0
+                && len > 0
+                && flags & !MMAP_WRITE == 0 =>
+        {
+            end
+        }
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+    let first = Page::containing_address(VirtAddr::new(addr));
+    let last = Page::containing_address(VirtAddr::new(end - 1));
+    let mut page_flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if flags & MMAP_WRITE != 0 {
+        page_flags |= PageTableFlags::WRITABLE;
+    }
+    let (active, _) = Cr3::read();
+    if active != memory::kernel_page_table() {
+        address_space::map_active(Page::range_inclusive(first, last), page_flags)?;
+        return Ok(addr);
+    }
+
+    memory::with_kernel_memory(|mapper, frame_allocator| {
+        for page in Page::range_inclusive(first, last) {
+            let mapped = frame_allocator
+                .allocate_frame()
+                .ok_or(SyscallError::OutOfMemory)
+                .and_then(|frame| {
+                    let zero: *mut u8 =
+                        (mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr();
+                    unsafe { zero.write_bytes(0, 4096) };
+                    unsafe { mapper.map_to(page, frame, page_flags, frame_allocator) }
+                        .map(|flush| flush.flush())
+                        .map_err(|err| {
+                            unsafe { frame_allocator.deallocate_frame(frame) };
+                            match err {
+                                MapToError::FrameAllocationFailed => SyscallError::OutOfMemory,
+                                _ => SyscallError::InvalidArgument,
+                            }
+                        })
+                });
+            if let Err(err) = mapped {
+                if page != first {
+                    for mapped in Page::range_inclusive(first, page - 1) {
+                        let frame = memory::unmap(mapper, mapped)
+                            .expect("unmapping pages just mapped failed");
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                    }
+                }
+                return Err(err);
+            }
+        }
+        Ok(addr)
+    })
+    .unwrap_or(Err(SyscallError::OutOfMemory))
+}
+
+fn sys_spawn(args: Args) -> Result<u64, SyscallError> {
+    let image = user_bytes(args[0], args[1])?;
+    let program = elf::load(image, &[], &[])?;
+    Ok(process::spawn(program).as_u64())
+}
+
+fn sys_wait(args: Args) -> Result<u64, SyscallError> {
+    match process::wait(Pid::from_u64(args[0])) {
+        Ok(ExitStatus::Exited(code)) => Ok(code),
+        Ok(_) => Err(SyscallError::ChildKilled),
+        Err(WaitError::NotAChild | WaitError::NoChildren) => Err(SyscallError::NotAChild),
+    }
+}
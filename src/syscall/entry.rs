@@ -0,0 +1,95 @@
+//! The `syscall` entry stub.
+//!
+//! `sysretq` to a non-canonical `rcx` raises #GP in kernel mode but on the
+//! user's stack, on Intel CPUs. The only one user code can produce is
+//! [`USER_SPACE_END`], after a `syscall` at the very end of user memory, and
+//! the stub returns through `iretq` for anything at or above it. That
+//! `iretq` raises the #GP instead, on the kernel stack, and the #GP handler
+//! blames the user code for it (see [`syscall_iret`]).
+
+use core::arch::global_asm;
+
+use super::dispatch;
+use crate::percpu::{KERNEL_STACK_OFFSET, USER_STACK_OFFSET};
+use crate::user::USER_SPACE_END;
+
+extern "C" {
+    pub fn syscall_entry();
+    /// The `iretq` of the slow return path. A fault there is a fault of the
+    /// user code at the return address.
+    pub fn syscall_iret();
+}
+
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    // Interrupts are off (SFMASK) until we are on the kernel stack.
+    "swapgs",
+    "mov gs:[{user_stack}], rsp",
+    "mov rsp, gs:[{kernel_stack}]",
+    // SyscallFrame, from the last field to the first
+    "push qword ptr gs:[{user_stack}]",
+    "push r11",
+    "push rcx",
+    "push rax",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push r10",
+    "push r8",
+    "push r9",
+    "sti",
+    "mov rdi, rsp",
+    "call {dispatch}",
+    "cli",
+    // rcx and r11 are reloaded from the frame either way.
+    "mov rcx, {user_space_end}",
+    "cmp [rsp + 56], rcx",
+    "jae 1f",
+    "pop r9",
+    "pop r8",
+    "pop r10",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    // the call number; rax holds the result
+    "add rsp, 8",
+    "pop rcx",
+    "pop r11",
+    "pop rsp",
+    "swapgs",
+    "sysretq",
+    // iretq frame: ss, rsp, rflags, cs, rip. The user selectors follow the
+    // base in STAR[63:48] like they do for sysretq.
+    "1:",
+    "mov r11, rax",
+    "mov ecx, 0xc0000081",
+    "rdmsr",
+    "mov rax, r11",
+    "shr edx, 16",
+    "lea ecx, [rdx + 8]",
+    "or ecx, 3",
+    "push rcx",
+    "push qword ptr [rsp + 8 + 72]",
+    "push qword ptr [rsp + 16 + 64]",
+    "lea ecx, [rdx + 16]",
+    "or ecx, 3",
+    "push rcx",
+    "push qword ptr [rsp + 32 + 56]",
+    "mov r9, [rsp + 40]",
+    "mov r8, [rsp + 48]",
+    "mov r10, [rsp + 56]",
+    "mov rdx, [rsp + 64]",
+    "mov rsi, [rsp + 72]",
+    "mov rdi, [rsp + 80]",
+    "mov rcx, [rsp]",
+    "mov r11, [rsp + 16]",
+    "swapgs",
+    ".global syscall_iret",
+    "syscall_iret:",
+    "iretq",
+    user_space_end = const USER_SPACE_END,
+    user_stack = const USER_STACK_OFFSET,
+    kernel_stack = const KERNEL_STACK_OFFSET,
+    dispatch = sym dispatch,
+);
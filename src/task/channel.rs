@@ -0,0 +1,30 @@
+//! Channels for passing values between tasks.
+//!
+//! - [`mpsc`]: many senders, one receiver; bounded or unbounded.
+//! - [`oneshot`]: a single value from one sender to one receiver.
+//! - [`broadcast`]: every value goes to all receivers.
+//!
+//! The non-blocking send paths of bounded channels never allocate and can be
+//! used from interrupt handlers. Values dropped there must not own heap
+//! memory, though, since freeing it could deadlock on the allocator lock.
+
+pub mod broadcast;
+pub mod mpsc;
+pub mod oneshot;
+
+use alloc::vec::Vec;
+use core::task::Waker;
+
+/// Adds `waker` to `wakers` unless it would wake the same task as one that
+/// is already registered.
+fn register(wakers: &mut Vec<Waker>, waker: &Waker) {
+    if !wakers.iter().any(|w| w.will_wake(waker)) {
+        wakers.push(waker.clone());
+    }
+}
+
+fn wake_all(wakers: &mut Vec<Waker>) {
+    for waker in wakers.drain(..) {
+        waker.wake();
+    }
+}
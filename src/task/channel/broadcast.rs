@@ -0,0 +1,228 @@
+//! Channels that deliver every value to all receivers.
+//!
+//! Values are kept in a ring buffer of fixed capacity. A receiver that falls
+//! more than `capacity` values behind skips the overwritten ones and is told
+//! how many it missed through [`RecvError::Lagged`].
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    fmt,
+    future::poll_fn,
+    task::{Context, Poll, Waker},
+};
+
+use super::{register, wake_all};
+use crate::sync::IrqSafeMutex;
+use crate::task::coop;
+
+/// There are no receivers; carries the value that could not be sent.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SendError(..)")
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "no receivers")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// All senders are gone and every value has been received.
+    Closed,
+    /// The receiver fell behind and missed this many values.
+    Lagged(u64),
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecvError::Closed => write!(f, "channel closed"),
+            RecvError::Lagged(n) => write!(f, "receiver lagged by {} values", n),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// No new value has been sent.
+    Empty,
+    Closed,
+    Lagged(u64),
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "channel empty"),
+            TryRecvError::Closed => write!(f, "channel closed"),
+            TryRecvError::Lagged(n) => write!(f, "receiver lagged by {} values", n),
+        }
+    }
+}
+
+struct State<T> {
+    /// Slot `seq % capacity` holds the value with sequence number `seq`.
+    buffer: Vec<Option<T>>,
+    /// Sequence number of the next value to be sent.
+    tail: u64,
+    senders: usize,
+    receivers: usize,
+    wakers: Vec<Waker>,
+}
+
+type Shared<T> = Arc<IrqSafeMutex<State<T>>>;
+
+/// Creates a broadcast channel that keeps the last `capacity` values.
+///
+/// # Panics
+/// Panics if `capacity` is 0.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "broadcast channel capacity must be non-zero");
+    let mut buffer = Vec::with_capacity(capacity);
+    buffer.resize_with(capacity, || None);
+    let shared = Arc::new(IrqSafeMutex::new(State {
+        buffer,
+        tail: 0,
+        senders: 1,
+        receivers: 1,
+        wakers: Vec::new(),
+    }));
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared, next: 0 },
+    )
+}
+
+pub struct Sender<T> {
+    shared: Shared<T>,
+}
+
+impl<T: Clone> Sender<T> {
+    /// Sends `value` to all current receivers and returns how many there
+    /// are. Never blocks or allocates, so it is safe to call from interrupt
+    /// handlers.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let mut state = self.shared.lock();
+        if state.receivers == 0 {
+            return Err(SendError(value));
+        }
+        let slot = (state.tail % state.buffer.len() as u64) as usize;
+        state.buffer[slot] = Some(value);
+        state.tail += 1;
+        wake_all(&mut state.wakers);
+        Ok(state.receivers)
+    }
+
+    /// Creates a receiver that sees values sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut state = self.shared.lock();
+        state.receivers += 1;
+        Receiver {
+            shared: self.shared.clone(),
+            next: state.tail,
+        }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.shared.lock().receivers
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            wake_all(&mut state.wakers);
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Shared<T>,
+    /// Sequence number of the next value this receiver expects.
+    next: u64,
+}
+
+impl<T: Clone> Receiver<T> {
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let state = self.shared.lock();
+        recv_locked(&state, &mut self.next)
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        if coop::poll_proceed(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let mut state = self.shared.lock();
+        match recv_locked(&state, &mut self.next) {
+            Ok(value) => Poll::Ready(Ok(value)),
+            Err(TryRecvError::Closed) => Poll::Ready(Err(RecvError::Closed)),
+            Err(TryRecvError::Lagged(n)) => Poll::Ready(Err(RecvError::Lagged(n))),
+            Err(TryRecvError::Empty) => {
+                register(&mut state.wakers, cx.waker());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+fn recv_locked<T: Clone>(state: &State<T>, next: &mut u64) -> Result<T, TryRecvError> {
+    if *next == state.tail {
+        return Err(if state.senders == 0 {
+            TryRecvError::Closed
+        } else {
+            TryRecvError::Empty
+        });
+    }
+    let capacity = state.buffer.len() as u64;
+    let oldest = state.tail.saturating_sub(capacity);
+    if *next < oldest {
+        let missed = oldest - *next;
+        *next = oldest;
+        return Err(TryRecvError::Lagged(missed));
+    }
+    let value = state.buffer[(*next % capacity) as usize]
+        .clone()
+        .expect("broadcast slot empty");
+    *next += 1;
+    Ok(value)
+}
+
+impl<T> Clone for Receiver<T> {
+    /// The clone starts at the same position as `self`.
+    fn clone(&self) -> Self {
+        self.shared.lock().receivers += 1;
+        Self {
+            shared: self.shared.clone(),
+            next: self.next,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.lock().receivers -= 1;
+    }
+}
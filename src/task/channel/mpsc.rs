@@ -0,0 +1,329 @@
+//! Multi-producer, single-consumer channels.
+
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::{
+    fmt,
+    future::poll_fn,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use futures_util::stream::Stream;
+
+use super::{register, wake_all};
+use crate::sync::IrqSafeMutex;
+use crate::task::coop;
+
+/// The receiver is gone; carries the value that could not be sent.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SendError(..)")
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "channel closed")
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is at capacity.
+    Full(T),
+    /// The receiver is gone.
+    Closed(T),
+}
+
+impl<T> TrySendError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            TrySendError::Full(value) | TrySendError::Closed(value) => value,
+        }
+    }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("Full(..)"),
+            TrySendError::Closed(_) => f.write_str("Closed(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "channel full"),
+            TrySendError::Closed(_) => write!(f, "channel closed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// Nothing is buffered right now.
+    Empty,
+    /// Nothing is buffered and all senders are gone.
+    Disconnected,
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "channel empty"),
+            TryRecvError::Disconnected => write!(f, "channel disconnected"),
+        }
+    }
+}
+
+struct State<T> {
+    buffer: VecDeque<T>,
+    /// `None` for unbounded channels.
+    capacity: Option<usize>,
+    senders: usize,
+    closed: bool,
+    receiver: Option<Waker>,
+    blocked_senders: Vec<Waker>,
+}
+
+type Shared<T> = Arc<IrqSafeMutex<State<T>>>;
+
+impl<T> State<T> {
+    fn is_full(&self) -> bool {
+        self.capacity
+            .is_some_and(|capacity| self.buffer.len() >= capacity)
+    }
+
+    fn try_send(&mut self, value: T) -> Result<(), TrySendError<T>> {
+        if self.closed {
+            return Err(TrySendError::Closed(value));
+        }
+        if self.is_full() {
+            return Err(TrySendError::Full(value));
+        }
+        self.buffer.push_back(value);
+        if let Some(waker) = self.receiver.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+}
+
+fn new_channel<T>(capacity: Option<usize>) -> Shared<T> {
+    Arc::new(IrqSafeMutex::new(State {
+        buffer: match capacity {
+            Some(capacity) => VecDeque::with_capacity(capacity),
+            None => VecDeque::new(),
+        },
+        capacity,
+        senders: 1,
+        closed: false,
+        receiver: None,
+        blocked_senders: Vec::new(),
+    }))
+}
+
+/// Creates a channel that buffers up to `capacity` values.
+///
+/// The buffer is allocated up front, so [`Sender::try_send`] can be called
+/// from interrupt handlers.
+///
+/// # Panics
+/// Panics if `capacity` is 0.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "mpsc channel capacity must be non-zero");
+    let shared = new_channel(Some(capacity));
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+/// Creates a channel without a limit on buffered values.
+///
+/// Sending may allocate, so it must not be used from interrupt handlers.
+pub fn unbounded_channel<T>() -> (UnboundedSender<T>, Receiver<T>) {
+    let shared = new_channel(None);
+    (
+        UnboundedSender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+fn clone_sender<T>(shared: &Shared<T>) -> Shared<T> {
+    shared.lock().senders += 1;
+    shared.clone()
+}
+
+fn drop_sender<T>(shared: &Shared<T>) {
+    let mut state = shared.lock();
+    state.senders -= 1;
+    if state.senders == 0 {
+        if let Some(waker) = state.receiver.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Sending half of a bounded channel.
+pub struct Sender<T> {
+    shared: Shared<T>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `value`, waiting for a free slot if the channel is full.
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut value = Some(value);
+        poll_fn(|cx| {
+            let mut state = self.shared.lock();
+            match state.try_send(value.take().unwrap()) {
+                Ok(()) => Poll::Ready(Ok(())),
+                Err(TrySendError::Closed(v)) => Poll::Ready(Err(SendError(v))),
+                Err(TrySendError::Full(v)) => {
+                    value = Some(v);
+                    register(&mut state.blocked_senders, cx.waker());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+
+    /// Sends `value` if there is room. Never blocks or allocates, so it is
+    /// safe to call from interrupt handlers.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.shared.lock().try_send(value)
+    }
+
+    /// Whether the receiver has been dropped or closed.
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().closed
+    }
+
+    /// Number of values that can be sent right now without waiting.
+    pub fn capacity(&self) -> usize {
+        let state = self.shared.lock();
+        state.capacity.unwrap() - state.buffer.len()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: clone_sender(&self.shared),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        drop_sender(&self.shared);
+    }
+}
+
+/// Sending half of an unbounded channel.
+pub struct UnboundedSender<T> {
+    shared: Shared<T>,
+}
+
+impl<T> UnboundedSender<T> {
+    /// Sends `value` without waiting. Fails only if the receiver is gone.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.shared
+            .lock()
+            .try_send(value)
+            .map_err(|err| SendError(err.into_inner()))
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().closed
+    }
+}
+
+impl<T> Clone for UnboundedSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: clone_sender(&self.shared),
+        }
+    }
+}
+
+impl<T> Drop for UnboundedSender<T> {
+    fn drop(&mut self) {
+        drop_sender(&self.shared);
+    }
+}
+
+/// Receiving half of a bounded or unbounded channel.
+pub struct Receiver<T> {
+    shared: Shared<T>,
+}
+
+impl<T> Receiver<T> {
+    /// Receives the next value, or `None` once all senders are gone and the
+    /// buffer is drained.
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut state = self.shared.lock();
+        match state.buffer.pop_front() {
+            Some(value) => {
+                wake_all(&mut state.blocked_senders);
+                Ok(value)
+            }
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        if coop::poll_proceed(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let mut state = self.shared.lock();
+        if let Some(value) = state.buffer.pop_front() {
+            wake_all(&mut state.blocked_senders);
+            return Poll::Ready(Some(value));
+        }
+        if state.senders == 0 {
+            return Poll::Ready(None);
+        }
+        match &state.receiver {
+            Some(waker) if waker.will_wake(cx.waker()) => {}
+            _ => state.receiver = Some(cx.waker().clone()),
+        }
+        Poll::Pending
+    }
+
+    /// Stops accepting new values. Already buffered values can still be
+    /// received.
+    pub fn close(&mut self) {
+        let mut state = self.shared.lock();
+        state.closed = true;
+        wake_all(&mut state.blocked_senders);
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
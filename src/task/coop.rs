@@ -0,0 +1,61 @@
+//! Cooperative yielding.
+//!
+//! Every poll of a task gets a budget of [`POLL_BUDGET`] operations. Leaf
+//! futures that may be ready over and over (queues, channels) call
+//! [`poll_proceed`] and return `Pending` once the budget is spent, so a task
+//! that loops over an always-ready source still hands the CPU back to the
+//! executor.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU32, Ordering},
+    task::{Context, Poll},
+};
+
+pub const POLL_BUDGET: u32 = 128;
+
+crate::percpu! {
+    static BUDGET: AtomicU32 = AtomicU32::new(POLL_BUDGET);
+}
+
+/// Called by the executor before each task poll.
+pub(crate) fn reset_budget() {
+    BUDGET.get().store(POLL_BUDGET, Ordering::Relaxed);
+}
+
+/// Consumes one unit of the current task's budget.
+///
+/// Returns `Pending` and schedules the task again once the budget is spent.
+pub fn poll_proceed(cx: &mut Context<'_>) -> Poll<()> {
+    let budget = BUDGET.get();
+    let remaining = budget.load(Ordering::Relaxed);
+    if remaining == 0 {
+        cx.waker().wake_by_ref();
+        return Poll::Pending;
+    }
+    budget.store(remaining - 1, Ordering::Relaxed);
+    Poll::Ready(())
+}
+
+/// Yields to the executor once, letting other ready tasks run first.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
@@ -0,0 +1,196 @@
+//! Deferred interrupt work ("bottom halves").
+//!
+//! Interrupt handlers should only acknowledge the device and hand the rest of
+//! the work to task context. They can either [`schedule`] a plain function
+//! that the [`Executor`](super::executor::Executor) runs before polling tasks,
+//! or feed an async task through an [`IrqQueue`] or [`IrqEvent`].
+
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::{future::Future, stream::Stream, task::AtomicWaker};
+
+use super::coop;
+use crate::println;
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+
+const WORK_QUEUE_SIZE: usize = 64;
+
+static WORK_QUEUE: OnceCell<ArrayQueue<fn()>> = OnceCell::uninit();
+
+pub(crate) fn init() {
+    WORK_QUEUE.init_once(|| ArrayQueue::new(WORK_QUEUE_SIZE));
+}
+
+/// Queues `work` to run in task context. Safe to call from interrupt
+/// handlers; returns `work` back if the queue is full or not set up yet.
+pub fn schedule(work: fn()) -> Result<(), fn()> {
+    match WORK_QUEUE.try_get() {
+        Ok(queue) => queue.push(work).map_err(|err| err.0),
+        Err(_) => Err(work),
+    }
+}
+
+pub(crate) fn has_pending() -> bool {
+    WORK_QUEUE
+        .try_get()
+        .map(|queue| !queue.is_empty())
+        .unwrap_or(false)
+}
+
+/// Runs all queued work items, including ones queued while draining.
+pub(crate) fn run_pending() {
+    if let Ok(queue) = WORK_QUEUE.try_get() {
+        while let Ok(work) = queue.pop() {
+            work();
+        }
+    }
+}
+
+/// A fixed-size queue that an interrupt handler pushes into and a single
+/// task consumes as a [`Stream`].
+pub struct IrqQueue<T> {
+    queue: OnceCell<ArrayQueue<T>>,
+    waker: AtomicWaker,
+    /// Values pushed while the queue was full or not set up yet, reported
+    /// by the consumer.
+    dropped: AtomicUsize,
+    capacity: usize,
+    name: &'static str,
+}
+
+impl<T> IrqQueue<T> {
+    pub const fn new(name: &'static str, capacity: usize) -> Self {
+        Self {
+            queue: OnceCell::uninit(),
+            waker: AtomicWaker::new(),
+            dropped: AtomicUsize::new(0),
+            capacity,
+            name,
+        }
+    }
+
+    /// Allocates the queue. Must be called from task context once, before
+    /// items pushed by the interrupt handler are accepted.
+    pub fn init(&self) {
+        self.queue
+            .try_init_once(|| ArrayQueue::new(self.capacity))
+            .expect("IrqQueue::init should only be called once");
+    }
+
+    /// Pushes `value` and wakes the consumer. Called from interrupt context;
+    /// drops the value if the queue is full or not set up yet. The consumer
+    /// warns about dropped values, since printing here could run into a
+    /// console lock the interrupted code holds.
+    pub fn push(&self, value: T) {
+        let pushed = match self.queue.try_get() {
+            Ok(queue) => queue.push(value).is_ok(),
+            Err(_) => false,
+        };
+        if !pushed {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.waker.wake();
+    }
+
+    pub fn poll_pop(&self, cx: &mut Context) -> Poll<T> {
+        if coop::poll_proceed(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let queue = self.queue.try_get().expect("IrqQueue not initialized");
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            println!("WARNING: {} queue dropped {} inputs", self.name, dropped);
+        }
+
+        if let Ok(value) = queue.pop() {
+            return Poll::Ready(value);
+        }
+        self.waker.register(cx.waker());
+        match queue.pop() {
+            Ok(value) => {
+                self.waker.take();
+                Poll::Ready(value)
+            }
+            //return with waker
+            Err(crossbeam_queue::PopError) => Poll::Pending,
+        }
+    }
+
+    pub fn stream(&self) -> IrqQueueStream<'_, T> {
+        IrqQueueStream { queue: self }
+    }
+}
+
+pub struct IrqQueueStream<'a, T> {
+    queue: &'a IrqQueue<T>,
+}
+
+impl<T> Stream for IrqQueueStream<'_, T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.queue.poll_pop(cx).map(Some)
+    }
+}
+
+/// A flag an interrupt handler raises to wake a waiting task.
+pub struct IrqEvent {
+    pending: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl IrqEvent {
+    pub const fn new() -> Self {
+        Self {
+            pending: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    /// Raises the event. Safe to call from interrupt context; raising it
+    /// again before the task ran is coalesced.
+    pub fn signal(&self) {
+        self.pending.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+
+    /// Waits until the event is raised and clears it.
+    pub fn wait(&self) -> IrqEventWait<'_> {
+        IrqEventWait { event: self }
+    }
+
+    fn poll_wait(&self, cx: &mut Context) -> Poll<()> {
+        if self.pending.swap(false, Ordering::Acquire) {
+            return Poll::Ready(());
+        }
+        self.waker.register(cx.waker());
+        if self.pending.swap(false, Ordering::Acquire) {
+            self.waker.take();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Default for IrqEvent {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct IrqEventWait<'a> {
+    event: &'a IrqEvent,
+}
+
+impl Future for IrqEventWait<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.event.poll_wait(cx)
+    }
+}
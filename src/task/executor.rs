@@ -1,29 +1,300 @@
-use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
-use core::task::{Context, Poll, Waker};
-use crossbeam_queue::ArrayQueue;
+use super::{coop, deferred, local, recover, scope, JoinHandle, Priority, SendTask, Task, TaskId};
+use crate::interrupts::stats;
+use crate::sync::IrqSafeMutex;
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    sync::Arc,
+    task::Wake,
+    vec::Vec,
+};
+use core::{
+    cell::RefCell,
+    fmt,
+    future::Future,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use crossbeam_queue::SegQueue;
+use x86_64::instructions::interrupts;
+
+/// Consecutive polls of higher-priority tasks after which one waiting task of
+/// the lowest non-empty priority gets polled, so it cannot starve.
+const STARVATION_LIMIT: u32 = 16;
+
+/// Number of completed tasks kept for [`Executor::tasks`].
+const COMPLETED_HISTORY: usize = 16;
 
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    task_queue: Arc<ReadyQueues>,
+    waker_cache: BTreeMap<TaskId, CachedWaker>,
+    spawn_queue: Arc<SegQueue<SendTask>>,
+    completed: VecDeque<TaskInfo>,
+    isolate_panics: bool,
+    panic_hook: Option<Box<PanicHook>>,
+}
+
+type PanicHook = dyn FnMut(&TaskInfo, &str);
+
+crate::percpu! {
+    /// The spawner of the executor that is running on each CPU.
+    static CURRENT_SPAWNER: RefCell<Option<Spawner>> = RefCell::new(None);
+}
+
+/// Restores the spawner that was current before [`Executor::enter`].
+struct Entered {
+    previous: Option<Spawner>,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT_SPAWNER.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// A cloneable handle that spawns tasks onto an [`Executor`] from inside
+/// running tasks or deferred interrupt work.
+///
+/// Not for interrupt handlers themselves: spawning allocates.
+#[derive(Clone)]
+pub struct Spawner {
+    spawn_queue: Arc<SegQueue<SendTask>>,
+}
+
+impl Spawner {
+    /// The spawner of the running executor, if any.
+    pub fn current() -> Option<Spawner> {
+        CURRENT_SPAWNER.with(|spawner| spawner.borrow().clone())
+    }
+
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawn_with(future, |task| task)
+    }
+
+    pub fn spawn_with_priority<F>(&self, future: F, priority: Priority) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawn_with(future, |task| task.with_priority(priority))
+    }
+
+    pub fn spawn_named<F>(&self, name: impl Into<String>, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawn_with(future, |task| task.with_name(name))
+    }
+
+    /// Spawns `future` into the [`CancelScope`](super::scope::CancelScope)
+    /// of the running task, if it has one.
+    fn spawn_with<F>(
+        &self,
+        future: F,
+        configure: impl FnOnce(SendTask) -> SendTask,
+    ) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = SendTask::with_handle(scope::inherit(future));
+        scope::adopt(&handle);
+        self.spawn_queue.push(configure(task));
+        handle
+    }
+}
+
+/// One FIFO ready queue per [`Priority`].
+///
+/// A task is in at most one queue at a time (see [`TaskWaker`]), so each
+/// queue keeps room for all live tasks of its priority and pushing never
+/// allocates. That makes waking safe from interrupt handlers.
+struct ReadyQueues {
+    queues: [IrqSafeMutex<ReadyQueue>; Priority::COUNT],
+    depth: AtomicUsize,
+    max_depth: AtomicUsize,
+    wakes: AtomicU64,
+    redundant_wakes: AtomicU64,
+}
+
+#[derive(Default)]
+struct ReadyQueue {
+    task_ids: VecDeque<TaskId>,
+    live_tasks: usize,
+}
+
+/// Snapshot of the executor's ready queue statistics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueMetrics {
+    /// Tasks currently waiting to be polled.
+    pub depth: usize,
+    /// Largest `depth` seen so far.
+    pub max_depth: usize,
+    /// Wake-ups that queued a task.
+    pub wakes: u64,
+    /// Wake-ups of tasks that were already queued and got merged.
+    pub redundant_wakes: u64,
+}
+
+impl ReadyQueues {
+    fn new() -> Self {
+        Self {
+            queues: Default::default(),
+            depth: AtomicUsize::new(0),
+            max_depth: AtomicUsize::new(0),
+            wakes: AtomicU64::new(0),
+            redundant_wakes: AtomicU64::new(0),
+        }
+    }
+
+    /// Makes room for one more task of `priority`. Called in task context.
+    fn add_task(&self, priority: Priority) {
+        let mut queue = self.queues[priority.index()].lock();
+        queue.live_tasks += 1;
+        let additional = queue.live_tasks.saturating_sub(queue.task_ids.len());
+        queue.task_ids.reserve(additional);
+    }
+
+    /// Gives back the room of a finished task and marks it queued for good,
+    /// so that wakers which outlive it never queue it again. A task that
+    /// woke itself before finishing is still in the queue; its ID is dropped
+    /// so that the queue never holds more IDs than there are live tasks.
+    fn remove_task(&self, priority: Priority, task_id: TaskId, queued: &AtomicBool) {
+        let mut queue = self.queues[priority.index()].lock();
+        queue.live_tasks -= 1;
+        if queued.swap(true, Ordering::AcqRel) {
+            let before = queue.task_ids.len();
+            queue.task_ids.retain(|&id| id != task_id);
+            self.depth
+                .fetch_sub(before - queue.task_ids.len(), Ordering::Relaxed);
+        }
+    }
+
+    /// Queues `task_id` unless `queued` says it already is. `queued` is
+    /// only set under the queue lock, so [`ReadyQueues::remove_task`] finds
+    /// the ID whenever it finds the flag set.
+    fn push(&self, priority: Priority, task_id: TaskId, queued: &AtomicBool) {
+        let mut queue = self.queues[priority.index()].lock();
+        if queued.swap(true, Ordering::AcqRel) {
+            drop(queue);
+            self.redundant_wakes.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        debug_assert!(queue.task_ids.len() < queue.task_ids.capacity());
+        queue.task_ids.push_back(task_id);
+        drop(queue);
+
+        self.wakes.fetch_add(1, Ordering::Relaxed);
+        let depth = self.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        self.max_depth.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    fn pop_from<'a>(
+        &self,
+        mut queues: impl Iterator<Item = &'a IrqSafeMutex<ReadyQueue>>,
+    ) -> Option<TaskId> {
+        let task_id = queues.find_map(|queue| queue.lock().task_ids.pop_front())?;
+        self.depth.fetch_sub(1, Ordering::Relaxed);
+        Some(task_id)
+    }
+
+    fn pop_highest(&self) -> Option<TaskId> {
+        self.pop_from(self.queues.iter())
+    }
+
+    fn pop_lowest(&self) -> Option<TaskId> {
+        self.pop_from(self.queues.iter().rev())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.depth.load(Ordering::Relaxed) == 0
+    }
+
+    /// Whether a task below the highest non-empty priority is waiting.
+    fn has_lower_waiting(&self) -> bool {
+        self.queues
+            .iter()
+            .filter(|queue| !queue.lock().task_ids.is_empty())
+            .count()
+            > 1
+    }
+
+    fn metrics(&self) -> QueueMetrics {
+        QueueMetrics {
+            depth: self.depth.load(Ordering::Relaxed),
+            max_depth: self.max_depth.load(Ordering::Relaxed),
+            wakes: self.wakes.load(Ordering::Relaxed),
+            redundant_wakes: self.redundant_wakes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What last woke a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum WakeSource {
+    /// Queued when it was spawned.
+    Spawn,
+    /// Woken from task context, e.g. by another task or deferred work.
+    Task,
+    /// Woken from an interrupt handler.
+    Interrupt,
+}
+
+impl WakeSource {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => WakeSource::Spawn,
+            1 => WakeSource::Task,
+            _ => WakeSource::Interrupt,
+        }
+    }
+}
+
+impl fmt::Display for WakeSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            WakeSource::Spawn => "spawn",
+            WakeSource::Task => "task",
+            WakeSource::Interrupt => "interrupt",
+        };
+        f.pad(name)
+    }
 }
 
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    priority: Priority,
+    /// Set while the task sits in a ready queue, and for good once it has
+    /// completed. Wakes while it is set are merged.
+    queued: AtomicBool,
+    last_wake: AtomicU8,
+    task_queue: Arc<ReadyQueues>,
 }
 
 impl TaskWaker {
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        let source = if stats::in_interrupt() {
+            WakeSource::Interrupt
+        } else {
+            WakeSource::Task
+        };
+        self.wake_from(source);
     }
 
-    fn waker_new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
+    fn wake_from(&self, source: WakeSource) {
+        self.last_wake.store(source as u8, Ordering::Relaxed);
+        self.task_queue
+            .push(self.priority, self.task_id, &self.queued);
     }
 }
 
@@ -37,21 +308,149 @@ impl Wake for TaskWaker {
     }
 }
 
+/// The waker of a live task, kept both as the shared state and as a ready
+/// `Waker` to hand to `poll`.
+struct CachedWaker {
+    state: Arc<TaskWaker>,
+    waker: Waker,
+}
+
 impl Executor {
     pub fn new() -> Self {
+        deferred::init();
         Self {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            task_queue: Arc::new(ReadyQueues::new()),
             waker_cache: BTreeMap::new(),
+            spawn_queue: Arc::new(SegQueue::new()),
+            completed: VecDeque::new(),
+            isolate_panics: false,
+            panic_hook: None,
         }
     }
 
-    pub fn spawn(&mut self, task: Task) {
+    /// With `enabled`, a task that panics is marked failed instead of
+    /// halting the kernel: its [`JoinHandle`] resolves to
+    /// [`JoinError::Panicked`](super::JoinError::Panicked) and the other
+    /// tasks keep running.
+    ///
+    /// There is no unwinding: the failed task's box is freed without
+    /// running the future's destructor, so whatever the future owns leaks.
+    /// [`IrqSafeMutex`](crate::sync::IrqSafeMutex) locks held at the time of
+    /// the panic are force-released. Panics in interrupt handlers still
+    /// halt.
+    pub fn isolate_panics(&mut self, enabled: bool) {
+        self.isolate_panics = enabled;
+    }
+
+    /// Calls `hook` with every task that fails while panics are isolated,
+    /// instead of printing a message.
+    pub fn on_task_panic(&mut self, hook: impl FnMut(&TaskInfo, &str) + 'static) {
+        self.panic_hook = Some(Box::new(hook));
+    }
+
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            spawn_queue: self.spawn_queue.clone(),
+        }
+    }
+
+    /// Spawns `future` and returns a handle to await its output.
+    pub fn spawn<F>(&mut self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        self.spawn_with_priority(future, Priority::default())
+    }
+
+    pub fn spawn_with_priority<F>(&mut self, future: F, priority: Priority) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let (task, handle) = Task::with_handle(future);
+        self.spawn_task(task.with_priority(priority));
+        handle
+    }
+
+    pub fn spawn_named<F>(&mut self, name: impl Into<String>, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let (task, handle) = Task::with_handle(future);
+        self.spawn_task(task.with_name(name));
+        handle
+    }
+
+    pub fn spawn_task(&mut self, task: Task) {
         let task_id = task.id;
+        let priority = task.priority;
         if self.tasks.insert(task_id, task).is_some() {
             panic!("task with same ID already in tasks");
         }
-        self.task_queue.push(task_id).expect("queue full");
+        self.task_queue.add_task(priority);
+
+        let state = Arc::new(TaskWaker {
+            task_id,
+            priority,
+            queued: AtomicBool::new(false),
+            last_wake: AtomicU8::new(WakeSource::Spawn as u8),
+            task_queue: self.task_queue.clone(),
+        });
+        let waker = Waker::from(state.clone());
+        state.wake_from(WakeSource::Spawn);
+        self.waker_cache
+            .insert(task_id, CachedWaker { state, waker });
+    }
+
+    pub fn queue_metrics(&self) -> QueueMetrics {
+        self.task_queue.metrics()
+    }
+
+    /// Statistics of all live tasks, followed by the most recently
+    /// completed ones.
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        let mut tasks: Vec<_> = self
+            .tasks
+            .values()
+            .map(|task| {
+                let waker = &self.waker_cache[&task.id].state;
+                let state = if waker.queued.load(Ordering::Relaxed) {
+                    TaskState::Ready
+                } else {
+                    TaskState::Pending
+                };
+                TaskInfo::new(task, state, waker)
+            })
+            .collect();
+        tasks.extend(self.completed.iter().cloned());
+        tasks
+    }
+
+    /// The task list as a printable table.
+    pub fn task_table(&self) -> TaskTable {
+        TaskTable(self.tasks())
+    }
+
+    /// Moves tasks spawned through a [`Spawner`] into the executor.
+    fn spawn_pending(&mut self) {
+        while let Ok(task) = self.spawn_queue.pop() {
+            self.spawn_task(task.into_task());
+        }
+    }
+
+    fn has_pending_work(&self) -> bool {
+        !self.task_queue.is_empty() || !self.spawn_queue.is_empty() || deferred::has_pending()
+    }
+
+    /// Makes [`Spawner::current`] and [`super::spawn`] target this executor
+    /// until the returned guard is dropped.
+    fn enter(&self) -> Entered {
+        let spawner = self.spawner();
+        let previous = CURRENT_SPAWNER.with(|current| current.borrow_mut().replace(spawner));
+        Entered { previous }
     }
 
     fn run_ready_tasks(&mut self) {
@@ -59,29 +458,102 @@ impl Executor {
             tasks,
             task_queue,
             waker_cache,
+            completed,
+            isolate_panics,
+            panic_hook,
+            ..
         } = self;
 
-        while let Ok(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue,
+        let mut higher_polls = 0;
+        loop {
+            let task_id = if higher_polls >= STARVATION_LIMIT {
+                higher_polls = 0;
+                task_queue.pop_lowest()
+            } else {
+                if task_queue.has_lower_waiting() {
+                    higher_polls += 1;
+                }
+                task_queue.pop_highest()
+            };
+            let task_id = match task_id {
+                Some(task_id) => task_id,
+                None => break,
+            };
+            let (task, cached) = match (tasks.get_mut(&task_id), waker_cache.get(&task_id)) {
+                (Some(task), Some(cached)) => (task, cached),
+                _ => continue,
             };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::waker_new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
+            // Wakes from now on must queue the task again.
+            cached.state.queued.store(false, Ordering::Release);
+            let mut context = Context::from_waker(&cached.waker);
+            coop::reset_budget();
+            let result = if *isolate_panics {
+                let interrupts_enabled = interrupts::are_enabled();
+                let locals = local::entered();
+                let result = recover::catch(|| task.poll(&mut context));
+                if result.is_err() {
+                    crate::percpu::current().set_current_task(None);
+                    local::clear_entered(locals);
+                    // The panic may have struck while an `IrqSafeMutex` was
+                    // held.
+                    if interrupts_enabled {
+                        interrupts::enable();
+                    }
                 }
-                Poll::Pending => {}
+                result
+            } else {
+                Ok(task.poll(&mut context))
+            };
+            let state = match result {
+                Ok(Poll::Pending) => continue,
+                Ok(Poll::Ready(())) => TaskState::Completed,
+                Err(_) => TaskState::Failed,
+            };
+
+            let mut task = tasks.remove(&task_id).unwrap();
+            let cached = waker_cache.remove(&task_id).unwrap();
+            task_queue.remove_task(task.priority, task_id, &cached.state.queued);
+            let info = TaskInfo::new(&task, state, &cached.state);
+            if let Err(message) = result {
+                if let Some(on_panic) = task.on_panic.take() {
+                    on_panic(message.clone());
+                }
+                task.abandon();
+                match panic_hook {
+                    Some(hook) => hook(&info, &message),
+                    None => crate::println!(
+                        "task {} ({}) panicked: {}",
+                        info.id,
+                        info.name.as_deref().unwrap_or("-"),
+                        message
+                    ),
+                }
+            }
+            if completed.len() == COMPLETED_HISTORY {
+                completed.pop_front();
+            }
+            completed.push_back(info);
+        }
+    }
+
+    /// Runs tasks until none of them is ready, e.g. for tests.
+    pub fn run_until_idle(&mut self) {
+        let _entered = self.enter();
+        loop {
+            deferred::run_pending();
+            self.spawn_pending();
+            self.run_ready_tasks();
+            if !self.has_pending_work() {
+                break;
             }
         }
     }
 
     pub fn run(&mut self) -> ! {
+        let _entered = self.enter();
         loop {
+            deferred::run_pending();
+            self.spawn_pending();
             self.run_ready_tasks();
             self.sleep_if_idle();
         }
@@ -91,7 +563,7 @@ impl Executor {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
 
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        if !self.has_pending_work() {
             enable_and_hlt();
         } else {
             interrupts::enable();
@@ -104,3 +576,85 @@ impl Default for Executor {
         Self::new()
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// Queued to be polled.
+    Ready,
+    /// Waiting to be woken.
+    Pending,
+    Completed,
+    /// Panicked while panics were isolated.
+    Failed,
+}
+
+impl fmt::Display for TaskState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            TaskState::Ready => "ready",
+            TaskState::Pending => "pending",
+            TaskState::Completed => "completed",
+            TaskState::Failed => "failed",
+        };
+        f.pad(name)
+    }
+}
+
+/// Snapshot of a task's statistics, see [`Executor::tasks`].
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: u64,
+    pub name: Option<String>,
+    pub priority: Priority,
+    pub state: TaskState,
+    pub polls: u64,
+    /// Total time spent in the task's `poll`.
+    pub poll_time: Duration,
+    pub last_wake: WakeSource,
+}
+
+impl TaskInfo {
+    fn new(task: &Task, state: TaskState, waker: &TaskWaker) -> Self {
+        Self {
+            id: task.id.0,
+            name: task.name.clone(),
+            priority: task.priority,
+            state,
+            polls: task.polls,
+            poll_time: task.poll_time,
+            last_wake: WakeSource::from_u8(waker.last_wake.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Printable task list returned by [`Executor::task_table`].
+pub struct TaskTable(Vec<TaskInfo>);
+
+impl fmt::Display for TaskTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>5} {:<16} {:<6} {:<9} {:>8} {:>10} {:<9}",
+            "id", "name", "prio", "state", "polls", "time (us)", "woken by"
+        )?;
+        for task in &self.0 {
+            let priority = match task.priority {
+                Priority::High => "high",
+                Priority::Normal => "normal",
+                Priority::Low => "low",
+            };
+            writeln!(
+                f,
+                "{:>5} {:<16} {:<6} {:<9} {:>8} {:>10} {:<9}",
+                task.id,
+                task.name.as_deref().unwrap_or("-"),
+                priority,
+                task.state,
+                task.polls,
+                task.poll_time.as_micros(),
+                task.last_wake
+            )?;
+        }
+        Ok(())
+    }
+}
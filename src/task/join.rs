@@ -0,0 +1,211 @@
+use alloc::{string::String, sync::Arc};
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use crate::sync::IrqSafeMutex;
+
+/// Why a task did not produce its output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was aborted through [`JoinHandle::abort`].
+    Cancelled,
+    /// The task panicked. Carries the panic message.
+    Panicked(String),
+}
+
+impl JoinError {
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, JoinError::Cancelled)
+    }
+
+    pub fn is_panic(&self) -> bool {
+        matches!(self, JoinError::Panicked(_))
+    }
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => write!(f, "task was cancelled"),
+            JoinError::Panicked(message) => write!(f, "task panicked: {}", message),
+        }
+    }
+}
+
+struct JoinState<T> {
+    result: Option<Result<T, JoinError>>,
+    finished: bool,
+    aborted: bool,
+    join_waker: Option<Waker>,
+    task_waker: Option<Waker>,
+}
+
+impl<T> JoinState<T> {
+    fn complete(&mut self, result: Result<T, JoinError>) {
+        self.result = Some(result);
+        self.finished = true;
+        if let Some(waker) = self.join_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Wraps a spawned future and publishes its output to the [`JoinHandle`].
+pub(crate) struct JoinFuture<F: Future> {
+    future: F,
+    state: Arc<IrqSafeMutex<JoinState<F::Output>>>,
+}
+
+impl<F: Future> JoinFuture<F> {
+    pub(crate) fn new(future: F) -> (Self, JoinHandle<F::Output>) {
+        let state = Arc::new(IrqSafeMutex::new(JoinState {
+            result: None,
+            finished: false,
+            aborted: false,
+            join_waker: None,
+            task_waker: None,
+        }));
+        let handle = JoinHandle {
+            state: state.clone(),
+        };
+        (Self { future, state }, handle)
+    }
+
+    /// Returns a function that resolves the handle to
+    /// [`JoinError::Panicked`], for when a poll of this future panicked.
+    pub(crate) fn panic_reporter(&self) -> impl FnOnce(String)
+    where
+        F::Output: 'static,
+    {
+        let state = self.state.clone();
+        move |message| {
+            state.lock().complete(Err(JoinError::Panicked(message)));
+        }
+    }
+}
+
+impl<F: Future> Future for JoinFuture<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // SAFETY: `future` is structurally pinned and never moved out of
+        // `self`.
+        let this = unsafe { self.get_unchecked_mut() };
+        {
+            let mut state = this.state.lock();
+            if state.aborted {
+                state.complete(Err(JoinError::Cancelled));
+                return Poll::Ready(());
+            }
+            match &state.task_waker {
+                Some(waker) if waker.will_wake(cx.waker()) => {}
+                _ => state.task_waker = Some(cx.waker().clone()),
+            }
+        }
+
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        match future.poll(cx) {
+            Poll::Ready(output) => {
+                this.state.lock().complete(Ok(output));
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// An owned permission to await the output of a spawned task.
+///
+/// Dropping the handle detaches the task; it keeps running.
+pub struct JoinHandle<T> {
+    state: Arc<IrqSafeMutex<JoinState<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Requests cancellation. The task is dropped the next time the executor
+    /// would poll it, and the handle resolves to [`JoinError::Cancelled`]
+    /// unless the task already finished.
+    pub fn abort(&self) {
+        abort(&self.state);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().finished
+    }
+}
+
+impl<T: Send + 'static> JoinHandle<T> {
+    /// A handle that can abort the task but not await it.
+    pub fn abort_handle(&self) -> AbortHandle {
+        AbortHandle {
+            state: self.state.clone(),
+        }
+    }
+}
+
+trait Abort: Send + Sync {
+    fn abort(&self);
+    fn is_finished(&self) -> bool;
+}
+
+fn abort<T>(state: &IrqSafeMutex<JoinState<T>>) {
+    let task_waker = {
+        let mut state = state.lock();
+        if state.finished {
+            return;
+        }
+        state.aborted = true;
+        state.task_waker.take()
+    };
+    if let Some(waker) = task_waker {
+        waker.wake();
+    }
+}
+
+impl<T: Send> Abort for IrqSafeMutex<JoinState<T>> {
+    fn abort(&self) {
+        abort(self);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.lock().finished
+    }
+}
+
+/// Aborts a task without owning its output, see [`JoinHandle::abort_handle`].
+#[derive(Clone)]
+pub struct AbortHandle {
+    state: Arc<dyn Abort>,
+}
+
+impl AbortHandle {
+    pub fn abort(&self) {
+        self.state.abort();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.is_finished()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        if let Some(result) = state.result.take() {
+            return Poll::Ready(result);
+        }
+        let finished = state.finished;
+        if !finished {
+            state.join_waker = Some(cx.waker().clone());
+        }
+        drop(state);
+        assert!(!finished, "JoinHandle polled after it returned the result");
+        Poll::Pending
+    }
+}
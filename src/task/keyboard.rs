@@ -2,29 +2,16 @@ use core::{
     pin::Pin,
     task::{Context, Poll},
 };
-use futures_util::{
-    stream::{Stream, StreamExt},
-    task::AtomicWaker,
-};
+use futures_util::stream::{Stream, StreamExt};
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 
-use crate::{print, println};
-use conquer_once::spin::OnceCell;
-use crossbeam_queue::ArrayQueue;
+use super::deferred::IrqQueue;
+use crate::print;
 
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
-static WAKER: AtomicWaker = AtomicWaker::new();
+static SCANCODE_QUEUE: IrqQueue<u8> = IrqQueue::new("scancode", 100);
 
 pub(crate) fn add_scancode(scancode: u8) {
-    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if queue.push(scancode).is_err() {
-            println!("WARNING: scancode queue full; dropping keyboard input");
-        } else {
-            WAKER.wake();
-        }
-    } else {
-        println!("WARNING: scancode queue uninitialized");
-    }
+    SCANCODE_QUEUE.push(scancode);
 }
 
 pub struct ScancodeStream {
@@ -33,9 +20,7 @@ pub struct ScancodeStream {
 
 impl ScancodeStream {
     pub fn new() -> Self {
-        SCANCODE_QUEUE
-            .try_init_once(|| ArrayQueue::new(100))
-            .expect("ScancodeStream::new should only be called once");
+        SCANCODE_QUEUE.init();
         Self { _private: () }
     }
 }
@@ -50,22 +35,7 @@ impl Stream for ScancodeStream {
     type Item = u8;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let queue = SCANCODE_QUEUE
-            .try_get()
-            .expect("scancode queue not initialized");
-
-        if let Ok(scancode) = queue.pop() {
-            return Poll::Ready(Some(scancode));
-        }
-        WAKER.register(cx.waker());
-        match queue.pop() {
-            Ok(scancode) => {
-                WAKER.take();
-                Poll::Ready(Some(scancode))
-            }
-            //return with waker
-            Err(crossbeam_queue::PopError) => Poll::Pending,
-        }
+        SCANCODE_QUEUE.poll_pop(cx).map(Some)
     }
 }
 
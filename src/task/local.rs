@@ -0,0 +1,185 @@
+//! Task-local storage.
+//!
+//! A [`LocalKey`] declared with [`task_local!`](crate::task_local) holds a
+//! value only while a future wrapped by [`LocalKey::scope`] is being polled,
+//! so every task sees the value it was given. The value stays in the
+//! [`TaskLocalFuture`]; while it is polled, the future publishes a pointer to
+//! it in a list that belongs to the running kernel thread, or to the CPU
+//! outside of threads. A thread preempted in the middle of a poll takes its
+//! list along, so tasks polled by other threads do not see its values.
+
+use core::{
+    fmt,
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    ptr,
+    task::{Context, Poll},
+};
+
+/// Declares task-local keys.
+///
+/// ```ignore
+/// task_local! {
+///     pub static REQUEST_ID: u64;
+/// }
+///
+/// executor.spawn(REQUEST_ID.scope(7, async {
+///     assert_eq!(REQUEST_ID.get(), 7);
+/// }));
+/// ```
+#[macro_export]
+macro_rules! task_local {
+    ($($vis:vis static $name:ident: $ty:ty;)+) => {
+        $(
+            $vis static $name: $crate::task::local::LocalKey<$ty> =
+                $crate::task::local::LocalKey::new();
+        )+
+    };
+}
+
+/// The key is not set for the running code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessError;
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "task-local value not set")
+    }
+}
+
+/// A value published for a key, on the stack of the code that set it.
+struct Entry {
+    key: *const u8,
+    /// Null if the key is unset for the scope.
+    value: *const (),
+    /// The entry that was innermost before this one, as for [`entered`].
+    prev: usize,
+}
+
+/// The innermost entry of the running thread, or 0 if there is none. To be
+/// passed to [`clear_entered`].
+pub(crate) fn entered() -> usize {
+    crate::percpu::current().task_locals()
+}
+
+/// Unpublishes the entries added after [`entered`] returned `head`, after
+/// the poll they were added for was abandoned by a recovered panic.
+pub(crate) fn clear_entered(head: usize) {
+    crate::percpu::current().set_task_locals(head);
+}
+
+pub struct LocalKey<T: 'static> {
+    /// Gives every key an address of its own, which identifies its entries.
+    _address: u8,
+    _value: PhantomData<T>,
+}
+
+// SAFETY: a key holds no data.
+unsafe impl<T: 'static> Sync for LocalKey<T> {}
+
+impl<T: 'static> LocalKey<T> {
+    #[doc(hidden)]
+    pub const fn new() -> Self {
+        Self {
+            _address: 0,
+            _value: PhantomData,
+        }
+    }
+}
+
+impl<T: Send + 'static> LocalKey<T> {
+    fn address(&'static self) -> *const u8 {
+        self as *const Self as *const u8
+    }
+
+    /// Sets the key to `value` while `future` is polled.
+    pub fn scope<F: Future>(&'static self, value: T, future: F) -> TaskLocalFuture<T, F> {
+        self.scope_option(Some(value), future)
+    }
+
+    /// Like [`scope`](Self::scope), but `None` unsets the key for `future`.
+    pub(crate) fn scope_option<F: Future>(
+        &'static self,
+        value: Option<T>,
+        future: F,
+    ) -> TaskLocalFuture<T, F> {
+        TaskLocalFuture {
+            key: self,
+            value,
+            future,
+        }
+    }
+
+    /// Sets the key to `value` while `f` runs.
+    pub fn sync_scope<R>(&'static self, value: T, f: impl FnOnce() -> R) -> R {
+        self.enter(Some(&value), f)
+    }
+
+    /// Publishes `value` for this key while `f` runs.
+    fn enter<R>(&'static self, value: Option<&T>, f: impl FnOnce() -> R) -> R {
+        let entry = Entry {
+            key: self.address(),
+            value: value.map_or(ptr::null(), |value| value as *const T as *const ()),
+            prev: entered(),
+        };
+        crate::percpu::current().set_task_locals(&entry as *const Entry as usize);
+        let result = f();
+        // A preemption in `f` may have moved the thread to another CPU.
+        crate::percpu::current().set_task_locals(entry.prev);
+        result
+    }
+
+    /// Calls `f` with the current value.
+    ///
+    /// # Panics
+    /// Panics outside of a scope for this key.
+    pub fn with<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+        self.try_with(f).expect("task-local value not set")
+    }
+
+    pub fn try_with<R>(&'static self, f: impl FnOnce(&T) -> R) -> Result<R, AccessError> {
+        let mut entry = entered() as *const Entry;
+        // SAFETY: published entries stay alive until they are unpublished,
+        // and only the thread that published them sees them.
+        while let Some(current) = unsafe { entry.as_ref() } {
+            if current.key == self.address() {
+                let value = unsafe { (current.value as *const T).as_ref() };
+                return value.map(f).ok_or(AccessError);
+            }
+            entry = current.prev as *const Entry;
+        }
+        Err(AccessError)
+    }
+}
+
+impl<T: Clone + Send + 'static> LocalKey<T> {
+    /// A copy of the current value.
+    ///
+    /// # Panics
+    /// Panics outside of a scope for this key.
+    pub fn get(&'static self) -> T {
+        self.with(T::clone)
+    }
+}
+
+/// Future returned by [`LocalKey::scope`].
+pub struct TaskLocalFuture<T: 'static, F> {
+    key: &'static LocalKey<T>,
+    /// The scope's value, published while the future is polled.
+    value: Option<T>,
+    future: F,
+}
+
+impl<T: Send + 'static, F: Future> Future for TaskLocalFuture<T, F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // SAFETY: `future` is structurally pinned and never moved out of
+        // `self`; `value` is not pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        this.key.enter(this.value.as_ref(), || future.poll(cx))
+    }
+}
@@ -1,30 +1,192 @@
-use alloc::boxed::Box;
+use crate::time::Instant;
+use alloc::{boxed::Box, string::String};
 use core::{
+    alloc::Layout,
     future::Future,
     pin::Pin,
     task::{Context, Poll},
+    time::Duration,
 };
 
+pub mod channel;
+pub mod coop;
+pub mod deferred;
 pub mod executor;
+pub mod join;
 pub mod keyboard;
+pub mod local;
+pub mod recover;
+pub mod scope;
 pub mod simple_executor;
+pub mod smp_executor;
+pub mod sync;
+pub mod timer;
+
+pub use coop::yield_now;
+pub use join::{AbortHandle, JoinError, JoinHandle};
+pub use scope::CancelScope;
+
+/// Scheduling priority of a task. Ready tasks of a higher priority are polled
+/// first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl Priority {
+    pub const COUNT: usize = 3;
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
 
 pub struct Task {
     id: TaskId,
+    priority: Priority,
+    name: Option<String>,
+    polls: u64,
+    poll_time: Duration,
     future: Pin<Box<dyn Future<Output = ()>>>,
+    /// Reports a panic of the task to its [`JoinHandle`].
+    on_panic: Option<Box<dyn FnOnce(String)>>,
 }
 
 impl Task {
     pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
         Task {
             id: TaskId::new(),
+            priority: Priority::default(),
+            name: None,
+            polls: 0,
+            poll_time: Duration::ZERO,
             future: Box::pin(future),
+            on_panic: None,
+        }
+    }
+
+    /// Names the task in the executor's task list.
+    pub fn with_name(mut self, name: impl Into<String>) -> Task {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn with_priority(mut self, priority: Priority) -> Task {
+        self.priority = priority;
+        self
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Wraps a future with any output and returns a handle to await it.
+    pub fn with_handle<F>(future: F) -> (Task, JoinHandle<F::Output>)
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let (future, handle) = join::JoinFuture::new(future);
+        let on_panic = future.panic_reporter();
+        let mut task = Task::new(future);
+        task.on_panic = Some(Box::new(on_panic));
+        (task, handle)
+    }
+
+    /// Drops a task whose poll was abandoned by a recovered panic. Running
+    /// the future's destructor could drop values the poll had already moved
+    /// out, so only the memory of the future itself is freed; whatever it
+    /// owns leaks.
+    pub(crate) fn abandon(self) {
+        let future = unsafe { Pin::into_inner_unchecked(self.future) };
+        let layout = Layout::for_value(&*future);
+        let future = Box::into_raw(future);
+        if layout.size() != 0 {
+            unsafe { alloc::alloc::dealloc(future as *mut u8, layout) };
         }
     }
 
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+        let cpu = crate::percpu::current();
+        cpu.set_current_task(Some(self.id.0));
+        let start = Instant::now();
+        let result = self.future.as_mut().poll(context);
+        cpu.set_current_task(None);
+        self.polls += 1;
+        self.poll_time += start.elapsed();
+        result
+    }
+}
+
+/// The parts of a [`Task`] built from a `Send` future, so it can be handed
+/// to an executor from another task. Turned into a [`Task`] by the executor
+/// that runs it.
+pub(crate) struct SendTask {
+    priority: Priority,
+    name: Option<String>,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+    on_panic: Box<dyn FnOnce(String) + Send>,
+}
+
+impl SendTask {
+    pub(crate) fn with_handle<F>(future: F) -> (SendTask, JoinHandle<F::Output>)
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (future, handle) = join::JoinFuture::new(future);
+        let on_panic = future.panic_reporter();
+        let task = SendTask {
+            priority: Priority::default(),
+            name: None,
+            future: Box::pin(future),
+            on_panic: Box::new(on_panic),
+        };
+        (task, handle)
+    }
+
+    pub(crate) fn with_priority(mut self, priority: Priority) -> SendTask {
+        self.priority = priority;
+        self
     }
+
+    pub(crate) fn with_name(mut self, name: impl Into<String>) -> SendTask {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub(crate) fn into_task(self) -> Task {
+        Task {
+            id: TaskId::new(),
+            priority: self.priority,
+            name: self.name,
+            polls: 0,
+            poll_time: Duration::ZERO,
+            future: self.future,
+            on_panic: Some(self.on_panic),
+        }
+    }
+}
+
+/// Spawns `future` on the executor that is currently running.
+///
+/// # Panics
+/// Panics if no executor has been started yet.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    executor::Spawner::current()
+        .expect("task::spawn called without a running executor")
+        .spawn(future)
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
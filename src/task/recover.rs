@@ -0,0 +1,152 @@
+//! Recovering from panics in task polls without unwinding.
+//!
+//! [`catch`] runs a closure behind a recovery point. If the closure panics,
+//! [`try_recover`], called first thing by the panic handler, jumps back to
+//! that point instead of halting the kernel.
+//!
+//! The stack frames between the two are abandoned as if passed to
+//! `mem::forget`: their destructors do not run, so memory they own leaks.
+//! The [`IrqSafeMutex`](crate::sync::IrqSafeMutex)es they hold are released.
+//!
+//! Recovery points belong to the kernel thread that set them up, or to the
+//! CPU outside of threads, so a panic in one thread never resumes in another
+//! one that was preempted inside [`catch`].
+
+use alloc::string::String;
+use core::{
+    arch::global_asm,
+    fmt::{self, Write},
+    panic::PanicInfo,
+    ptr,
+};
+
+use crate::interrupts::stats;
+use crate::sync;
+
+const MESSAGE_CAPACITY: usize = 256;
+
+struct Landing {
+    /// Stack pointer to resume at, see `recover_call`.
+    rsp: u64,
+    /// Locks held when the recovery point was set up.
+    held_locks: usize,
+    message: [u8; MESSAGE_CAPACITY],
+    len: usize,
+}
+
+impl Write for Landing {
+    /// Keeps what fits, since the panic handler must not allocate.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let free = MESSAGE_CAPACITY - self.len;
+        let mut n = s.len().min(free);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.message[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// The innermost active recovery point of the running thread.
+fn landing() -> *mut Landing {
+    crate::percpu::current().landing() as *mut Landing
+}
+
+fn set_landing(landing: *mut Landing) {
+    crate::percpu::current().set_landing(landing as usize);
+}
+
+extern "C" {
+    /// Saves the callee-saved registers, stores the stack pointer in `rsp`
+    /// and calls `f(data)`. Returns 0 when `f` returns and 1 when
+    /// `recover_jump` was called with the stored stack pointer.
+    fn recover_call(f: extern "C" fn(*mut u8), data: *mut u8, rsp: *mut u64) -> u64;
+    fn recover_jump(rsp: u64) -> !;
+}
+
+global_asm!(
+    ".global recover_call",
+    "recover_call:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    // realign the stack to 16 bytes for the call
+    "sub rsp, 8",
+    "mov [rdx], rsp",
+    "mov rax, rdi",
+    "mov rdi, rsi",
+    "call rax",
+    "xor eax, eax",
+    "recover_resume:",
+    "add rsp, 8",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    ".global recover_jump",
+    "recover_jump:",
+    "mov rsp, rdi",
+    "mov eax, 1",
+    "jmp recover_resume",
+);
+
+extern "C" fn call_once<F: FnOnce() -> R, R>(data: *mut u8) {
+    let data = unsafe { &mut *(data as *mut (Option<F>, Option<R>)) };
+    let f = data.0.take().unwrap();
+    data.1 = Some(f());
+}
+
+/// Runs `f` and returns its result, or the panic message if it panicked.
+pub(crate) fn catch<F: FnOnce() -> R, R>(f: F) -> Result<R, String> {
+    let mut landing = Landing {
+        rsp: 0,
+        held_locks: sync::held(),
+        message: [0; MESSAGE_CAPACITY],
+        len: 0,
+    };
+    let landing: *mut Landing = &mut landing;
+    let mut data = (Some(f), None::<R>);
+
+    let previous = self::landing();
+    set_landing(landing);
+    let panicked = unsafe {
+        recover_call(
+            call_once::<F, R>,
+            &mut data as *mut _ as *mut u8,
+            ptr::addr_of_mut!((*landing).rsp),
+        )
+    };
+    set_landing(previous);
+
+    if panicked == 0 {
+        Ok(data.1.take().unwrap())
+    } else {
+        let landing = unsafe { &*landing };
+        unsafe { sync::release_held(landing.held_locks) };
+        Err(String::from_utf8_lossy(&landing.message[..landing.len]).into_owned())
+    }
+}
+
+/// Resumes at the innermost recovery point of the running thread, if there
+/// is one. Returns otherwise, including for panics in interrupt handlers.
+pub fn try_recover(info: &PanicInfo) {
+    if stats::in_interrupt() {
+        return;
+    }
+    // Disarm first, so that a panic while recovering halts as usual.
+    let landing = self::landing();
+    if landing.is_null() {
+        return;
+    }
+    set_landing(ptr::null_mut());
+    let landing = unsafe { &mut *landing };
+    let _ = write!(landing, "{}", info.message());
+    unsafe { recover_jump(landing.rsp) }
+}
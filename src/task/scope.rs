@@ -0,0 +1,136 @@
+//! Structured cancellation.
+//!
+//! Tasks spawned through a [`CancelScope`] belong to it, and so does every
+//! task they spawn in turn with [`super::spawn`] or a
+//! [`Spawner`](super::executor::Spawner). Cancelling or dropping the scope
+//! aborts all of them, so a subsystem can shut down its workers with one
+//! call.
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use super::{executor::Spawner, join::AbortHandle, JoinHandle};
+use crate::sync::IrqSafeMutex;
+
+crate::task_local! {
+    static CURRENT_SCOPE: Arc<ScopeInner>;
+}
+
+#[derive(Default)]
+struct ScopeInner {
+    cancelled: AtomicBool,
+    tasks: IrqSafeMutex<Vec<AbortHandle>>,
+    children: IrqSafeMutex<Vec<Weak<ScopeInner>>>,
+}
+
+impl ScopeInner {
+    fn cancel(&self) {
+        if self.cancelled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let tasks = core::mem::take(&mut *self.tasks.lock());
+        for task in tasks {
+            task.abort();
+        }
+        let children = core::mem::take(&mut *self.children.lock());
+        for child in children.iter().filter_map(Weak::upgrade) {
+            child.cancel();
+        }
+    }
+
+    fn add_task(&self, task: AbortHandle) {
+        let mut tasks = self.tasks.lock();
+        // `cancel` sets the flag before it takes the list.
+        if self.cancelled.load(Ordering::Acquire) {
+            drop(tasks);
+            task.abort();
+            return;
+        }
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(task);
+    }
+
+    fn add_child(&self, child: &Arc<ScopeInner>) {
+        let mut children = self.children.lock();
+        if self.cancelled.load(Ordering::Acquire) {
+            drop(children);
+            child.cancel();
+            return;
+        }
+        children.retain(|child| child.strong_count() > 0);
+        children.push(Arc::downgrade(child));
+    }
+}
+
+/// A group of tasks that is cancelled as a whole.
+///
+/// A scope created while a task of another scope is running becomes its
+/// child and is cancelled with it.
+pub struct CancelScope {
+    inner: Arc<ScopeInner>,
+}
+
+impl CancelScope {
+    pub fn new() -> Self {
+        let inner = Arc::new(ScopeInner::default());
+        if let Ok(parent) = CURRENT_SCOPE.try_with(Arc::clone) {
+            parent.add_child(&inner);
+        }
+        Self { inner }
+    }
+
+    /// Spawns `future` into this scope on the running executor.
+    ///
+    /// # Panics
+    /// Panics if no executor has been started yet.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let spawner = Spawner::current().expect("CancelScope::spawn without a running executor");
+        CURRENT_SCOPE.sync_scope(self.inner.clone(), || spawner.spawn(future))
+    }
+
+    /// Aborts all tasks of this scope and its child scopes. Tasks spawned
+    /// into it later are aborted right away.
+    pub fn cancel(&self) {
+        self.inner.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::Acquire)
+    }
+}
+
+impl Default for CancelScope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for CancelScope {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Makes a task being spawned run in the scope of the running code, if any.
+pub(crate) fn inherit<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let scope = CURRENT_SCOPE.try_with(Arc::clone).ok();
+    CURRENT_SCOPE.scope_option(scope, future)
+}
+
+/// Adds a task that was just spawned to the scope of the running code, if
+/// any.
+pub(crate) fn adopt<T: Send + 'static>(handle: &JoinHandle<T>) {
+    if let Ok(scope) = CURRENT_SCOPE.try_with(Arc::clone) {
+        scope.add_task(handle.abort_handle());
+    }
+}
@@ -0,0 +1,439 @@
+//! An executor that polls tasks on several CPUs at once.
+//!
+//! Every CPU that calls [`SmpExecutor::run`] gets its own run queue. A woken
+//! task goes back to the queue of the CPU that polled it last. A CPU without
+//! work steals half of another CPU's queue before it halts, and a wake that
+//! lands on the queue of a halted CPU sends it a wakeup IPI. Wakes that
+//! find every run queue full go to an overflow list that any CPU drains.
+//!
+//! Tasks may move between CPUs, so their futures must be `Send`.
+
+use super::{coop, deferred, join::JoinFuture, JoinHandle, TaskId};
+use crate::apic;
+use crate::percpu::{self, MAX_CPUS};
+use alloc::{boxed::Box, sync::Arc, task::Wake};
+use conquer_once::spin::OnceCell;
+use core::{
+    future::Future,
+    pin::Pin,
+    ptr,
+    sync::atomic::{
+        fence, AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering,
+    },
+    task::{Context, Waker},
+};
+use crossbeam_queue::{ArrayQueue, SegQueue};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Ready tasks a CPU's run queue can hold.
+const QUEUE_CAPACITY: usize = 256;
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+// Task states
+const IDLE: u8 = 0;
+const SCHEDULED: u8 = 1;
+const RUNNING: u8 = 2;
+/// Woken while being polled; queued again once the poll returns.
+const NOTIFIED: u8 = 3;
+const COMPLETE: u8 = 4;
+
+struct TaskCell {
+    id: TaskId,
+    state: AtomicU8,
+    /// The CPU whose run queue the task goes to when woken.
+    cpu: AtomicUsize,
+    future: Mutex<Option<BoxFuture>>,
+    shared: Arc<Shared>,
+    /// Next task in [`Shared::overflow`].
+    next_overflow: AtomicPtr<TaskCell>,
+}
+
+impl Wake for TaskCell {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    /// Never allocates, so tasks can be woken from interrupt handlers.
+    fn wake_by_ref(self: &Arc<Self>) {
+        let mut state = self.state.load(Ordering::Acquire);
+        let next = loop {
+            let next = match state {
+                IDLE => SCHEDULED,
+                RUNNING => NOTIFIED,
+                _ => return,
+            };
+            match self
+                .state
+                .compare_exchange_weak(state, next, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => break next,
+                Err(actual) => state = actual,
+            }
+        };
+        if next == SCHEDULED {
+            let cpu = self.cpu.load(Ordering::Relaxed);
+            self.shared.push(cpu, self.clone());
+        }
+    }
+}
+
+struct Cpu {
+    /// Set once the CPU has joined the executor.
+    queue: OnceCell<ArrayQueue<Arc<TaskCell>>>,
+    apic_id: AtomicU32,
+    /// Set while the CPU is halted or about to halt.
+    sleeping: AtomicBool,
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self {
+            queue: OnceCell::uninit(),
+            apic_id: AtomicU32::new(0),
+            sleeping: AtomicBool::new(false),
+        }
+    }
+}
+
+struct Shared {
+    cpus: [Cpu; MAX_CPUS],
+    cpu_count: AtomicUsize,
+    /// Spawned tasks that have not been polled yet.
+    injector: SegQueue<Arc<TaskCell>>,
+    /// Woken tasks that found every run queue full, as a lock-free stack
+    /// linked through [`TaskCell::next_overflow`]. Holds a reference to each.
+    overflow: AtomicPtr<TaskCell>,
+    live_tasks: AtomicUsize,
+    steals: AtomicU64,
+    ipis: AtomicU64,
+}
+
+impl Shared {
+    fn queue(&self, cpu: usize) -> Option<&ArrayQueue<Arc<TaskCell>>> {
+        self.cpus[cpu].queue.try_get().ok()
+    }
+
+    fn cpu_count(&self) -> usize {
+        self.cpu_count.load(Ordering::Acquire)
+    }
+
+    /// Adds the executing CPU, or returns its index if it already joined.
+    fn register_cpu(&self) -> usize {
+        let apic_id = apic::id();
+        if let Some(cpu) = self.find_cpu(apic_id) {
+            return cpu;
+        }
+        let cpu = self.cpu_count.fetch_add(1, Ordering::AcqRel);
+        assert!(cpu < MAX_CPUS, "more than {} CPUs in executor", MAX_CPUS);
+        self.cpus[cpu].apic_id.store(apic_id, Ordering::Relaxed);
+        self.cpus[cpu]
+            .queue
+            .init_once(|| ArrayQueue::new(QUEUE_CAPACITY));
+        cpu
+    }
+
+    fn find_cpu(&self, apic_id: u32) -> Option<usize> {
+        (0..self.cpu_count()).find(|&cpu| {
+            self.queue(cpu).is_some() && self.cpus[cpu].apic_id.load(Ordering::Relaxed) == apic_id
+        })
+    }
+
+    /// Queues a woken task on `cpu`, or on any other CPU if that queue is
+    /// full, or on the overflow list if all are.
+    fn push(&self, cpu: usize, task: Arc<TaskCell>) {
+        let count = self.cpu_count();
+        let mut task = task;
+        for target in (0..count).map(|offset| (cpu + offset) % count) {
+            let queue = match self.queue(target) {
+                Some(queue) => queue,
+                None => continue,
+            };
+            match queue.push(task) {
+                Ok(()) => {
+                    self.notify(target);
+                    return;
+                }
+                Err(err) => task = err.0,
+            }
+        }
+        self.push_overflow(task);
+        self.wake_any();
+    }
+
+    /// Intrusive, so that it never allocates.
+    fn push_overflow(&self, task: Arc<TaskCell>) {
+        let task = Arc::into_raw(task) as *mut TaskCell;
+        let mut head = self.overflow.load(Ordering::Relaxed);
+        loop {
+            unsafe { (*task).next_overflow.store(head, Ordering::Relaxed) };
+            match self.overflow.compare_exchange_weak(
+                head,
+                task,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(actual) => head = actual,
+            }
+        }
+    }
+
+    /// Takes the whole overflow list at once, which keeps popping free of
+    /// ABA problems. Returns one task and queues the rest again, starting
+    /// with `cpu`.
+    fn take_overflow(&self, cpu: usize) -> Option<Arc<TaskCell>> {
+        if self.overflow.load(Ordering::Relaxed).is_null() {
+            return None;
+        }
+        let mut next = self.overflow.swap(ptr::null_mut(), Ordering::Acquire);
+        let mut first = None;
+        while !next.is_null() {
+            let task = unsafe { Arc::from_raw(next) };
+            next = task.next_overflow.load(Ordering::Relaxed);
+            match first {
+                None => first = Some(task),
+                Some(_) => {
+                    task.cpu.store(cpu, Ordering::Relaxed);
+                    self.push(cpu, task);
+                }
+            }
+        }
+        first
+    }
+
+    /// Wakes `target` if it is halted. If it is busy and has a backlog,
+    /// wakes some other halted CPU to steal from it instead.
+    fn notify(&self, target: usize) {
+        fence(Ordering::SeqCst);
+        if self.cpus[target].sleeping.load(Ordering::Relaxed) {
+            self.send_wakeup(target);
+        } else if self.queue(target).is_some_and(|queue| queue.len() > 1) {
+            self.wake_any();
+        }
+    }
+
+    fn wake_any(&self) {
+        fence(Ordering::SeqCst);
+        if let Some(cpu) =
+            (0..self.cpu_count()).find(|&cpu| self.cpus[cpu].sleeping.load(Ordering::Relaxed))
+        {
+            self.send_wakeup(cpu);
+        }
+    }
+
+    fn send_wakeup(&self, cpu: usize) {
+        let apic_id = self.cpus[cpu].apic_id.load(Ordering::Relaxed);
+        // A halted CPU can only be woken by an interrupt; our own CPU is
+        // obviously awake.
+        if !apic::is_initialized() || apic_id == apic::id() {
+            return;
+        }
+        self.ipis.fetch_add(1, Ordering::Relaxed);
+        apic::send_ipi(apic_id, apic::WAKEUP_VECTOR);
+    }
+
+    fn next_task(&self, cpu: usize) -> Option<Arc<TaskCell>> {
+        self.queue(cpu)?
+            .pop()
+            .ok()
+            .or_else(|| self.injector.pop().ok())
+            .or_else(|| self.take_overflow(cpu))
+            .or_else(|| self.steal(cpu))
+    }
+
+    /// Takes half of the first non-empty run queue of another CPU. Returns
+    /// one of the stolen tasks and queues the rest on `cpu`.
+    fn steal(&self, cpu: usize) -> Option<Arc<TaskCell>> {
+        let count = self.cpu_count();
+        let own = self.queue(cpu)?;
+        for victim in (1..count).map(|offset| (cpu + offset) % count) {
+            let queue = match self.queue(victim) {
+                Some(queue) => queue,
+                None => continue,
+            };
+            let batch = queue.len().div_ceil(2);
+            let first = match queue.pop() {
+                Ok(task) => task,
+                Err(_) => continue,
+            };
+            for _ in 1..batch {
+                let task = match queue.pop() {
+                    Ok(task) => task,
+                    Err(_) => break,
+                };
+                task.cpu.store(cpu, Ordering::Relaxed);
+                if let Err(err) = own.push(task) {
+                    self.push(victim, err.0);
+                    break;
+                }
+            }
+            self.steals.fetch_add(1, Ordering::Relaxed);
+            return Some(first);
+        }
+        None
+    }
+
+    fn has_work(&self) -> bool {
+        !self.injector.is_empty()
+            || !self.overflow.load(Ordering::Relaxed).is_null()
+            || deferred::has_pending()
+            || (0..self.cpu_count())
+                .any(|cpu| self.queue(cpu).is_some_and(|queue| !queue.is_empty()))
+    }
+
+    fn run_task(&self, cpu: usize, task: Arc<TaskCell>) {
+        task.cpu.store(cpu, Ordering::Relaxed);
+        task.state.store(RUNNING, Ordering::Release);
+        let waker = Waker::from(task.clone());
+        let mut context = Context::from_waker(&waker);
+        coop::reset_budget();
+
+        let this_cpu = percpu::current();
+        this_cpu.set_current_task(Some(task.id.0));
+        let mut future = task.future.lock();
+        let ready = match future.as_mut() {
+            Some(future) => future.as_mut().poll(&mut context).is_ready(),
+            None => true,
+        };
+        this_cpu.set_current_task(None);
+        if ready {
+            *future = None;
+            drop(future);
+            task.state.store(COMPLETE, Ordering::Release);
+            self.live_tasks.fetch_sub(1, Ordering::Relaxed);
+            return;
+        }
+        drop(future);
+
+        if task
+            .state
+            .compare_exchange(RUNNING, IDLE, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            // woken while it was polled
+            task.state.store(SCHEDULED, Ordering::Release);
+            self.push(cpu, task);
+        }
+    }
+
+    fn run_ready_tasks(&self, cpu: usize) {
+        while let Some(task) = self.next_task(cpu) {
+            self.run_task(cpu, task);
+        }
+    }
+
+    fn sleep_if_idle(&self, cpu: usize) {
+        let sleeping = &self.cpus[cpu].sleeping;
+        interrupts::disable();
+        sleeping.store(true, Ordering::Relaxed);
+        fence(Ordering::SeqCst);
+        if !self.has_work() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+        sleeping.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Statistics of an [`SmpExecutor`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SmpMetrics {
+    /// CPUs that have joined the executor.
+    pub cpus: usize,
+    pub live_tasks: usize,
+    /// Times a CPU took work from another CPU's queue.
+    pub steals: u64,
+    /// Wakeup IPIs sent to halted CPUs.
+    pub ipis: u64,
+}
+
+/// A handle to a multi-core executor. Clones refer to the same executor, so
+/// every CPU can run it and every task can spawn onto it.
+#[derive(Clone)]
+pub struct SmpExecutor {
+    shared: Arc<Shared>,
+}
+
+impl SmpExecutor {
+    pub fn new() -> Self {
+        deferred::init();
+        Self {
+            shared: Arc::new(Shared {
+                cpus: Default::default(),
+                cpu_count: AtomicUsize::new(0),
+                injector: SegQueue::new(),
+                overflow: AtomicPtr::new(ptr::null_mut()),
+                live_tasks: AtomicUsize::new(0),
+                steals: AtomicU64::new(0),
+                ipis: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Spawns `future` and returns a handle to await its output. The task
+    /// runs on whichever CPU gets to it first.
+    ///
+    /// Allocates, so it must not be called from interrupt handlers.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (future, handle) = JoinFuture::new(future);
+        let task = Arc::new(TaskCell {
+            id: TaskId::new(),
+            state: AtomicU8::new(SCHEDULED),
+            cpu: AtomicUsize::new(0),
+            future: Mutex::new(Some(Box::pin(future))),
+            shared: self.shared.clone(),
+            next_overflow: AtomicPtr::new(ptr::null_mut()),
+        });
+        self.shared.live_tasks.fetch_add(1, Ordering::Relaxed);
+        self.shared.injector.push(task);
+        self.shared.wake_any();
+        handle
+    }
+
+    pub fn metrics(&self) -> SmpMetrics {
+        let shared = &self.shared;
+        SmpMetrics {
+            cpus: shared.cpu_count(),
+            live_tasks: shared.live_tasks.load(Ordering::Relaxed),
+            steals: shared.steals.load(Ordering::Relaxed),
+            ipis: shared.ipis.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Runs tasks on the calling CPU until no CPU has a ready task, e.g. for
+    /// tests.
+    pub fn run_until_idle(&self) {
+        let cpu = self.shared.register_cpu();
+        loop {
+            deferred::run_pending();
+            self.shared.run_ready_tasks(cpu);
+            if !self.shared.has_work() {
+                break;
+            }
+        }
+    }
+
+    /// Makes the calling CPU run tasks of this executor for good. Call it
+    /// on every CPU that should take part, with interrupts enabled.
+    pub fn run(&self) -> ! {
+        let cpu = self.shared.register_cpu();
+        loop {
+            deferred::run_pending();
+            self.shared.run_ready_tasks(cpu);
+            self.shared.sleep_if_idle(cpu);
+        }
+    }
+}
+
+impl Default for SmpExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,607 @@
+//! Synchronization primitives for tasks.
+//!
+//! Unlike `spin::Mutex`, waiting on these parks the task and lets the
+//! executor run others, so guards may be held across `.await`. Waiters are
+//! served in FIFO order.
+//!
+//! They are not for interrupt handlers: releasing a primitive may wake tasks
+//! while holding its internal lock. That lock is an [`IrqSafeMutex`], so a
+//! thread cannot be preempted while it holds it.
+
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use crate::sync::IrqSafeMutex;
+
+struct Waiter {
+    needed: usize,
+    granted: AtomicBool,
+}
+
+struct SemaphoreState {
+    permits: usize,
+    waiters: VecDeque<(Arc<Waiter>, Waker)>,
+}
+
+impl SemaphoreState {
+    /// Hands permits to waiters from the front of the queue. Stops at the
+    /// first waiter that needs more than is available, so a large request
+    /// is not overtaken by later smaller ones.
+    fn grant(&mut self) {
+        while let Some((waiter, _)) = self.waiters.front() {
+            if waiter.needed > self.permits {
+                break;
+            }
+            self.permits -= waiter.needed;
+            let (waiter, waker) = self.waiters.pop_front().unwrap();
+            waiter.granted.store(true, Ordering::Release);
+            waker.wake();
+        }
+    }
+}
+
+/// A counting semaphore with FIFO waiters.
+pub struct Semaphore {
+    state: IrqSafeMutex<SemaphoreState>,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            state: IrqSafeMutex::new(SemaphoreState {
+                permits,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    pub fn add_permits(&self, n: usize) {
+        let mut state = self.state.lock();
+        state.permits += n;
+        state.grant();
+    }
+
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    pub fn acquire_many(&self, n: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            needed: n,
+            waiter: None,
+        }
+    }
+
+    /// Takes a permit if one is free and nobody is queued for it.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.try_acquire_many(1)
+    }
+
+    pub fn try_acquire_many(&self, n: usize) -> Option<SemaphorePermit<'_>> {
+        let mut state = self.state.lock();
+        if state.waiters.is_empty() && state.permits >= n {
+            state.permits -= n;
+            Some(SemaphorePermit {
+                semaphore: self,
+                permits: n,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Semaphore")
+            .field("permits", &self.available_permits())
+            .finish()
+    }
+}
+
+/// Future returned by [`Semaphore::acquire`].
+///
+/// The task is queued on its first poll. Dropping the future gives up its
+/// place, and returns the permits if they had already been handed over.
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    needed: usize,
+    waiter: Option<Arc<Waiter>>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let semaphore = self.semaphore;
+        let needed = self.needed;
+        let mut state = semaphore.state.lock();
+        match &self.waiter {
+            Some(waiter) => {
+                if !waiter.granted.load(Ordering::Acquire) {
+                    if let Some((_, waker)) = state
+                        .waiters
+                        .iter_mut()
+                        .find(|(queued, _)| Arc::ptr_eq(queued, waiter))
+                    {
+                        if !waker.will_wake(cx.waker()) {
+                            *waker = cx.waker().clone();
+                        }
+                    }
+                    return Poll::Pending;
+                }
+            }
+            None => {
+                if !(state.waiters.is_empty() && state.permits >= needed) {
+                    let waiter = Arc::new(Waiter {
+                        needed,
+                        granted: AtomicBool::new(false),
+                    });
+                    state
+                        .waiters
+                        .push_back((waiter.clone(), cx.waker().clone()));
+                    drop(state);
+                    self.waiter = Some(waiter);
+                    return Poll::Pending;
+                }
+                state.permits -= needed;
+            }
+        }
+        drop(state);
+        self.waiter = None;
+        Poll::Ready(SemaphorePermit {
+            semaphore,
+            permits: needed,
+        })
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let waiter = match self.waiter.take() {
+            Some(waiter) => waiter,
+            None => return,
+        };
+        let mut state = self.semaphore.state.lock();
+        if waiter.granted.load(Ordering::Acquire) {
+            state.permits += waiter.needed;
+        } else {
+            state
+                .waiters
+                .retain(|(queued, _)| !Arc::ptr_eq(queued, &waiter));
+        }
+        state.grant();
+    }
+}
+
+/// Permits taken from a [`Semaphore`]; released on drop.
+#[must_use]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// Drops the permits without returning them to the semaphore.
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.add_permits(self.permits);
+        }
+    }
+}
+
+/// A mutex that can be held across `.await`.
+pub struct Mutex<T: ?Sized> {
+    semaphore: Semaphore,
+    data: UnsafeCell<T>,
+}
+
+// SAFETY: access to `data` is serialized by the semaphore.
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            semaphore: Semaphore::new(1),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        let permit = self.semaphore.acquire().await;
+        MutexGuard {
+            lock: self,
+            _permit: permit,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.semaphore.try_acquire().map(|permit| MutexGuard {
+            lock: self,
+            _permit: permit,
+        })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Mutex").finish_non_exhaustive()
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    lock: &'a Mutex<T>,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+/// Permits a writer takes, i.e. the maximum number of concurrent readers.
+const MAX_READERS: usize = usize::MAX >> 3;
+
+/// A reader-writer lock that can be held across `.await`.
+///
+/// Readers and writers queue in one FIFO, so a waiting writer blocks readers
+/// that arrive after it and cannot be starved.
+pub struct RwLock<T: ?Sized> {
+    semaphore: Semaphore,
+    data: UnsafeCell<T>,
+}
+
+// SAFETY: the semaphore allows either one writer or several readers.
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            semaphore: Semaphore::new(MAX_READERS),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        let permit = self.semaphore.acquire().await;
+        RwLockReadGuard {
+            lock: self,
+            _permit: permit,
+        }
+    }
+
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let permit = self.semaphore.acquire_many(MAX_READERS).await;
+        RwLockWriteGuard {
+            lock: self,
+            _permit: permit,
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.semaphore.try_acquire().map(|permit| RwLockReadGuard {
+            lock: self,
+            _permit: permit,
+        })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.semaphore
+            .try_acquire_many(MAX_READERS)
+            .map(|permit| RwLockWriteGuard {
+                lock: self,
+                _permit: permit,
+            })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RwLock").finish_non_exhaustive()
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+struct NotifyWaiter {
+    notified: AtomicBool,
+}
+
+struct NotifyState {
+    /// A [`Notify::notify_one`] that found nobody waiting.
+    permit: bool,
+    waiters: VecDeque<(Arc<NotifyWaiter>, Waker)>,
+}
+
+/// Wakes tasks waiting for an event.
+pub struct Notify {
+    state: IrqSafeMutex<NotifyState>,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Self {
+            state: IrqSafeMutex::new(NotifyState {
+                permit: false,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Waits for a notification. The task is queued on the first poll.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            waiter: None,
+        }
+    }
+
+    /// Wakes the longest-waiting task, or lets the next call to
+    /// [`notified`](Self::notified) complete immediately if none waits.
+    pub fn notify_one(&self) {
+        let mut state = self.state.lock();
+        match state.waiters.pop_front() {
+            Some((waiter, waker)) => {
+                waiter.notified.store(true, Ordering::Release);
+                waker.wake();
+            }
+            None => state.permit = true,
+        }
+    }
+
+    /// Wakes all tasks that are currently waiting. Stores no permit.
+    pub fn notify_waiters(&self) {
+        let waiters: Vec<_> = self.state.lock().waiters.drain(..).collect();
+        for (waiter, waker) in waiters {
+            waiter.notified.store(true, Ordering::Release);
+            waker.wake();
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Future returned by [`Notify::notified`].
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    waiter: Option<Arc<NotifyWaiter>>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.notify.state.lock();
+        match &self.waiter {
+            Some(waiter) => {
+                if !waiter.notified.load(Ordering::Acquire) {
+                    if let Some((_, waker)) = state
+                        .waiters
+                        .iter_mut()
+                        .find(|(queued, _)| Arc::ptr_eq(queued, waiter))
+                    {
+                        if !waker.will_wake(cx.waker()) {
+                            *waker = cx.waker().clone();
+                        }
+                    }
+                    return Poll::Pending;
+                }
+            }
+            None => {
+                if !core::mem::take(&mut state.permit) {
+                    let waiter = Arc::new(NotifyWaiter {
+                        notified: AtomicBool::new(false),
+                    });
+                    state
+                        .waiters
+                        .push_back((waiter.clone(), cx.waker().clone()));
+                    drop(state);
+                    self.waiter = Some(waiter);
+                    return Poll::Pending;
+                }
+            }
+        }
+        drop(state);
+        self.waiter = None;
+        Poll::Ready(())
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let waiter = match self.waiter.take() {
+            Some(waiter) => waiter,
+            None => return,
+        };
+        let mut state = self.notify.state.lock();
+        if waiter.notified.load(Ordering::Acquire) {
+            // Pass an unobserved notification on. This also stores a permit
+            // for `notify_waiters`, which is harmless: a spurious wake-up.
+            drop(state);
+            self.notify.notify_one();
+        } else {
+            state
+                .waiters
+                .retain(|(queued, _)| !Arc::ptr_eq(queued, &waiter));
+        }
+    }
+}
+
+struct BarrierState {
+    arrived: usize,
+    generation: u64,
+    waiters: Vec<Waker>,
+}
+
+/// Lets a fixed number of tasks wait until all of them have arrived.
+pub struct Barrier {
+    parties: usize,
+    state: IrqSafeMutex<BarrierState>,
+}
+
+impl Barrier {
+    pub const fn new(parties: usize) -> Self {
+        Self {
+            parties,
+            state: IrqSafeMutex::new(BarrierState {
+                arrived: 0,
+                generation: 0,
+                waiters: Vec::new(),
+            }),
+        }
+    }
+
+    /// Waits until `parties` tasks have called `wait`. The barrier can be
+    /// reused afterwards.
+    ///
+    /// Dropping the future after its first poll still counts the arrival.
+    pub fn wait(&self) -> BarrierWait<'_> {
+        BarrierWait {
+            barrier: self,
+            generation: None,
+        }
+    }
+}
+
+/// Future returned by [`Barrier::wait`].
+pub struct BarrierWait<'a> {
+    barrier: &'a Barrier,
+    generation: Option<u64>,
+}
+
+impl Future for BarrierWait<'_> {
+    type Output = BarrierWaitResult;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<BarrierWaitResult> {
+        let barrier = self.barrier;
+        let mut state = barrier.state.lock();
+        if let Some(generation) = self.generation {
+            if state.generation != generation {
+                return Poll::Ready(BarrierWaitResult(false));
+            }
+            if !state.waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                state.waiters.push(cx.waker().clone());
+            }
+            return Poll::Pending;
+        }
+
+        state.arrived += 1;
+        if state.arrived >= barrier.parties {
+            state.arrived = 0;
+            state.generation += 1;
+            for waker in state.waiters.drain(..) {
+                waker.wake();
+            }
+            return Poll::Ready(BarrierWaitResult(true));
+        }
+        self.generation = Some(state.generation);
+        state.waiters.push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierWaitResult(bool);
+
+impl BarrierWaitResult {
+    /// True for exactly one task per generation: the last one to arrive.
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
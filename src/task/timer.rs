@@ -0,0 +1,170 @@
+//! Timer futures driven by the timer interrupt.
+//!
+//! Pending deadlines are kept in a map ordered by tick. The timer interrupt
+//! only compares the current tick with the earliest deadline and, when it has
+//! passed, defers waking the expired timers to the executor.
+
+use alloc::collections::BTreeMap;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use super::deferred;
+use crate::sync::IrqSafeMutex;
+use crate::time::{self, Instant};
+
+/// (deadline tick, timer ID)
+type TimerKey = (u64, u64);
+
+static TIMERS: IrqSafeMutex<BTreeMap<TimerKey, Waker>> = IrqSafeMutex::new(BTreeMap::new());
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+static EXPIRE_SCHEDULED: AtomicBool = AtomicBool::new(false);
+
+/// Called by the timer interrupt after the tick counter was advanced.
+pub(crate) fn on_tick(now: u64) {
+    if now < NEXT_DEADLINE.load(Ordering::Relaxed) {
+        return;
+    }
+    if !EXPIRE_SCHEDULED.swap(true, Ordering::AcqRel) && deferred::schedule(expire).is_err() {
+        // try again on the next tick
+        EXPIRE_SCHEDULED.store(false, Ordering::Release);
+    }
+}
+
+fn expire() {
+    EXPIRE_SCHEDULED.store(false, Ordering::Release);
+
+    let now = time::ticks();
+    let expired = {
+        let mut timers = TIMERS.lock();
+        let pending = timers.split_off(&(now + 1, 0));
+        let expired = core::mem::replace(&mut *timers, pending);
+        update_next_deadline(&timers);
+        expired
+    };
+    for (_, waker) in expired {
+        waker.wake();
+    }
+}
+
+fn update_next_deadline(timers: &BTreeMap<TimerKey, Waker>) {
+    let next = timers.keys().next().map_or(u64::MAX, |&(tick, _)| tick);
+    NEXT_DEADLINE.store(next, Ordering::Relaxed);
+}
+
+fn next_timer_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Waits until `duration` has elapsed.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+/// Waits until `deadline` has been reached.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        key: None,
+    }
+}
+
+pub struct Sleep {
+    deadline: Instant,
+    key: Option<TimerKey>,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    pub fn is_elapsed(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+
+    fn unregister(&mut self) {
+        if let Some(key) = self.key.take() {
+            let mut timers = TIMERS.lock();
+            timers.remove(&key);
+            update_next_deadline(&timers);
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.is_elapsed() {
+            self.unregister();
+            return Poll::Ready(());
+        }
+
+        let mut timers = TIMERS.lock();
+        if let Some(waker) = self.key.and_then(|key| timers.get_mut(&key)) {
+            if !waker.will_wake(cx.waker()) {
+                *waker = cx.waker().clone();
+            }
+            return Poll::Pending;
+        }
+        // Not registered yet, or woken a tick early: register again from the
+        // current tick.
+        let key = (self.deadline.tick_deadline(), next_timer_id());
+        self.key = Some(key);
+        timers.insert(key, cx.waker().clone());
+        if key.0 < NEXT_DEADLINE.load(Ordering::Relaxed) {
+            NEXT_DEADLINE.store(key.0, Ordering::Relaxed);
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+/// Error returned by [`timeout`] when the deadline passed first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Runs `future` but gives up once `duration` has elapsed.
+pub fn timeout<F: Future>(future: F, duration: Duration) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
+
+/// `future` is structurally pinned: it is only reached through
+/// [`Pin::map_unchecked_mut`] in `poll`, never moved out, and `Timeout` has
+/// no `Drop` impl that could move it.
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: see the invariant on `Timeout`.
+        let future = unsafe { self.as_mut().map_unchecked_mut(|this| &mut this.future) };
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        // SAFETY: `Sleep` is `Unpin`, so pinning it is not a commitment.
+        let sleep = unsafe { self.map_unchecked_mut(|this| &mut this.sleep) };
+        match sleep.poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
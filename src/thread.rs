@@ -0,0 +1,501 @@
+//! Preemptive kernel threads.
+//!
+//! Every thread has its own stack. The timer interrupt switches round-robin
+//! between ready threads once the running one has used up its time slice;
+//! threads can also give up the CPU early through [`yield_now`], [`sleep`],
+//! [`park`] or [`JoinHandle::join`].
+//!
+//! [`init`] turns the code that calls it into the first thread, so the async
+//! executor can keep running there alongside the other threads.
+//!
+//! A thread is only preempted while interrupts are enabled. Data shared
+//! between threads must therefore be protected by an
+//! [`IrqSafeMutex`](crate::sync::IrqSafeMutex): a thread preempted while
+//! holding a `spin::Mutex` makes every other thread that wants it spin until
+//! the holder runs again.
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{alloc::Layout, fmt, time::Duration};
+use x86_64::{
+    instructions::interrupts,
+    registers::control::Cr3,
+    structures::paging::{Page, PhysFrame},
+    VirtAddr,
+};
+
+use crate::sync::IrqSafeMutex;
+use crate::time::{self, Instant};
+
+mod switch;
+
+/// Stack size of spawned threads. The stacks live on the heap, above an
+/// unmapped guard page once [`memory::install`](crate::memory::install) has
+/// run.
+pub const STACK_SIZE: usize = 4096 * 4;
+
+const GUARD_SIZE: usize = 4096;
+
+/// Timer ticks a thread may run before it is preempted.
+pub const TIME_SLICE_TICKS: u32 = 10;
+
+type Entry = Box<dyn FnOnce() + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        use core::sync::atomic::{AtomicU64, Ordering};
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Running,
+    Ready,
+    Sleeping,
+    /// Waiting in [`JoinHandle::join`].
+    Joining,
+    /// Waiting in [`park`].
+    Parked,
+}
+
+struct Thread {
+    state: State,
+    /// Saved stack pointer while the thread is switched out.
+    rsp: u64,
+    /// Saved stack for entries from user mode, see
+    /// [`CpuLocal::kernel_stack`](crate::percpu::CpuLocal::kernel_stack).
+    kernel_stack: VirtAddr,
+    /// Saved task-local values, see
+    /// [`CpuLocal::task_locals`](crate::percpu::CpuLocal::task_locals).
+    task_locals: usize,
+    /// Saved panic recovery point, see
+    /// [`CpuLocal::landing`](crate::percpu::CpuLocal::landing).
+    landing: usize,
+    /// The address space the thread runs in.
+    page_table: PhysFrame,
+    /// `None` for the thread that called [`init`], which runs on the boot
+    /// stack.
+    stack: Option<Stack>,
+    /// Threads waiting for this one to finish.
+    joiners: Vec<ThreadId>,
+    /// Set by [`unpark`] while the thread was not parked.
+    unparked: bool,
+}
+
+/// A thread stack above a guard page, so that an overflow faults instead of
+/// overwriting other heap memory.
+struct Stack {
+    /// Start of the guard page.
+    base: VirtAddr,
+}
+
+/// Stacks of finished threads, reused by new ones. They are never given back
+/// to the heap, so their guard pages can stay unmapped.
+static FREE_STACKS: IrqSafeMutex<Vec<Stack>> = IrqSafeMutex::new(Vec::new());
+
+impl Stack {
+    /// Reuses a free stack or allocates a new one. The guard page is only
+    /// unmapped if the kernel page table has been installed.
+    fn new() -> Stack {
+        if let Some(stack) = FREE_STACKS.lock().pop() {
+            return stack;
+        }
+        let layout = Layout::from_size_align(GUARD_SIZE + STACK_SIZE, GUARD_SIZE).unwrap();
+        let base = unsafe { alloc::alloc::alloc(layout) };
+        if base.is_null() {
+            alloc::alloc::handle_alloc_error(layout);
+        }
+        let base = VirtAddr::from_ptr(base);
+        crate::memory::with_kernel_memory(|mapper, _| {
+            crate::memory::unmap(mapper, Page::containing_address(base))
+                .expect("thread stack not mapped")
+        });
+        Stack { base }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        let bottom = self.base + GUARD_SIZE as u64;
+        unsafe { core::slice::from_raw_parts_mut(bottom.as_mut_ptr(), STACK_SIZE) }
+    }
+}
+
+struct Scheduler {
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    ready: VecDeque<ThreadId>,
+    /// (wake-up tick, thread)
+    sleepers: BTreeSet<(u64, ThreadId)>,
+    current: Option<ThreadId>,
+    idle: Option<ThreadId>,
+    slice_left: u32,
+    /// A finished thread whose stack is freed once we have switched away
+    /// from it.
+    zombie: Option<Box<Thread>>,
+}
+
+static SCHEDULER: IrqSafeMutex<Scheduler> = IrqSafeMutex::new(Scheduler {
+    threads: BTreeMap::new(),
+    ready: VecDeque::new(),
+    sleepers: BTreeSet::new(),
+    current: None,
+    idle: None,
+    slice_left: TIME_SLICE_TICKS,
+    zombie: None,
+});
+
+/// Stack pointers for a pending context switch.
+struct Switch {
+    old_rsp: *mut u64,
+    new_rsp: u64,
+}
+
+impl Scheduler {
+    fn is_initialized(&self) -> bool {
+        self.idle.is_some()
+    }
+
+    fn current(&self) -> ThreadId {
+        self.current.expect("thread::init has not been called")
+    }
+
+    fn make_ready(&mut self, id: ThreadId) {
+        if let Some(thread) = self.threads.get_mut(&id) {
+            thread.state = State::Ready;
+            self.ready.push_back(id);
+        }
+    }
+
+    fn wake_sleepers(&mut self, now: u64) {
+        while let Some(&(tick, id)) = self.sleepers.first() {
+            if tick > now {
+                break;
+            }
+            self.sleepers.remove(&(tick, id));
+            self.make_ready(id);
+        }
+    }
+
+    /// Picks the next thread and makes it current. The current thread must
+    /// already have been given its new state; it is queued again if that
+    /// state is `Ready`. Returns `None` if it keeps running.
+    fn switch_next(&mut self) -> Option<Switch> {
+        let current = self.current();
+        let idle = self.idle.expect("no idle thread");
+        let current_state = self.threads.get(&current).map(|thread| thread.state);
+
+        let next = match self.ready.pop_front() {
+            Some(next) => next,
+            None if current_state == Some(State::Ready) || current == idle => {
+                if let Some(thread) = self.threads.get_mut(&current) {
+                    thread.state = State::Running;
+                }
+                self.slice_left = TIME_SLICE_TICKS;
+                return None;
+            }
+            None => idle,
+        };
+        if current_state == Some(State::Ready) && current != idle {
+            self.ready.push_back(current);
+        }
+
+        let cpu = crate::percpu::current();
+        let old_thread = match self.threads.get_mut(&current) {
+            Some(thread) => thread,
+            None => self.zombie.as_mut().expect("current thread vanished"),
+        };
+        old_thread.kernel_stack = cpu.kernel_stack();
+        old_thread.task_locals = cpu.task_locals();
+        old_thread.landing = cpu.landing();
+        let (page_table, cr3_flags) = Cr3::read();
+        old_thread.page_table = page_table;
+        let old_rsp = &mut old_thread.rsp as *mut u64;
+        let next_thread = self.threads.get_mut(&next).expect("ready thread vanished");
+        next_thread.state = State::Running;
+        let new_rsp = next_thread.rsp;
+        cpu.set_kernel_stack(next_thread.kernel_stack);
+        cpu.set_task_locals(next_thread.task_locals);
+        cpu.set_landing(next_thread.landing);
+        if next_thread.page_table != page_table {
+            unsafe { Cr3::write(next_thread.page_table, cr3_flags) };
+        }
+        self.current = Some(next);
+        cpu.set_current_thread(Some(next.as_u64()));
+        self.slice_left = TIME_SLICE_TICKS;
+        Some(Switch { old_rsp, new_rsp })
+    }
+}
+
+/// Switches to the next thread. `prepare` returns the new state of the
+/// current thread.
+fn schedule(prepare: impl FnOnce(&mut Scheduler, ThreadId) -> State) {
+    let interrupts_were_enabled = interrupts::are_enabled();
+    interrupts::disable();
+    let switch = {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current();
+        let state = prepare(&mut scheduler, current);
+        if let Some(thread) = scheduler.threads.get_mut(&current) {
+            thread.state = state;
+        }
+        scheduler.switch_next()
+    };
+    if let Some(switch) = switch {
+        unsafe { switch::switch_context(switch.old_rsp, switch.new_rsp) };
+        after_switch();
+    }
+    if interrupts_were_enabled {
+        interrupts::enable();
+    }
+}
+
+/// Runs on the new thread right after every switch.
+fn after_switch() {
+    let zombie = SCHEDULER.lock().zombie.take();
+    if let Some(stack) = zombie.and_then(|thread| thread.stack) {
+        FREE_STACKS.lock().push(stack);
+    }
+}
+
+/// Turns the caller into the first thread and starts preemption. Needs the
+/// heap.
+pub fn init() {
+    let main = ThreadId::new();
+    {
+        let mut scheduler = SCHEDULER.lock();
+        assert!(!scheduler.is_initialized(), "thread::init called twice");
+        scheduler.threads.insert(
+            main,
+            Box::new(Thread {
+                state: State::Running,
+                rsp: 0,
+                kernel_stack: crate::percpu::current().kernel_stack(),
+                task_locals: 0,
+                landing: 0,
+                page_table: Cr3::read().0,
+                stack: None,
+                joiners: Vec::new(),
+                unparked: false,
+            }),
+        );
+        scheduler.current = Some(main);
+        crate::percpu::current().set_current_thread(Some(main.as_u64()));
+    }
+
+    let idle = create(Box::new(|| {
+        crate::hlt_loop();
+    }));
+    SCHEDULER.lock().idle = Some(idle);
+}
+
+/// Allocates a thread that starts by calling `entry`, without queuing it.
+fn create(entry: Entry) -> ThreadId {
+    let id = ThreadId::new();
+    let mut stack = Stack::new();
+    let rsp = switch::prepare_stack(stack.as_mut_slice(), Box::into_raw(Box::new(entry)));
+    SCHEDULER.lock().threads.insert(
+        id,
+        Box::new(Thread {
+            state: State::Ready,
+            rsp,
+            kernel_stack: crate::percpu::current().kernel_stack(),
+            task_locals: 0,
+            landing: 0,
+            page_table: Cr3::read().0,
+            stack: Some(stack),
+            joiners: Vec::new(),
+            unparked: false,
+        }),
+    );
+    id
+}
+
+/// First Rust code a new thread runs.
+extern "C" fn thread_start(entry: *mut Entry) -> ! {
+    after_switch();
+    // The switch to a new thread may come from the timer interrupt, which
+    // runs with interrupts disabled.
+    interrupts::enable();
+    let entry = unsafe { Box::from_raw(entry) };
+    entry();
+    exit();
+}
+
+/// Ends the current thread and wakes threads joining it.
+fn exit() -> ! {
+    schedule(|scheduler, current| {
+        let thread = scheduler
+            .threads
+            .remove(&current)
+            .expect("current thread vanished");
+        for &joiner in &thread.joiners {
+            scheduler.make_ready(joiner);
+        }
+        scheduler.zombie = Some(thread);
+        State::Ready
+    });
+    unreachable!("finished thread was scheduled again");
+}
+
+/// Spawns a thread that runs `f` and returns a handle to join it.
+///
+/// # Panics
+/// Panics if [`init`] has not been called.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    assert!(
+        SCHEDULER.lock().is_initialized(),
+        "thread::init has not been called"
+    );
+    let packet = Arc::new(IrqSafeMutex::new(None));
+    let result = packet.clone();
+    let id = create(Box::new(move || {
+        let output = f();
+        *result.lock() = Some(output);
+    }));
+    SCHEDULER.lock().make_ready(id);
+    JoinHandle { id, packet }
+}
+
+/// The ID of the running thread.
+pub fn current() -> ThreadId {
+    SCHEDULER.lock().current()
+}
+
+/// The ID of the running thread, or `None` before [`init`] and on CPUs
+/// that do not run threads. Safe to call from the panic handler.
+pub(crate) fn try_current() -> Option<ThreadId> {
+    crate::percpu::current().current_thread().map(ThreadId)
+}
+
+/// Lets other ready threads run before the current one continues.
+pub fn yield_now() {
+    schedule(|_, _| State::Ready);
+}
+
+/// Blocks the current thread for at least `duration`.
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        let tick = deadline.tick_deadline();
+        schedule(|scheduler, current| {
+            scheduler.sleepers.insert((tick, current));
+            State::Sleeping
+        });
+    }
+}
+
+/// Blocks the current thread until [`unpark`] is called for it. Returns at
+/// once if that already happened since the last `park`.
+pub fn park() {
+    schedule(|scheduler, current| {
+        let thread = scheduler
+            .threads
+            .get_mut(&current)
+            .expect("current thread vanished");
+        if core::mem::take(&mut thread.unparked) {
+            State::Ready
+        } else {
+            State::Parked
+        }
+    });
+}
+
+/// Wakes the thread `id` from [`park`], or makes its next `park` return at
+/// once. Does nothing if the thread has finished.
+pub fn unpark(id: ThreadId) {
+    let mut scheduler = SCHEDULER.lock();
+    if let Some(thread) = scheduler.threads.get_mut(&id) {
+        if thread.state == State::Parked {
+            scheduler.make_ready(id);
+        } else {
+            thread.unparked = true;
+        }
+    }
+}
+
+/// Called by the timer interrupt after it has sent the EOI.
+pub(crate) fn on_tick() {
+    let switch = {
+        let mut scheduler = match SCHEDULER.try_lock() {
+            Some(scheduler) if scheduler.is_initialized() => scheduler,
+            _ => return,
+        };
+        scheduler.wake_sleepers(time::ticks());
+
+        let current = scheduler.current();
+        let idle = scheduler.idle == Some(current);
+        scheduler.slice_left = scheduler.slice_left.saturating_sub(1);
+        if (scheduler.slice_left > 0 && !idle) || scheduler.ready.is_empty() {
+            return;
+        }
+        if let Some(thread) = scheduler.threads.get_mut(&current) {
+            thread.state = State::Ready;
+        }
+        scheduler.switch_next()
+    };
+    if let Some(switch) = switch {
+        unsafe { switch::switch_context(switch.old_rsp, switch.new_rsp) };
+        after_switch();
+    }
+}
+
+/// An owned permission to join a thread.
+///
+/// Dropping the handle detaches the thread.
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    packet: Arc<IrqSafeMutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn thread_id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        !SCHEDULER.lock().threads.contains_key(&self.id)
+    }
+
+    /// Blocks the current thread until the thread has finished and returns
+    /// its output.
+    pub fn join(self) -> T {
+        let target = self.id;
+        while !self.is_finished() {
+            schedule(
+                |scheduler, current| match scheduler.threads.get_mut(&target) {
+                    Some(thread) => {
+                        thread.joiners.push(current);
+                        State::Joining
+                    }
+                    // finished in the meantime
+                    None => State::Ready,
+                },
+            );
+        }
+        self.packet
+            .lock()
+            .take()
+            .expect("joined thread produced no output")
+    }
+}
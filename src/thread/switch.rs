@@ -0,0 +1,53 @@
+//! The context switch.
+
+use core::arch::global_asm;
+
+use super::thread_start;
+
+extern "C" {
+    /// Saves the callee-saved registers on the current stack, stores the
+    /// stack pointer in `old_rsp` and resumes the thread whose stack pointer
+    /// is `new_rsp`.
+    pub fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn thread_trampoline();
+}
+
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    // A new thread's first switch returns here with its entry point in r12.
+    "thread_trampoline:",
+    "mov rdi, r12",
+    "call {start}",
+    "ud2",
+    start = sym thread_start,
+);
+
+/// Lays out `stack` like a thread that was switched out just before calling
+/// the trampoline, and returns its stack pointer.
+pub(super) fn prepare_stack(stack: &mut [u8], entry: *mut super::Entry) -> u64 {
+    let top = (stack.as_mut_ptr() as u64 + stack.len() as u64) & !0xf;
+    let trampoline = thread_trampoline as unsafe extern "C" fn() as usize as u64;
+    // r15, r14, r13, r12, rbx, rbp and the return address
+    let frame: [u64; 7] = [0, 0, 0, entry as u64, 0, 0, trampoline];
+    // The return address sits 8 bytes below the 16-byte aligned top, so the
+    // trampoline's `call` sees a correctly aligned stack.
+    let rsp = top - 8 * frame.len() as u64;
+    unsafe { (rsp as *mut [u64; 7]).write(frame) };
+    rsp
+}
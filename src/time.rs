@@ -0,0 +1,173 @@
+use core::arch::x86_64::_rdtsc;
+use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+pub mod pit;
+
+/// Timer interrupt rate set up by [`init`].
+pub const DEFAULT_TIMER_HZ: u32 = 1000;
+
+const CALIBRATION_MICROS: u32 = 10_000;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Timer interrupt period. Starts at the PIT's power-on default rate
+/// (1193182 Hz / 65536) until [`init`] reprograms it.
+static TICK_NANOS: AtomicU64 = AtomicU64::new(54_925_493);
+/// 0 until the TSC has been calibrated.
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+static TSC_AT_BOOT: AtomicU64 = AtomicU64::new(0);
+
+/// Programs the timer interrupt to [`DEFAULT_TIMER_HZ`] and calibrates the
+/// TSC against the PIT.
+pub fn init() {
+    init_with_frequency(DEFAULT_TIMER_HZ);
+}
+
+/// Programs the timer interrupt to `frequency_hz` and calibrates the TSC
+/// against the PIT. Must be called before interrupts are enabled.
+pub fn init_with_frequency(frequency_hz: u32) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let tick_nanos = pit::set_frequency(frequency_hz);
+        TICK_NANOS.store(tick_nanos, Ordering::Relaxed);
+
+        let cycles = pit::measure_tsc(CALIBRATION_MICROS);
+        let tsc_hz = cycles * 1_000_000 / u64::from(CALIBRATION_MICROS);
+        // Anchor both clocks at the same moment so that tick-based deadlines
+        // and TSC-based instants agree.
+        TICKS.store(0, Ordering::Relaxed);
+        TSC_AT_BOOT.store(unsafe { _rdtsc() }, Ordering::Relaxed);
+        TSC_HZ.store(tsc_hz, Ordering::Relaxed);
+    });
+}
+
+/// Advances the kernel tick counter. Called by the timer interrupt only.
+pub(crate) fn tick() -> u64 {
+    TICKS.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+/// Number of timer interrupts since the clock was initialized.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn tick_period() -> Duration {
+    Duration::from_nanos(TICK_NANOS.load(Ordering::Relaxed))
+}
+
+/// Calibrated TSC frequency, if [`init`] has run.
+pub fn tsc_frequency() -> Option<u64> {
+    match TSC_HZ.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+/// Converts a number of TSC cycles to a duration.
+pub fn cycles_to_duration(cycles: u64) -> Option<Duration> {
+    tsc_frequency().map(|hz| {
+        Duration::from_nanos(
+            (u128::from(cycles) * u128::from(NANOS_PER_SEC) / u128::from(hz)) as u64,
+        )
+    })
+}
+
+/// A point in time since boot, measured by the monotonic kernel clock.
+///
+/// Uses the TSC once it is calibrated and falls back to counting timer
+/// interrupts before that.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        match tsc_frequency() {
+            Some(_) => {
+                let cycles =
+                    unsafe { _rdtsc() }.saturating_sub(TSC_AT_BOOT.load(Ordering::Relaxed));
+                Instant(cycles_to_duration(cycles).unwrap_or_default().as_nanos() as u64)
+            }
+            None => Instant(ticks() * TICK_NANOS.load(Ordering::Relaxed)),
+        }
+    }
+
+    pub fn from_nanos(nanos: u64) -> Self {
+        Instant(nanos)
+    }
+
+    /// Nanoseconds since boot.
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    /// The tick by which this instant should have passed.
+    ///
+    /// Counted from the current tick rather than from boot, so that drift
+    /// between the TSC and the timer interrupt cannot delay it. It can come
+    /// up to one tick early, so callers check the instant again.
+    pub(crate) fn tick_deadline(&self) -> u64 {
+        let tick_nanos = TICK_NANOS.load(Ordering::Relaxed);
+        let remaining = self.0.saturating_sub(Instant::now().0);
+        ticks() + remaining.div_ceil(tick_nanos)
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        u64::try_from(duration.as_nanos())
+            .ok()
+            .and_then(|nanos| self.0.checked_add(nanos))
+            .map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+#[test_case]
+fn test_instant_is_monotonic() {
+    let earlier = Instant::now();
+    let later = Instant::now();
+    assert!(later >= earlier);
+}
+
+#[test_case]
+fn test_clock_follows_timer() {
+    let start = Instant::now();
+    let start_tick = ticks();
+    while ticks() < start_tick + 2 {
+        x86_64::instructions::hlt();
+    }
+    let elapsed = start.elapsed();
+    assert!(elapsed >= tick_period());
+    assert!(elapsed <= tick_period() * 5);
+}
@@ -0,0 +1,74 @@
+//! 8253/8254 programmable interval timer.
+
+use x86_64::instructions::port::Port;
+
+/// Input clock of all PIT channels.
+pub const BASE_FREQUENCY_HZ: u32 = 1_193_182;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+const PORT_B: u16 = 0x61;
+
+/// channel 0, lobyte/hibyte, mode 2 (rate generator), binary
+const CHANNEL_0_RATE_GENERATOR: u8 = 0b0011_0100;
+/// channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+const CHANNEL_2_ONE_SHOT: u8 = 0b1011_0000;
+
+const PORT_B_GATE_2: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUT_2: u8 = 1 << 5;
+
+fn divisor(frequency_hz: u32) -> u32 {
+    (BASE_FREQUENCY_HZ / frequency_hz.max(1)).clamp(1, 0x10000)
+}
+
+/// Reprograms channel 0, which drives IRQ 0, to fire at about `frequency_hz`.
+///
+/// Returns the period actually programmed in nanoseconds, which differs from
+/// the requested one because the divisor is an integer.
+pub fn set_frequency(frequency_hz: u32) -> u64 {
+    let divisor = divisor(frequency_hz);
+    // A divisor of 0x10000 is written as 0.
+    let reload = divisor as u16;
+
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut channel_0: Port<u8> = Port::new(CHANNEL_0);
+    unsafe {
+        command.write(CHANNEL_0_RATE_GENERATOR);
+        channel_0.write(reload as u8);
+        channel_0.write((reload >> 8) as u8);
+    }
+    u64::from(divisor) * 1_000_000_000 / u64::from(BASE_FREQUENCY_HZ)
+}
+
+/// Busy-waits for `micros` using channel 2 and returns the number of TSC
+/// cycles that elapsed meanwhile.
+///
+/// Must be called with interrupts disabled. `micros` must not exceed the
+/// 16-bit counter range, about 54 ms.
+pub fn measure_tsc(micros: u32) -> u64 {
+    use core::arch::x86_64::_rdtsc;
+
+    let count = u64::from(BASE_FREQUENCY_HZ) * u64::from(micros) / 1_000_000;
+    assert!(count > 0 && count <= 0xffff, "PIT one-shot out of range");
+
+    let mut port_b: Port<u8> = Port::new(PORT_B);
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut channel_2: Port<u8> = Port::new(CHANNEL_2);
+    unsafe {
+        // gate on, speaker off
+        let value = port_b.read();
+        port_b.write((value & !PORT_B_SPEAKER) | PORT_B_GATE_2);
+
+        command.write(CHANNEL_2_ONE_SHOT);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+
+        let start = _rdtsc();
+        while port_b.read() & PORT_B_OUT_2 == 0 {
+            core::hint::spin_loop();
+        }
+        _rdtsc() - start
+    }
+}
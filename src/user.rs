@@ -0,0 +1,143 @@
+//! Running code in user mode (ring 3).
+//!
+//! [`enter_user_mode`] `iretq`s to user code and returns once the user code
+//! executes `int 0x80` ([`EXIT_VECTOR`]) or the exit system call. Interrupts
+//! and system calls taken in user mode run on the kernel stack of the thread
+//! that entered it, right below the frame of [`enter_user_mode`].
+//!
+//! User code runs with `GS_BASE` 0 and the per-CPU block in `KERNEL_GS_BASE`,
+//! so every way in and out of user mode `swapgs`es.
+
+use core::arch::global_asm;
+use x86_64::{instructions::interrupts, VirtAddr};
+
+use crate::{gdt, percpu};
+
+/// Interrupt that user code raises to return to the kernel. The user's
+/// `rax` becomes the return value of [`enter_user_mode`].
+pub const EXIT_VECTOR: u8 = 0x80;
+
+/// Start of user memory in an address space: the level 4 slots from here
+/// on belong to user code and are never shared with the kernel.
+pub const USER_SPACE_START: u64 = 0x0000_6000_0000_0000;
+/// End of the lower half of the address space, where user memory lives.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+extern "C" {
+    fn user_enter(entry: u64, stack: u64, code_selector: u64, data_selector: u64) -> u64;
+    fn user_exit();
+    fn user_return(value: u64) -> !;
+}
+
+global_asm!(
+    ".global user_enter",
+    "user_enter:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    // The CPU aligns the stack to 16 bytes before it pushes an interrupt
+    // frame, so the frame must not start above an unaligned stack pointer.
+    "sub rsp, 8",
+    "mov r12, rdi",
+    "mov r13, rsi",
+    "mov r14, rdx",
+    "mov r15, rcx",
+    "mov rdi, rsp",
+    "call {set_kernel_stack}",
+    // iretq frame: ss, rsp, rflags with IF set, cs, rip
+    "push r15",
+    "push r13",
+    "push 0x202",
+    "push r14",
+    "push r12",
+    // Leave no kernel values in the registers.
+    "xor eax, eax",
+    "xor ebx, ebx",
+    "xor ecx, ecx",
+    "xor edx, edx",
+    "xor esi, esi",
+    "xor edi, edi",
+    "xor ebp, ebp",
+    "xor r8d, r8d",
+    "xor r9d, r9d",
+    "xor r10d, r10d",
+    "xor r11d, r11d",
+    "xor r12d, r12d",
+    "xor r13d, r13d",
+    "xor r14d, r14d",
+    "xor r15d, r15d",
+    // No interrupt may see the user's GS_BASE with a kernel CS.
+    "cli",
+    "swapgs",
+    "iretq",
+    // Called in kernel mode, GS_BASE already points at the per-CPU block.
+    ".global user_return",
+    "user_return:",
+    "mov rax, rdi",
+    "jmp user_unwind",
+    // The handler for `EXIT_VECTOR`. Drops the interrupt frame and returns
+    // from `user_enter` with the user's rax.
+    ".global user_exit",
+    "user_exit:",
+    "swapgs",
+    "user_unwind:",
+    "mov rsp, gs:[{kernel_stack}]",
+    "add rsp, 8",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    set_kernel_stack = sym set_kernel_stack,
+    kernel_stack = const percpu::KERNEL_STACK_OFFSET,
+);
+
+extern "C" fn set_kernel_stack(top: u64) {
+    percpu::current().set_kernel_stack(VirtAddr::new(top));
+}
+
+/// Address of the handler to install for [`EXIT_VECTOR`].
+pub(crate) fn exit_handler() -> VirtAddr {
+    VirtAddr::new(user_exit as unsafe extern "C" fn() as usize as u64)
+}
+
+/// Abandons the kernel stack down to [`enter_user_mode`] and makes it
+/// return `value`.
+///
+/// # Safety
+/// Must run on behalf of user code, on the stack of the thread that entered
+/// user mode. Nothing on the abandoned part of the stack is dropped.
+pub(crate) unsafe fn return_to_kernel(value: u64) -> ! {
+    user_return(value)
+}
+
+/// Runs user code at `entry` on the user stack `stack` until it raises
+/// [`EXIT_VECTOR`] or makes the exit system call, and returns its `rax` or
+/// the exit code. The user code runs with interrupts enabled.
+///
+/// # Safety
+/// `entry` and `stack` must be mapped with `USER_ACCESSIBLE`, and the
+/// kernel must not rely on anything user code can reach through them.
+pub unsafe fn enter_user_mode(entry: VirtAddr, stack: VirtAddr) -> u64 {
+    let selectors = gdt::selectors();
+    let interrupts_were_enabled = interrupts::are_enabled();
+    let result = user_enter(
+        entry.as_u64(),
+        stack.as_u64(),
+        u64::from(selectors.user_code_selector.0),
+        u64::from(selectors.user_data_selector.0),
+    );
+    // The exit handler is an interrupt gate, the exit system call runs with
+    // interrupts enabled.
+    if interrupts_were_enabled {
+        interrupts::enable();
+    } else {
+        interrupts::disable();
+    }
+    result
+}
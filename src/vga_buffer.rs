@@ -1,6 +1,6 @@
+use crate::sync::IrqSafeMutex;
 use core::fmt;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use volatile::Volatile;
 
 #[macro_export]
@@ -16,11 +16,22 @@ macro_rules! println {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    WRITER.lock().write_fmt(args).unwrap();
+}
+
+/// Prints `args` unless the console is in use, e.g. by the code an
+/// exception interrupted. Returns whether it printed.
+pub fn try_print(args: fmt::Arguments) -> bool {
+    use core::fmt::Write;
+
+    match WRITER.try_lock() {
+        Some(mut writer) => {
+            writer.write_fmt(args).unwrap();
+            true
+        }
+        None => false,
+    }
 }
 
 #[allow(dead_code)]
@@ -140,7 +151,7 @@ impl fmt::Write for Writer {
 }
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+    pub static ref WRITER: IrqSafeMutex<Writer> = IrqSafeMutex::new(Writer {
         column_position: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
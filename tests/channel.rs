@@ -0,0 +1,151 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{rc::Rc, vec::Vec};
+use blog_os::task::{
+    channel::{broadcast, mpsc, oneshot},
+    executor::Executor,
+};
+use bootloader::{entry_point, BootInfo};
+use core::cell::RefCell;
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn bounded_mpsc_applies_backpressure() {
+    let mut executor = Executor::new();
+    let (tx, mut rx) = mpsc::channel(2);
+    let received = Rc::new(RefCell::new(Vec::new()));
+
+    for id in 0..2 {
+        let tx = tx.clone();
+        executor.spawn(async move {
+            for i in 0..5 {
+                tx.send(id * 10 + i).await.unwrap();
+            }
+        });
+    }
+    drop(tx);
+    let received_clone = received.clone();
+    executor.spawn(async move {
+        while let Some(value) = rx.recv().await {
+            received_clone.borrow_mut().push(value);
+        }
+    });
+    executor.run_until_idle();
+
+    let mut received = received.take();
+    received.sort_unstable();
+    assert_eq!(received, [0, 1, 2, 3, 4, 10, 11, 12, 13, 14]);
+}
+
+#[test_case]
+fn try_send_reports_full_and_closed() {
+    let (tx, mut rx) = mpsc::channel(1);
+
+    assert_eq!(tx.try_send(1), Ok(()));
+    assert_eq!(tx.try_send(2), Err(mpsc::TrySendError::Full(2)));
+    assert_eq!(rx.try_recv(), Ok(1));
+    assert_eq!(rx.try_recv(), Err(mpsc::TryRecvError::Empty));
+    drop(rx);
+    assert_eq!(tx.try_send(3), Err(mpsc::TrySendError::Closed(3)));
+}
+
+#[test_case]
+fn unbounded_mpsc_never_blocks() {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    for i in 0..1000 {
+        tx.send(i).unwrap();
+    }
+    drop(tx);
+    for i in 0..1000 {
+        assert_eq!(rx.try_recv(), Ok(i));
+    }
+    assert_eq!(rx.try_recv(), Err(mpsc::TryRecvError::Disconnected));
+}
+
+#[test_case]
+fn oneshot_delivers_value_or_error() {
+    let mut executor = Executor::new();
+    let results = Rc::new(RefCell::new(Vec::new()));
+
+    let (tx, rx) = oneshot::channel();
+    let (dropped_tx, dropped_rx) = oneshot::channel::<u32>();
+    let results_clone = results.clone();
+    executor.spawn(async move {
+        let value = rx.await;
+        results_clone.borrow_mut().push(value);
+        let dropped = dropped_rx.await;
+        results_clone.borrow_mut().push(dropped);
+    });
+    executor.run_until_idle();
+    assert!(results.borrow().is_empty());
+
+    tx.send(7).unwrap();
+    drop(dropped_tx);
+    executor.run_until_idle();
+    assert_eq!(*results.borrow(), [Ok(7), Err(oneshot::RecvError)]);
+}
+
+#[test_case]
+fn broadcast_reaches_every_receiver() {
+    let mut executor = Executor::new();
+    let (tx, rx) = broadcast::channel(4);
+    let received = Rc::new(RefCell::new(Vec::new()));
+
+    for mut rx in [rx, tx.subscribe()] {
+        let received = received.clone();
+        executor.spawn(async move {
+            while let Ok(value) = rx.recv().await {
+                received.borrow_mut().push(value);
+            }
+        });
+    }
+    executor.run_until_idle();
+    assert_eq!(tx.send(1), Ok(2));
+    assert_eq!(tx.send(2), Ok(2));
+    drop(tx);
+    executor.run_until_idle();
+
+    assert_eq!(*received.borrow(), [1, 2, 1, 2]);
+}
+
+#[test_case]
+fn broadcast_receiver_reports_lag() {
+    let (tx, mut rx) = broadcast::channel(2);
+
+    for i in 0..5 {
+        tx.send(i).unwrap();
+    }
+    assert_eq!(rx.try_recv(), Err(broadcast::TryRecvError::Lagged(3)));
+    assert_eq!(rx.try_recv(), Ok(3));
+    assert_eq!(rx.try_recv(), Ok(4));
+    assert_eq!(rx.try_recv(), Err(broadcast::TryRecvError::Empty));
+}
@@ -0,0 +1,224 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use blog_os::elf::{self, ElfError};
+use blog_os::memory::{self, address_space::MapError};
+use blog_os::user::USER_SPACE_START;
+use blog_os::{allocator, syscall, thread};
+use bootloader::{entry_point, BootInfo};
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+use core::ptr;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+const CODE_VADDR: u64 = 0x_6000_0000_0000;
+const DATA_VADDR: u64 = 0x_6000_0001_0000;
+const CODE_OFFSET: usize = 0x1000;
+const DATA_OFFSET: usize = 0x2000;
+/// The initialized word of the data segment; a zeroed word follows it.
+const DATA_WORD: u64 = 40;
+
+extern "C" {
+    static elf_program_start: u8;
+    static elf_program_end: u8;
+}
+
+// Exits with argc * 1000 + strlen(argv[0]) + both data words, after writing
+// argv[0] and storing 1 to the bss word. Adds 100000 if the stack pointer
+// is not 16-byte aligned at entry.
+global_asm!(
+    ".section .rodata.elf_program, \"a\"",
+    ".global elf_program_start",
+    "elf_program_start:",
+    "mov rbx, [rsp]",
+    "imul rbx, rbx, 1000",
+    "test rsp, 15",
+    "jz 1f",
+    "add rbx, 100000",
+    "1:",
+    "mov rdi, [rsp + 8]",
+    "xor esi, esi",
+    "2:",
+    "cmp byte ptr [rdi + rsi], 0",
+    "je 3f",
+    "inc rsi",
+    "jmp 2b",
+    "3:",
+    "add rbx, rsi",
+    "mov rdx, rsi",
+    "mov rsi, rdi",
+    "mov edi, 1",
+    "mov eax, {write}",
+    "syscall",
+    "mov rcx, {data}",
+    "add rbx, [rcx]",
+    "add rbx, [rcx + 8]",
+    "mov qword ptr [rcx + 8], 1",
+    "mov rdi, rbx",
+    "mov eax, {exit}",
+    "syscall",
+    ".global elf_program_end",
+    "elf_program_end:",
+    ".previous",
+    write = const syscall::WRITE,
+    exit = const syscall::EXIT,
+    data = const DATA_VADDR,
+);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::memory::BootInfoFrameAllocator;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    thread::init();
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn program_code() -> &'static [u8] {
+    unsafe {
+        let start = ptr::addr_of!(elf_program_start);
+        let len = ptr::addr_of!(elf_program_end) as usize - start as usize;
+        core::slice::from_raw_parts(start, len)
+    }
+}
+
+fn put(image: &mut [u8], offset: usize, bytes: &[u8]) {
+    image[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+fn program_header(
+    image: &mut [u8],
+    index: usize,
+    flags: u32,
+    offset: usize,
+    vaddr: u64,
+    file_size: usize,
+    mem_size: usize,
+) {
+    let at = 64 + 56 * index;
+    put(image, at, &1u32.to_le_bytes());
+    put(image, at + 4, &flags.to_le_bytes());
+    put(image, at + 8, &(offset as u64).to_le_bytes());
+    put(image, at + 16, &vaddr.to_le_bytes());
+    put(image, at + 24, &vaddr.to_le_bytes());
+    put(image, at + 32, &(file_size as u64).to_le_bytes());
+    put(image, at + 40, &(mem_size as u64).to_le_bytes());
+    put(image, at + 48, &4096u64.to_le_bytes());
+}
+
+/// An executable with the test program at `code_vaddr` and a read-write
+/// segment holding [`DATA_WORD`] and a bss word.
+fn build_elf(code_vaddr: u64) -> Vec<u8> {
+    let code = program_code();
+    let mut image = alloc::vec![0; DATA_OFFSET + 8];
+    put(&mut image, 0, b"\x7fELF\x02\x01\x01");
+    put(&mut image, 16, &2u16.to_le_bytes());
+    put(&mut image, 18, &62u16.to_le_bytes());
+    put(&mut image, 20, &1u32.to_le_bytes());
+    put(&mut image, 24, &code_vaddr.to_le_bytes());
+    put(&mut image, 32, &64u64.to_le_bytes());
+    put(&mut image, 52, &64u16.to_le_bytes());
+    put(&mut image, 54, &56u16.to_le_bytes());
+    put(&mut image, 56, &2u16.to_le_bytes());
+    program_header(
+        &mut image,
+        0,
+        5,
+        CODE_OFFSET,
+        code_vaddr,
+        code.len(),
+        code.len(),
+    );
+    program_header(&mut image, 1, 6, DATA_OFFSET, DATA_VADDR, 8, 16);
+    put(&mut image, CODE_OFFSET, code);
+    put(&mut image, DATA_OFFSET, &DATA_WORD.to_le_bytes());
+    image
+}
+
+#[test_case]
+fn runs_program_with_arguments() {
+    let program = elf::load(&build_elf(CODE_VADDR), &["hello", "world"], &["HOME=/"]).unwrap();
+    assert_eq!(program.entry(), VirtAddr::new(CODE_VADDR));
+    assert_eq!(program.run(), 2000 + 5 + DATA_WORD);
+    // the program's pages are only in its own address space
+    assert!(memory::effective_flags(VirtAddr::new(CODE_VADDR)).is_none());
+}
+
+#[test_case]
+fn programs_have_separate_memory() {
+    let image = build_elf(CODE_VADDR);
+    let first = elf::load(&image, &["a"], &[]).unwrap();
+    let second = elf::load(&image, &["a"], &[]).unwrap();
+    assert_eq!(first.run(), 1001 + DATA_WORD);
+    assert_eq!(second.run(), 1001 + DATA_WORD);
+    // the first run stored 1 to the bss word
+    assert_eq!(first.run(), 1001 + DATA_WORD + 1);
+}
+
+#[test_case]
+fn rejects_invalid_images() {
+    let image = build_elf(CODE_VADDR);
+    assert_eq!(
+        elf::load(&image[..40], &[], &[]).unwrap_err(),
+        ElfError::Truncated
+    );
+    assert_eq!(
+        elf::load(&image[..DATA_OFFSET], &[], &[]).unwrap_err(),
+        ElfError::Truncated
+    );
+
+    let mut bad_magic = image.clone();
+    bad_magic[1] = b'X';
+    assert_eq!(
+        elf::load(&bad_magic, &[], &[]).unwrap_err(),
+        ElfError::BadMagic
+    );
+
+    let kernel_space = elf::load(&build_elf(0x_ffff_8000_0000_0000), &[], &[]);
+    assert_eq!(kernel_space.unwrap_err(), ElfError::BadSegment);
+
+    // a bss far larger than any image may be
+    let mut huge = image.clone();
+    program_header(
+        &mut huge,
+        1,
+        6,
+        DATA_OFFSET,
+        DATA_VADDR,
+        8,
+        0x1000_0000_0000,
+    );
+    assert_eq!(
+        elf::load(&huge, &[], &[]).unwrap_err(),
+        ElfError::BadSegment
+    );
+
+    // below user memory: the kernel heap, and the last page before it
+    for vaddr in [allocator::HEAP_START as u64, USER_SPACE_START - 4096] {
+        match elf::load(&build_elf(vaddr), &[], &[]) {
+            Err(ElfError::Map(MapError::NotUserPage(page))) => {
+                assert_eq!(page.start_address(), VirtAddr::new(vaddr))
+            }
+            other => panic!("loaded into kernel slot: {:?}", other.map(|p| p.entry())),
+        }
+    }
+}
@@ -0,0 +1,148 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use blog_os::interrupts::{self, stats, watchdog};
+use blog_os::time::Instant;
+use blog_os::{apic, smp, vga_buffer};
+use bootloader::{entry_point, BootInfo};
+use core::arch::asm;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use x86_64::{instructions, structures::idt::InterruptStackFrameValue, VirtAddr};
+
+entry_point!(main);
+
+/// Lets CPU 1 start kicking the watchdog.
+static START_KICKER: AtomicBool = AtomicBool::new(false);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    apic::init(&mut mapper, &mut frame_allocator).expect("local APIC mapping failed");
+    smp::init(&mut mapper, &mut frame_allocator, phys_mem_offset, ap_main)
+        .expect("starting APs failed");
+
+    test_main();
+    loop {}
+}
+
+fn ap_main(cpu: usize) -> ! {
+    if cpu == 1 {
+        while !START_KICKER.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+        watchdog::run(Duration::from_millis(10));
+    }
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// Busy-waits, so that it also works with interrupts disabled.
+fn spin_for(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+fn wait_for_tick() {
+    let tick = blog_os::time::ticks();
+    while blog_os::time::ticks() == tick {
+        instructions::hlt();
+    }
+}
+
+#[test_case]
+fn kick_after_tick_is_not_a_hang() {
+    let hangs = watchdog::hangs();
+    let others = interrupts::other_nmis();
+    watchdog::enable();
+    wait_for_tick();
+    watchdog::kick();
+    spin_for(Duration::from_millis(1));
+    watchdog::disable();
+    assert_eq!(watchdog::hangs(), hangs);
+    assert_eq!(interrupts::other_nmis(), others);
+}
+
+#[test_case]
+fn kick_without_tick_is_a_hang() {
+    let hangs = watchdog::hangs();
+    watchdog::enable();
+    wait_for_tick();
+    instructions::interrupts::without_interrupts(|| {
+        watchdog::kick();
+        spin_for(Duration::from_millis(5));
+        watchdog::kick();
+        spin_for(Duration::from_millis(1));
+    });
+    watchdog::disable();
+    assert_eq!(watchdog::hangs(), hangs + 1);
+}
+
+#[test_case]
+fn other_nmis_are_not_swallowed() {
+    let others = interrupts::other_nmis();
+    watchdog::enable();
+    apic::send_nmi(apic::id());
+    spin_for(Duration::from_millis(1));
+    watchdog::disable();
+    assert_eq!(interrupts::other_nmis(), others + 1);
+}
+
+#[test_case]
+fn dump_state_stops_at_unmapped_stack() {
+    use blog_os::allocator::{HEAP_SIZE, HEAP_START};
+
+    let mut frame = InterruptStackFrameValue {
+        instruction_pointer: VirtAddr::new(0),
+        code_segment: 0,
+        cpu_flags: 0,
+        // two words before the end of the heap
+        stack_pointer: VirtAddr::new((HEAP_START + HEAP_SIZE - 16) as u64),
+        stack_segment: 0,
+    };
+    watchdog::dump_state(&frame);
+    frame.stack_pointer = VirtAddr::new(0x_dead_0000_0000);
+    watchdog::dump_state(&frame);
+}
+
+#[test_case]
+fn debug_exception_with_console_locked() {
+    let before = stats::count(interrupts::DEBUG_VECTOR);
+    {
+        let _console = vga_buffer::WRITER.lock();
+        // int1
+        unsafe { asm!(".byte 0xf1") };
+    }
+    assert_eq!(stats::count(interrupts::DEBUG_VECTOR), before + 1);
+}
+
+#[test_case]
+fn kicker_on_another_cpu_detects_hang() {
+    assert!(smp::online_cpus() > 1, "needs a second CPU");
+    let hangs = watchdog::hangs();
+    watchdog::enable();
+    START_KICKER.store(true, Ordering::Release);
+    spin_for(Duration::from_millis(50));
+    assert_eq!(watchdog::hangs(), hangs, "hang reported while ticking");
+    instructions::interrupts::without_interrupts(|| spin_for(Duration::from_millis(50)));
+    watchdog::disable();
+    assert!(watchdog::hangs() > hangs);
+}
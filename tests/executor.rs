@@ -0,0 +1,463 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{rc::Rc, vec::Vec};
+use blog_os::task::{executor::Executor, yield_now, JoinError, Priority};
+use bootloader::{entry_point, BootInfo};
+use core::cell::{Cell, RefCell};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn join_handle_returns_output() {
+    let mut executor = Executor::new();
+    let result = Rc::new(Cell::new(None));
+
+    let handle = executor.spawn(async { 6 * 7 });
+    let result_clone = result.clone();
+    executor.spawn(async move { result_clone.set(Some(handle.await)) });
+    executor.run_until_idle();
+
+    assert_eq!(result.take(), Some(Ok(42)));
+}
+
+#[test_case]
+fn abort_cancels_pending_task() {
+    let mut executor = Executor::new();
+    let result = Rc::new(RefCell::new(None));
+
+    let handle = executor.spawn(futures_util::future::pending::<()>());
+    executor.run_until_idle();
+    assert!(!handle.is_finished());
+
+    handle.abort();
+    let result_clone = result.clone();
+    executor.spawn(async move { *result_clone.borrow_mut() = Some(handle.await) });
+    executor.run_until_idle();
+
+    assert_eq!(result.take(), Some(Err(JoinError::Cancelled)));
+}
+
+#[test_case]
+fn spawn_from_running_task() {
+    use core::sync::atomic::{AtomicU32, Ordering};
+    static DONE: AtomicU32 = AtomicU32::new(0);
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    executor.spawn(async move {
+        let child = blog_os::task::spawn(async { 1 });
+        let sibling = spawner.spawn(async { 2 });
+        let sum = child.await.unwrap() + sibling.await.unwrap();
+        DONE.store(sum, Ordering::Relaxed);
+    });
+    executor.run_until_idle();
+
+    assert_eq!(DONE.load(Ordering::Relaxed), 3);
+}
+
+#[test_case]
+fn spawner_is_only_current_while_running() {
+    use blog_os::task::executor::Spawner;
+
+    let mut executor = Executor::new();
+    let inside = Rc::new(Cell::new(false));
+    let inside_clone = inside.clone();
+    executor.spawn(async move { inside_clone.set(Spawner::current().is_some()) });
+    executor.run_until_idle();
+
+    assert!(inside.get());
+    assert!(Spawner::current().is_none());
+}
+
+#[test_case]
+fn high_priority_tasks_are_polled_first() {
+    let mut executor = Executor::new();
+    let order = Rc::new(RefCell::new(Vec::new()));
+
+    for (name, priority) in [
+        ("low", Priority::Low),
+        ("normal", Priority::Normal),
+        ("high", Priority::High),
+    ] {
+        let order = order.clone();
+        executor.spawn_with_priority(async move { order.borrow_mut().push(name) }, priority);
+    }
+    executor.run_until_idle();
+
+    assert_eq!(*order.borrow(), ["high", "normal", "low"]);
+}
+
+#[test_case]
+fn yield_now_lets_other_tasks_run() {
+    let mut executor = Executor::new();
+    let order = Rc::new(RefCell::new(Vec::new()));
+
+    for name in ["a", "b"] {
+        let order = order.clone();
+        executor.spawn(async move {
+            order.borrow_mut().push(name);
+            yield_now().await;
+            order.borrow_mut().push(name);
+        });
+    }
+    executor.run_until_idle();
+
+    assert_eq!(*order.borrow(), ["a", "b", "a", "b"]);
+}
+
+#[test_case]
+fn many_tasks_and_redundant_wakes() {
+    let mut executor = Executor::new();
+    let done = Rc::new(Cell::new(0));
+
+    for _ in 0..1000 {
+        let done = done.clone();
+        executor.spawn(async move { done.set(done.get() + 1) });
+    }
+    let mut polls = 0;
+    executor.spawn(futures_util::future::poll_fn(move |cx| {
+        polls += 1;
+        if polls > 1 {
+            return core::task::Poll::Ready(polls);
+        }
+        for _ in 0..10 {
+            cx.waker().wake_by_ref();
+        }
+        core::task::Poll::Pending
+    }));
+    executor.run_until_idle();
+
+    let metrics = executor.queue_metrics();
+    assert_eq!(done.get(), 1000);
+    assert_eq!(metrics.depth, 0);
+    assert_eq!(metrics.max_depth, 1001);
+    assert_eq!(metrics.redundant_wakes, 9);
+}
+
+#[test_case]
+fn self_wake_before_completion_leaves_no_stale_id() {
+    let mut executor = Executor::new();
+    for _ in 0..100 {
+        executor.spawn(futures_util::future::poll_fn(|cx| {
+            cx.waker().wake_by_ref();
+            core::task::Poll::Ready(())
+        }));
+    }
+    executor.run_until_idle();
+
+    let metrics = executor.queue_metrics();
+    assert_eq!(metrics.depth, 0);
+    assert_eq!(metrics.max_depth, 100);
+    assert_eq!(metrics.wakes, 200);
+    assert!(executor.tasks().iter().all(|task| task.polls == 1));
+}
+
+#[test_case]
+fn task_list_reports_stats() {
+    use blog_os::task::executor::{TaskState, WakeSource};
+
+    let mut executor = Executor::new();
+    executor.spawn_named("yielder", async {
+        yield_now().await;
+        yield_now().await;
+    });
+    executor.spawn_named("waiter", futures_util::future::pending::<()>());
+    executor.run_until_idle();
+
+    let tasks = executor.tasks();
+    let waiter = tasks
+        .iter()
+        .find(|task| task.name.as_deref() == Some("waiter"))
+        .unwrap();
+    assert_eq!(waiter.state, TaskState::Pending);
+    assert_eq!(waiter.polls, 1);
+    assert_eq!(waiter.last_wake, WakeSource::Spawn);
+
+    let yielder = tasks
+        .iter()
+        .find(|task| task.name.as_deref() == Some("yielder"))
+        .unwrap();
+    assert_eq!(yielder.state, TaskState::Completed);
+    assert_eq!(yielder.polls, 3);
+    assert_eq!(yielder.last_wake, WakeSource::Task);
+
+    let table = alloc::format!("{}", executor.task_table());
+    assert!(table.contains("yielder"));
+    assert!(table.contains("pending"));
+}
+
+blog_os::task_local! {
+    static REQUEST_ID: u32;
+}
+
+#[test_case]
+fn task_local_resolves_per_task() {
+    let mut executor = Executor::new();
+    let seen = Rc::new(RefCell::new(Vec::new()));
+
+    for id in [1, 2] {
+        let seen = seen.clone();
+        executor.spawn(REQUEST_ID.scope(id, async move {
+            seen.borrow_mut().push(REQUEST_ID.get());
+            yield_now().await;
+            seen.borrow_mut().push(REQUEST_ID.get());
+        }));
+    }
+    executor.run_until_idle();
+
+    assert_eq!(*seen.borrow(), [1, 2, 1, 2]);
+    assert!(REQUEST_ID.try_with(|_| ()).is_err());
+}
+
+#[test_case]
+fn cancel_scope_aborts_nested_tasks() {
+    use blog_os::task::{channel::oneshot, CancelScope};
+    use futures_util::future::pending;
+
+    let mut executor = Executor::new();
+    let results = Rc::new(RefCell::new(Vec::new()));
+
+    let results_clone = results.clone();
+    executor.spawn(async move {
+        let scope = CancelScope::new();
+        let (tx, rx) = oneshot::channel();
+        let child = scope.spawn(async move {
+            let grandchild = blog_os::task::spawn(pending::<()>());
+            let _ = tx.send(grandchild);
+            pending::<()>().await
+        });
+        let grandchild = rx.await.unwrap();
+        drop(scope);
+
+        let child = child.await;
+        let grandchild = grandchild.await;
+
+        let scope = CancelScope::new();
+        scope.cancel();
+        let late = scope.spawn(async {}).await;
+        *results_clone.borrow_mut() = [child, grandchild, late].into();
+    });
+    executor.run_until_idle();
+
+    let cancelled = Err(JoinError::Cancelled);
+    assert_eq!(
+        *results.borrow(),
+        [cancelled.clone(), cancelled.clone(), cancelled]
+    );
+}
+
+async fn check_code(code: u32) -> u32 {
+    yield_now().await;
+    assert_eq!(code, 0, "bad code");
+    code
+}
+
+#[test_case]
+fn isolated_panic_fails_only_its_task() {
+    use blog_os::task::executor::TaskState;
+
+    let mut executor = Executor::new();
+    executor.isolate_panics(true);
+    let failures = Rc::new(RefCell::new(Vec::new()));
+    let failures_clone = failures.clone();
+    executor.on_task_panic(move |task, message| {
+        assert_eq!(task.state, TaskState::Failed);
+        failures_clone.borrow_mut().push(task.name.clone());
+        assert!(message.contains("bad code"));
+    });
+    let results = Rc::new(RefCell::new(Vec::new()));
+
+    let failing = executor.spawn_named("failing", check_code(7));
+    let passing = executor.spawn(check_code(0));
+    let results_clone = results.clone();
+    executor.spawn(async move {
+        let failed = failing.await;
+        let passed = passing.await;
+        *results_clone.borrow_mut() = [failed, passed].into();
+    });
+    executor.run_until_idle();
+
+    assert_eq!(*failures.borrow(), [Some("failing".into())]);
+    let results = results.borrow();
+    assert!(
+        matches!(&results[0], Err(JoinError::Panicked(message)) if message.contains("bad code"))
+    );
+    assert_eq!(results[1], Ok(0));
+}
+
+#[test_case]
+fn panic_while_holding_a_lock_releases_it() {
+    use blog_os::sync::IrqSafeMutex;
+    use x86_64::instructions::interrupts;
+
+    static COUNTER: IrqSafeMutex<u32> = IrqSafeMutex::new(0);
+
+    let mut executor = Executor::new();
+    executor.isolate_panics(true);
+    executor.on_task_panic(|_, _| {});
+    let failing = executor.spawn(async {
+        let mut counter = COUNTER.lock();
+        *counter += 1;
+        panic!("with the lock held");
+    });
+    let result = Rc::new(RefCell::new(None));
+    let result_clone = result.clone();
+    executor.spawn(async move { *result_clone.borrow_mut() = Some(failing.await) });
+    executor.run_until_idle();
+
+    assert!(matches!(
+        *result.borrow(),
+        Some(Err(JoinError::Panicked(_)))
+    ));
+    assert!(interrupts::are_enabled());
+    assert_eq!(*COUNTER.lock(), 1);
+}
+
+#[test_case]
+fn smp_executor_runs_send_tasks() {
+    use alloc::sync::Arc;
+    use blog_os::task::{channel::mpsc, smp_executor::SmpExecutor};
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    let executor = SmpExecutor::new();
+    let (tx, mut rx) = mpsc::channel(4);
+    let received = Arc::new(AtomicU32::new(0));
+    let joined = Arc::new(AtomicU32::new(0));
+
+    let handles: Vec<_> = (1..=20)
+        .map(|n| {
+            let tx = tx.clone();
+            executor.spawn(async move {
+                yield_now().await;
+                tx.send(n).await.unwrap();
+                n
+            })
+        })
+        .collect();
+    drop(tx);
+    let received_clone = received.clone();
+    executor.spawn(async move {
+        while let Some(n) = rx.recv().await {
+            received_clone.fetch_add(n, Ordering::Relaxed);
+        }
+    });
+    let joined_clone = joined.clone();
+    let spawner = executor.clone();
+    executor.spawn(async move {
+        for handle in handles {
+            joined_clone.fetch_add(handle.await.unwrap(), Ordering::Relaxed);
+        }
+        let nested = spawner.spawn(async { 1000 }).await.unwrap();
+        joined_clone.fetch_add(nested, Ordering::Relaxed);
+    });
+    executor.run_until_idle();
+
+    assert_eq!(received.load(Ordering::Relaxed), 210);
+    assert_eq!(joined.load(Ordering::Relaxed), 1210);
+    let metrics = executor.metrics();
+    assert_eq!(metrics.cpus, 1);
+    assert_eq!(metrics.live_tasks, 0);
+}
+
+/// Runs `executor` until `done` returns true, sleeping between timer ticks.
+fn run_until(executor: &mut Executor, done: impl Fn() -> bool) {
+    use blog_os::time::Instant;
+    use core::time::Duration;
+
+    let give_up = Instant::now() + Duration::from_secs(2);
+    loop {
+        executor.run_until_idle();
+        if done() {
+            break;
+        }
+        assert!(Instant::now() < give_up, "timer never fired");
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn sleep_fires_after_its_duration() {
+    use blog_os::{task::timer, time::Instant};
+    use core::time::Duration;
+
+    let mut executor = Executor::new();
+    let woke_at = Rc::new(Cell::new(None));
+    let start = Instant::now();
+    let woke_at_clone = woke_at.clone();
+    executor.spawn(async move {
+        timer::sleep(Duration::from_millis(5)).await;
+        woke_at_clone.set(Some(Instant::now()));
+    });
+    run_until(&mut executor, || woke_at.get().is_some());
+
+    let woke_at = woke_at.get().unwrap();
+    assert!(woke_at.duration_since(start) >= Duration::from_millis(5));
+}
+
+#[test_case]
+fn timeout_expires() {
+    use blog_os::task::timer::{self, Elapsed};
+    use core::time::Duration;
+
+    let mut executor = Executor::new();
+    let result = Rc::new(Cell::new(None));
+    let result_clone = result.clone();
+    executor.spawn(async move {
+        let pending = futures_util::future::pending::<()>();
+        result_clone.set(Some(
+            timer::timeout(pending, Duration::from_millis(5)).await,
+        ));
+    });
+    run_until(&mut executor, || result.get().is_some());
+
+    assert_eq!(result.get(), Some(Err(Elapsed)));
+}
+
+#[test_case]
+fn timeout_completes_in_time() {
+    use blog_os::{task::timer, time::Instant};
+    use core::time::Duration;
+
+    let mut executor = Executor::new();
+    let result = Rc::new(Cell::new(None));
+    let start = Instant::now();
+    let result_clone = result.clone();
+    executor.spawn(async move {
+        let work = async {
+            timer::sleep(Duration::from_millis(2)).await;
+            7
+        };
+        result_clone.set(Some(timer::timeout(work, Duration::from_secs(1)).await));
+    });
+    run_until(&mut executor, || result.get().is_some());
+
+    assert_eq!(result.get(), Some(Ok(7)));
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
@@ -0,0 +1,143 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, task::Wake};
+use blog_os::interrupts::nesting::{lower_priority_mask, NestedIrq};
+use blog_os::interrupts::{pic, stats, InterruptIndex};
+use blog_os::task::keyboard::ScancodeStream;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use futures_util::stream::Stream;
+use x86_64::instructions::{interrupts, port::Port};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// Spins until the timer handler ran at least once, or gives up.
+fn wait_for_timer() -> bool {
+    let timer = InterruptIndex::Timer.as_u8();
+    let before = stats::count(timer);
+    for _ in 0..100_000_000 {
+        if stats::count(timer) != before {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+#[test_case]
+fn priority_order() {
+    assert_eq!(lower_priority_mask(0), 0xfffb);
+    assert_eq!(lower_priority_mask(1), 0xfffa);
+    assert_eq!(lower_priority_mask(8), 0xfff8);
+    assert_eq!(lower_priority_mask(7), 1 << 7);
+}
+
+#[test_case]
+fn nested_section_masks_lower_priority() {
+    interrupts::disable();
+    let before = pic::read_masks();
+    {
+        let _nested = NestedIrq::enter(InterruptIndex::Timer.irq());
+        let masks = pic::read_masks();
+        assert_eq!(masks & 1, 1, "timer must not preempt itself");
+        assert_eq!(
+            masks & (1 << 1),
+            1 << 1,
+            "keyboard must not preempt the timer"
+        );
+        assert_eq!(
+            masks & (1 << 2),
+            before & (1 << 2),
+            "cascade line untouched"
+        );
+    }
+    assert!(!interrupts::are_enabled());
+    assert_eq!(pic::read_masks(), before);
+    interrupts::enable();
+}
+
+/// Waker of the scancode stream. The keyboard handler wakes it after it
+/// re-enabled interrupts, so waiting for a tick here makes the handler long.
+struct SlowWaker {
+    timer_ran: AtomicBool,
+}
+
+impl Wake for SlowWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if stats::in_interrupt() && wait_for_timer() {
+            self.timer_ran.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Makes the i8042 controller deliver `scancode` as if it came from the
+/// keyboard, which raises IRQ 1.
+fn inject_scancode(scancode: u8) {
+    let mut command: Port<u8> = Port::new(0x64);
+    let mut data: Port<u8> = Port::new(0x60);
+    unsafe {
+        // write keyboard output buffer
+        command.write(0xd2);
+        data.write(scancode);
+    }
+}
+
+#[test_case]
+fn timer_preempts_keyboard_handler() {
+    let waker = Arc::new(SlowWaker {
+        timer_ran: AtomicBool::new(false),
+    });
+    let mut scancodes = ScancodeStream::new();
+    let stream_waker = Waker::from(waker.clone());
+    let mut cx = Context::from_waker(&stream_waker);
+    assert!(Pin::new(&mut scancodes).poll_next(&mut cx).is_pending());
+
+    let keyboard = InterruptIndex::Keyboard.as_u8();
+    let before = stats::count(keyboard);
+    inject_scancode(0x1e);
+    while stats::count(keyboard) == before {
+        x86_64::instructions::hlt();
+    }
+
+    assert!(
+        waker.timer_ran.load(Ordering::Relaxed),
+        "timer IRQ was delayed by the keyboard handler"
+    );
+    assert_eq!(
+        Pin::new(&mut scancodes).poll_next(&mut cx),
+        Poll::Ready(Some(0x1e))
+    );
+}
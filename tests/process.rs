@@ -0,0 +1,373 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use blog_os::process::{self, ExitStatus, Pid, WaitError};
+use blog_os::user::USER_SPACE_END;
+use blog_os::{elf, memory, syscall, thread};
+use bootloader::{entry_point, BootInfo};
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+use core::ptr;
+use x86_64::{structures::idt::PageFaultErrorCode, VirtAddr};
+
+entry_point!(main);
+
+const CODE_VADDR: u64 = 0x_6000_0000_0000;
+/// Where [`spawn_parent`] puts the length of the child's image, followed by
+/// the image.
+const CHILD_IMAGE_VADDR: u64 = 0x_6000_0010_0000;
+
+extern "C" {
+    static process_programs_start: u8;
+    static process_programs_end: u8;
+    static exit_7: u8;
+    static null_read: u8;
+    static sleep_then_exit_3: u8;
+    static divide_by_zero: u8;
+    static invalid_opcode: u8;
+    static breakpoint: u8;
+    static privileged: u8;
+    static spawn_and_wait: u8;
+    static spawn_and_exit: u8;
+}
+
+global_asm!(
+    ".section .rodata.process_programs, \"a\"",
+    ".global process_programs_start",
+    "process_programs_start:",
+    ".global exit_7",
+    "exit_7:",
+    "mov edi, 7",
+    "mov eax, {exit}",
+    "syscall",
+    ".global null_read",
+    "null_read:",
+    "xor ecx, ecx",
+    "mov rax, [rcx]",
+    "mov rdi, rax",
+    "mov eax, {exit}",
+    "syscall",
+    ".global sleep_then_exit_3",
+    "sleep_then_exit_3:",
+    "mov edi, 20",
+    "mov eax, {sleep}",
+    "syscall",
+    "mov edi, 3",
+    "mov eax, {exit}",
+    "syscall",
+    // Each faults with its first or second instruction.
+    ".global divide_by_zero",
+    "divide_by_zero:",
+    "xor ecx, ecx",
+    "div ecx",
+    ".global invalid_opcode",
+    "invalid_opcode:",
+    "ud2",
+    ".global breakpoint",
+    "breakpoint:",
+    "int3",
+    "ud2",
+    ".global privileged",
+    "privileged:",
+    "hlt",
+    // Start the child image at CHILD_IMAGE_VADDR, then wait for the child
+    // or exit right away, both with what the system call returned.
+    ".global spawn_and_wait",
+    "spawn_and_wait:",
+    "mov rdi, {child_image}",
+    "mov rsi, [rdi]",
+    "add rdi, 8",
+    "mov eax, {spawn}",
+    "syscall",
+    "mov rdi, rax",
+    "mov eax, {wait}",
+    "syscall",
+    "mov rdi, rax",
+    "mov eax, {exit}",
+    "syscall",
+    ".global spawn_and_exit",
+    "spawn_and_exit:",
+    "mov rdi, {child_image}",
+    "mov rsi, [rdi]",
+    "add rdi, 8",
+    "mov eax, {spawn}",
+    "syscall",
+    "mov rdi, rax",
+    "mov eax, {exit}",
+    "syscall",
+    ".global process_programs_end",
+    "process_programs_end:",
+    ".previous",
+    exit = const syscall::EXIT,
+    sleep = const syscall::SLEEP,
+    spawn = const syscall::SPAWN,
+    wait = const syscall::WAIT,
+    child_image = const CHILD_IMAGE_VADDR,
+);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::BootInfoFrameAllocator;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    thread::init();
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn put(image: &mut [u8], offset: usize, bytes: &[u8]) {
+    image[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+fn programs() -> &'static [u8] {
+    unsafe {
+        let start = ptr::addr_of!(process_programs_start);
+        let len = ptr::addr_of!(process_programs_end) as usize - start as usize;
+        core::slice::from_raw_parts(start, len)
+    }
+}
+
+/// Address of `program` in a process.
+fn program_vaddr(program: &u8) -> u64 {
+    CODE_VADDR + (program as *const u8 as u64 - programs().as_ptr() as u64)
+}
+
+/// An executable of all test programs, starting at `program`.
+fn programs_image(program: &u8) -> Vec<u8> {
+    build_image(programs(), CODE_VADDR, program_vaddr(program), &[])
+}
+
+fn spawn(program: &u8) -> Pid {
+    spawn_image(&programs_image(program))
+}
+
+/// Spawns `parent` with an image of the test programs starting at `child`
+/// at [`CHILD_IMAGE_VADDR`].
+fn spawn_parent(parent: &u8, child: &u8) -> Pid {
+    let child = programs_image(child);
+    let mut data = (child.len() as u64).to_le_bytes().to_vec();
+    data.extend_from_slice(&child);
+    spawn_image(&build_image(
+        programs(),
+        CODE_VADDR,
+        program_vaddr(parent),
+        &data,
+    ))
+}
+
+fn spawn_image(image: &[u8]) -> Pid {
+    process::spawn(elf::load(image, &["test"], &[]).expect("loading test program failed"))
+}
+
+/// An executable with `code` at `vaddr` and, unless it is empty, `data`
+/// read-only at [`CHILD_IMAGE_VADDR`].
+fn build_image(code: &[u8], vaddr: u64, entry: u64, data: &[u8]) -> Vec<u8> {
+    let data_offset = (0x1000 + code.len()).next_multiple_of(0x1000);
+    let mut image: Vec<u8> = alloc::vec![0; data_offset + data.len()];
+    put(&mut image, 0, b"\x7fELF\x02\x01\x01");
+    put(&mut image, 16, &2u16.to_le_bytes());
+    put(&mut image, 18, &62u16.to_le_bytes());
+    put(&mut image, 20, &1u32.to_le_bytes());
+    put(&mut image, 24, &entry.to_le_bytes());
+    put(&mut image, 32, &64u64.to_le_bytes());
+    put(&mut image, 52, &64u16.to_le_bytes());
+    put(&mut image, 54, &56u16.to_le_bytes());
+    put(
+        &mut image,
+        56,
+        &(1 + u16::from(!data.is_empty())).to_le_bytes(),
+    );
+    // R|X
+    program_header(&mut image, 0, 5, 0x1000, vaddr, code.len());
+    put(&mut image, 0x1000, code);
+    if !data.is_empty() {
+        // R
+        program_header(&mut image, 1, 4, data_offset, CHILD_IMAGE_VADDR, data.len());
+        put(&mut image, data_offset, data);
+    }
+    image
+}
+
+/// Writes the `index`th program header, a `PT_LOAD` without bss.
+fn program_header(
+    image: &mut [u8],
+    index: usize,
+    flags: u32,
+    offset: usize,
+    vaddr: u64,
+    size: usize,
+) {
+    let at = 64 + index * 56;
+    put(image, at, &1u32.to_le_bytes());
+    put(image, at + 4, &flags.to_le_bytes());
+    put(image, at + 8, &(offset as u64).to_le_bytes());
+    put(image, at + 16, &vaddr.to_le_bytes());
+    put(image, at + 32, &(size as u64).to_le_bytes());
+    put(image, at + 40, &(size as u64).to_le_bytes());
+}
+
+fn frames_in_use() -> usize {
+    memory::with_kernel_memory(|_, frames| frames.frames_in_use()).unwrap()
+}
+
+#[test_case]
+fn wait_returns_exit_status() {
+    let pid = spawn(unsafe { &exit_7 });
+    assert_eq!(process::wait(pid), Ok(ExitStatus::Exited(7)));
+    // reaped
+    assert!(!process::pids().contains(&pid));
+    assert_eq!(process::wait(pid), Err(WaitError::NotAChild));
+}
+
+#[test_case]
+fn page_fault_kills_process() {
+    let pid = spawn(unsafe { &null_read });
+    match process::wait(pid) {
+        Ok(ExitStatus::PageFault {
+            address,
+            error_code,
+            ..
+        }) => {
+            assert_eq!(address, VirtAddr::new(0));
+            assert!(error_code.contains(PageFaultErrorCode::USER_MODE));
+        }
+        other => panic!("process was not killed: {:?}", other),
+    }
+}
+
+#[test_case]
+fn exceptions_kill_process() {
+    let cases = [
+        (
+            unsafe { &divide_by_zero },
+            // after the two-byte `xor ecx, ecx`
+            ExitStatus::DivideError {
+                instruction: VirtAddr::new(program_vaddr(unsafe { &divide_by_zero }) + 2),
+            },
+        ),
+        (
+            unsafe { &invalid_opcode },
+            ExitStatus::InvalidOpcode {
+                instruction: VirtAddr::new(program_vaddr(unsafe { &invalid_opcode })),
+            },
+        ),
+        (
+            unsafe { &breakpoint },
+            ExitStatus::Breakpoint {
+                instruction: VirtAddr::new(program_vaddr(unsafe { &breakpoint }) + 1),
+            },
+        ),
+        (
+            unsafe { &privileged },
+            ExitStatus::GeneralProtection {
+                instruction: VirtAddr::new(program_vaddr(unsafe { &privileged })),
+                error_code: 0,
+            },
+        ),
+    ];
+    for (program, status) in cases {
+        let pid = spawn(program);
+        assert_eq!(process::wait(pid), Ok(status));
+    }
+}
+
+#[test_case]
+fn finished_process_frees_its_frames() {
+    let before = frames_in_use();
+    let pid = spawn(unsafe { &null_read });
+    assert!(matches!(
+        process::wait(pid),
+        Ok(ExitStatus::PageFault { .. })
+    ));
+    assert_eq!(frames_in_use(), before);
+}
+
+#[test_case]
+fn process_waits_for_its_child() {
+    let pid = spawn_parent(unsafe { &spawn_and_wait }, unsafe { &exit_7 });
+    assert_eq!(process::wait(pid), Ok(ExitStatus::Exited(7)));
+    // the parent reaped the child
+    assert!(process::pids().is_empty());
+}
+
+#[test_case]
+fn orphans_go_to_the_kernel() {
+    let parent = spawn_parent(unsafe { &spawn_and_exit }, unsafe { &sleep_then_exit_3 });
+    let child = match process::wait(parent) {
+        Ok(ExitStatus::Exited(child)) => child,
+        other => panic!("parent did not exit: {:?}", other),
+    };
+    let child = *process::pids()
+        .iter()
+        .find(|pid| pid.as_u64() == child)
+        .expect("child not in the table");
+    let parent_now = process::with_process(child, |process| process.parent());
+    assert_eq!(parent_now, Some(Pid::KERNEL));
+    assert_eq!(process::wait(child), Ok(ExitStatus::Exited(3)));
+}
+
+#[test_case]
+fn syscall_at_end_of_user_space_kills_process() {
+    // `mov eax, TIME; syscall`, returning to the first address past user
+    // memory, which sysretq cannot take
+    let tail = [0xb8, syscall::TIME as u8, 0, 0, 0, 0x0f, 0x05];
+    let mut code = [0; 4096];
+    code[4096 - tail.len()..].copy_from_slice(&tail);
+    let pid = spawn_image(&build_image(
+        &code,
+        USER_SPACE_END - 4096,
+        USER_SPACE_END - tail.len() as u64,
+        &[],
+    ));
+    assert_eq!(
+        process::wait(pid),
+        Ok(ExitStatus::GeneralProtection {
+            instruction: VirtAddr::new(USER_SPACE_END - 2),
+            error_code: 0,
+        })
+    );
+}
+
+#[test_case]
+fn process_has_parent_and_handles() {
+    let pid = spawn(unsafe { &sleep_then_exit_3 });
+    assert_eq!(process::current(), Pid::KERNEL);
+    let (parent, handles) =
+        process::with_process(pid, |process| (process.parent(), process.handles().len())).unwrap();
+    assert_eq!(parent, Pid::KERNEL);
+    assert_eq!(handles, process::INITIAL_HANDLES.len());
+    assert_eq!(process::wait(pid), Ok(ExitStatus::Exited(3)));
+}
+
+#[test_case]
+fn wait_any_reaps_every_child() {
+    let sleeper = spawn(unsafe { &sleep_then_exit_3 });
+    let quick = spawn(unsafe { &exit_7 });
+    let mut reaped = [process::wait_any().unwrap(), process::wait_any().unwrap()];
+    reaped.sort_by_key(|&(pid, _)| pid);
+    assert_eq!(
+        reaped,
+        [
+            (sleeper, ExitStatus::Exited(3)),
+            (quick, ExitStatus::Exited(7))
+        ]
+    );
+    assert_eq!(process::wait_any(), Err(WaitError::NoChildren));
+}
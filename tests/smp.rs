@@ -0,0 +1,277 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use blog_os::sync::IrqSafeMutex;
+use blog_os::task::smp_executor::SmpExecutor;
+use blog_os::time::Instant;
+use blog_os::{acpi, percpu, smp};
+use bootloader::{entry_point, BootInfo};
+use conquer_once::spin::OnceCell;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::task::{Poll, Waker};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    VirtAddr,
+};
+
+entry_point!(main);
+
+static PHYS_MEM_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+static STARTED: OnceCell<usize> = OnceCell::uninit();
+static AP_ENTRIES: AtomicUsize = AtomicUsize::new(0);
+static MEMORY: Mutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> = Mutex::new(None);
+
+/// The APs read the `u64` at `PROBE` whenever `ROUND` changes.
+static PROBE: AtomicU64 = AtomicU64::new(0);
+static ROUND: AtomicU64 = AtomicU64::new(0);
+static DONE: [AtomicU64; percpu::MAX_CPUS] = [const { AtomicU64::new(0) }; percpu::MAX_CPUS];
+static SEEN: [AtomicU64; percpu::MAX_CPUS] = [const { AtomicU64::new(0) }; percpu::MAX_CPUS];
+
+/// Once set, CPU 1 leaves the probe loop and runs the executor; the other
+/// APs follow once `ALL_APS_RUN` is set.
+static EXECUTOR: OnceCell<SmpExecutor> = OnceCell::uninit();
+static ALL_APS_RUN: AtomicBool = AtomicBool::new(false);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    blog_os::apic::init(&mut mapper, &mut frame_allocator).expect("local APIC mapping failed");
+
+    PHYS_MEM_OFFSET.init_once(|| phys_mem_offset);
+    let started = smp::init(&mut mapper, &mut frame_allocator, phys_mem_offset, ap_main)
+        .expect("starting APs failed");
+    STARTED.init_once(|| started);
+    *MEMORY.lock() = Some((mapper, frame_allocator));
+
+    test_main();
+    loop {}
+}
+
+fn ap_main(cpu: usize) -> ! {
+    let local = percpu::current();
+    if local.index() == cpu && local.apic_id() == blog_os::apic::id() && local.tss().is_some() {
+        AP_ENTRIES.fetch_add(1, Ordering::SeqCst);
+    }
+    let mut last = 0;
+    loop {
+        if let Ok(executor) = EXECUTOR.try_get() {
+            if cpu == 1 || ALL_APS_RUN.load(Ordering::Acquire) {
+                executor.run();
+            }
+        }
+        let round = ROUND.load(Ordering::Acquire);
+        if round != last {
+            let probe = PROBE.load(Ordering::Acquire) as *const u64;
+            SEEN[cpu].store(unsafe { probe.read_volatile() }, Ordering::Relaxed);
+            DONE[cpu].store(round, Ordering::Release);
+            last = round;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Has every AP read `PROBE` and returns what they saw.
+fn read_on_aps(round: u64) -> Vec<u64> {
+    ROUND.store(round, Ordering::Release);
+    (1..smp::online_cpus())
+        .map(|cpu| {
+            while DONE[cpu].load(Ordering::Acquire) != round {
+                core::hint::spin_loop();
+            }
+            SEEN[cpu].load(Ordering::Relaxed)
+        })
+        .collect()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn madt_lists_all_cpus() {
+    let processors = acpi::processors(*PHYS_MEM_OFFSET.get().unwrap()).unwrap();
+    // the test runner starts QEMU with `-smp 4`
+    assert_eq!(processors.len(), 4);
+    assert!(processors
+        .iter()
+        .any(|processor| processor.apic_id == blog_os::apic::id()));
+}
+
+#[test_case]
+fn all_cpus_come_online() {
+    assert_eq!(*STARTED.get().unwrap(), 4);
+    assert_eq!(smp::online_cpus(), 4);
+    assert_eq!(AP_ENTRIES.load(Ordering::SeqCst), 3);
+}
+
+#[test_case]
+fn unmap_shoots_down_remote_tlbs() {
+    let mut memory = MEMORY.lock();
+    let (mapper, frame_allocator) = memory.as_mut().unwrap();
+    let phys_mem_offset = *PHYS_MEM_OFFSET.get().unwrap();
+    let frames: [PhysFrame<Size4KiB>; 2] = [
+        frame_allocator.allocate_frame().unwrap(),
+        frame_allocator.allocate_frame().unwrap(),
+    ];
+    for (value, frame) in (1..).zip(frames) {
+        let ptr: *mut u64 = (phys_mem_offset + frame.start_address().as_u64()).as_mut_ptr();
+        unsafe { ptr.write_volatile(value) };
+    }
+
+    let page = Page::containing_address(VirtAddr::new(0x_6666_0000_0000));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe { mapper.map_to(page, frames[0], flags, frame_allocator) }
+        .unwrap()
+        .flush();
+    PROBE.store(page.start_address().as_u64(), Ordering::Release);
+    assert!(read_on_aps(1).iter().all(|&value| value == 1));
+
+    let shootdowns = memory::tlb::shootdowns();
+    assert_eq!(memory::unmap(mapper, page).unwrap(), frames[0]);
+    assert_eq!(memory::tlb::shootdowns(), shootdowns + 1);
+    unsafe { mapper.map_to(page, frames[1], flags, frame_allocator) }
+        .unwrap()
+        .flush();
+    assert!(read_on_aps(2).iter().all(|&value| value == 2));
+}
+
+/// Holds tasks back until it is opened, then wakes them all at once.
+struct Gate {
+    open: AtomicBool,
+    waiting: AtomicUsize,
+    wakers: IrqSafeMutex<Vec<Waker>>,
+}
+
+impl Gate {
+    const fn new() -> Self {
+        Self {
+            open: AtomicBool::new(false),
+            waiting: AtomicUsize::new(0),
+            wakers: IrqSafeMutex::new(Vec::new()),
+        }
+    }
+
+    async fn pass(&self) {
+        let mut registered = false;
+        futures_util::future::poll_fn(|cx| {
+            let mut wakers = self.wakers.lock();
+            if self.open.load(Ordering::Acquire) {
+                return Poll::Ready(());
+            }
+            if !registered {
+                registered = true;
+                wakers.push(cx.waker().clone());
+                self.waiting.fetch_add(1, Ordering::Relaxed);
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    fn open(&self) {
+        let wakers = {
+            let mut wakers = self.wakers.lock();
+            self.open.store(true, Ordering::Release);
+            core::mem::take(&mut *wakers)
+        };
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+fn wait_until(what: &str, done: impl Fn() -> bool) {
+    let give_up = Instant::now() + Duration::from_secs(5);
+    while !done() {
+        assert!(Instant::now() < give_up, "timed out waiting for {}", what);
+        core::hint::spin_loop();
+    }
+}
+
+fn spin_for(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// Spawns `count` tasks that wait at `gate` and then busy-wait a little.
+fn spawn_gated(executor: &SmpExecutor, gate: &'static Gate, count: usize) -> &'static AtomicUsize {
+    let done = alloc::boxed::Box::leak(alloc::boxed::Box::new(AtomicUsize::new(0)));
+    for _ in 0..count {
+        let done = &*done;
+        executor.spawn(async move {
+            gate.pass().await;
+            spin_for(Duration::from_micros(20));
+            done.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+    wait_until("tasks to wait at the gate", || {
+        gate.waiting.load(Ordering::Relaxed) == count
+    });
+    done
+}
+
+// The executor tests take the APs out of the probe loop, so they come last.
+
+#[test_case]
+fn executor_steals_and_wakes_halted_aps() {
+    static GATE: Gate = Gate::new();
+    const TASKS: usize = 64;
+
+    let executor = SmpExecutor::new();
+    EXECUTOR.init_once(|| executor.clone());
+    // only CPU 1 runs at first, so every task is queued there when woken
+    let done = spawn_gated(&executor, &GATE, TASKS);
+    ALL_APS_RUN.store(true, Ordering::Release);
+    wait_until("the other APs to join", || executor.metrics().cpus == 3);
+    spin_for(Duration::from_millis(10));
+
+    let before = executor.metrics();
+    GATE.open();
+    wait_until("the tasks to finish", || {
+        done.load(Ordering::Relaxed) == TASKS
+    });
+
+    let after = executor.metrics();
+    assert!(after.ipis > before.ipis, "no wakeup IPI sent");
+    assert!(after.steals > before.steals, "no AP stole work");
+    wait_until("the tasks to complete", || {
+        executor.metrics().live_tasks == 0
+    });
+}
+
+#[test_case]
+fn executor_overflows_full_run_queues() {
+    static GATE: Gate = Gate::new();
+    // more than the three AP run queues hold together
+    const TASKS: usize = 2000;
+
+    let executor = EXECUTOR.get().unwrap();
+    let done = spawn_gated(executor, &GATE, TASKS);
+    GATE.open();
+    wait_until("the tasks to finish", || {
+        done.load(Ordering::Relaxed) == TASKS
+    });
+    wait_until("the tasks to complete", || {
+        executor.metrics().live_tasks == 0
+    });
+}
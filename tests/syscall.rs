@@ -0,0 +1,256 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use blog_os::syscall::{self, SyscallError};
+use blog_os::{memory, thread, user};
+use bootloader::{entry_point, BootInfo};
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+use core::ptr;
+use x86_64::{
+    structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+const CODE: u64 = 0x_7000_0000_0000;
+const STACK: u64 = 0x_7000_0000_1000;
+const MMAP_ADDR: u64 = 0x_7000_0010_0000;
+
+extern "C" {
+    static test_programs_start: u8;
+    static test_programs_end: u8;
+    static test_write: u8;
+    static test_bad_write: u8;
+    static test_write_bad_handle: u8;
+    static test_unknown_call: u8;
+    static test_mmap: u8;
+    static test_mmap_kernel: u8;
+    static test_sleep: u8;
+    static test_reload_gs: u8;
+}
+
+// Each program ends with the exit call, passing what it wants to report.
+global_asm!(
+    ".section .rodata.test_programs, \"a\"",
+    ".global test_programs_start",
+    "test_programs_start:",
+    "message:",
+    ".ascii \"hello from user mode\\n\"",
+    ".set message_len, . - message",
+    ".global test_write",
+    "test_write:",
+    "mov eax, {write}",
+    "mov edi, 1",
+    "lea rsi, [rip + message]",
+    "mov edx, offset message_len",
+    "syscall",
+    "mov rdi, rax",
+    "mov eax, {exit}",
+    "syscall",
+    ".global test_bad_write",
+    "test_bad_write:",
+    "mov eax, {write}",
+    "mov edi, 1",
+    "mov rsi, 0xffff800000000000",
+    "mov edx, 8",
+    "syscall",
+    "mov rdi, rax",
+    "mov eax, {exit}",
+    "syscall",
+    ".global test_write_bad_handle",
+    "test_write_bad_handle:",
+    "mov eax, {write}",
+    "mov edi, 7",
+    "lea rsi, [rip + message]",
+    "mov edx, offset message_len",
+    "syscall",
+    "mov rdi, rax",
+    "mov eax, {exit}",
+    "syscall",
+    ".global test_unknown_call",
+    "test_unknown_call:",
+    "mov eax, 99",
+    "syscall",
+    "mov rdi, rax",
+    "mov eax, {exit}",
+    "syscall",
+    // Maps two pages, writes to the second and adds the first word of both.
+    ".global test_mmap",
+    "test_mmap:",
+    "mov eax, {mmap}",
+    "mov rdi, {mmap_addr}",
+    "mov esi, 8192",
+    "mov edx, {mmap_write}",
+    "syscall",
+    "cmp rax, rdi",
+    "jne 1f",
+    "mov qword ptr [rdi + 4096], 7",
+    "mov rax, [rdi]",
+    "add rax, [rdi + 4096]",
+    "1:",
+    "mov rdi, rax",
+    "mov eax, {exit}",
+    "syscall",
+    // Maps the page just below user memory.
+    ".global test_mmap_kernel",
+    "test_mmap_kernel:",
+    "mov eax, {mmap}",
+    "mov rdi, {kernel_addr}",
+    "mov esi, 4096",
+    "xor edx, edx",
+    "syscall",
+    "mov rdi, rax",
+    "mov eax, {exit}",
+    "syscall",
+    // Reports how many nanoseconds a yield and a 10 ms sleep took.
+    ".global test_sleep",
+    "test_sleep:",
+    "mov eax, {time}",
+    "syscall",
+    "mov rbx, rax",
+    "mov eax, {yield_now}",
+    "syscall",
+    "mov eax, {sleep}",
+    "mov edi, 10",
+    "syscall",
+    "mov eax, {time}",
+    "syscall",
+    "sub rax, rbx",
+    "mov rdi, rax",
+    "mov eax, {exit}",
+    "syscall",
+    // Loads GS with the user data selector, which zeroes GS_BASE, and
+    // reports the time.
+    ".global test_reload_gs",
+    "test_reload_gs:",
+    "mov eax, ss",
+    "mov gs, eax",
+    "mov eax, {time}",
+    "syscall",
+    "mov rdi, rax",
+    "mov eax, {exit}",
+    "syscall",
+    ".global test_programs_end",
+    "test_programs_end:",
+    ".previous",
+    write = const syscall::WRITE,
+    exit = const syscall::EXIT,
+    yield_now = const syscall::YIELD,
+    sleep = const syscall::SLEEP,
+    time = const syscall::TIME,
+    mmap = const syscall::MMAP,
+    mmap_write = const syscall::MMAP_WRITE,
+    mmap_addr = const MMAP_ADDR,
+    kernel_addr = const user::USER_SPACE_START - 4096,
+);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::BootInfoFrameAllocator;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    thread::init();
+
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    for addr in [CODE, STACK] {
+        let page = Page::containing_address(VirtAddr::new(addr));
+        let frame = frame_allocator.allocate_frame().unwrap();
+        unsafe { mapper.map_to(page, frame, flags, &mut frame_allocator) }
+            .expect("mapping user page failed")
+            .flush();
+    }
+    unsafe {
+        let start = ptr::addr_of!(test_programs_start);
+        let len = ptr::addr_of!(test_programs_end) as usize - start as usize;
+        ptr::copy_nonoverlapping(start, CODE as *mut u8, len);
+    }
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// Runs the test program starting at `program` and returns its exit code.
+fn run(program: &u8) -> u64 {
+    let start = ptr::addr_of!(test_programs_start) as u64;
+    let entry = VirtAddr::new(CODE + (program as *const u8 as u64 - start));
+    unsafe { user::enter_user_mode(entry, VirtAddr::new(STACK + 4096)) }
+}
+
+#[test_case]
+fn write_returns_length() {
+    let written = run(unsafe { &test_write });
+    assert_eq!(written, "hello from user mode\n".len() as u64);
+}
+
+#[test_case]
+fn write_rejects_kernel_memory() {
+    let result = run(unsafe { &test_bad_write });
+    assert_eq!(SyscallError::decode(result), Err(SyscallError::BadAddress));
+}
+
+#[test_case]
+fn write_rejects_unknown_handle() {
+    let result = run(unsafe { &test_write_bad_handle });
+    assert_eq!(SyscallError::decode(result), Err(SyscallError::BadHandle));
+}
+
+#[test_case]
+fn unknown_call_fails() {
+    let result = run(unsafe { &test_unknown_call });
+    assert_eq!(SyscallError::decode(result), Err(SyscallError::NoSuchCall));
+}
+
+#[test_case]
+fn mmap_maps_zeroed_memory() {
+    assert_eq!(run(unsafe { &test_mmap }), 7);
+    // the range is taken now
+    assert_eq!(
+        SyscallError::decode(run(unsafe { &test_mmap })),
+        Err(SyscallError::InvalidArgument)
+    );
+}
+
+#[test_case]
+fn mmap_rejects_kernel_slots() {
+    let result = run(unsafe { &test_mmap_kernel });
+    assert_eq!(
+        SyscallError::decode(result),
+        Err(SyscallError::InvalidArgument)
+    );
+    let page = VirtAddr::new(user::USER_SPACE_START - 4096);
+    assert!(memory::effective_flags(page).is_none());
+}
+
+#[test_case]
+fn sleep_takes_its_time() {
+    let nanos = run(unsafe { &test_sleep });
+    assert!(nanos >= 10_000_000, "slept only {} ns", nanos);
+}
+
+#[test_case]
+fn syscall_after_gs_reload() {
+    use blog_os::percpu;
+    use x86_64::registers::model_specific::GsBase;
+
+    let nanos = run(unsafe { &test_reload_gs });
+    assert!(matches!(SyscallError::decode(nanos), Ok(nanos) if nanos > 0));
+    assert_eq!(GsBase::read(), VirtAddr::from_ptr(percpu::cpu(0)));
+}
@@ -0,0 +1,197 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{rc::Rc, vec::Vec};
+use blog_os::task::{
+    executor::Executor,
+    sync::{Barrier, Mutex, Notify, RwLock, Semaphore},
+    yield_now,
+};
+use bootloader::{entry_point, BootInfo};
+use core::cell::{Cell, RefCell};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn mutex_guard_held_across_await() {
+    let mut executor = Executor::new();
+    let mutex = Rc::new(Mutex::new(Vec::new()));
+
+    for name in ["a", "b"] {
+        let mutex = mutex.clone();
+        executor.spawn(async move {
+            let mut log = mutex.lock().await;
+            log.push(name);
+            yield_now().await;
+            log.push(name);
+        });
+    }
+    executor.run_until_idle();
+
+    assert_eq!(*mutex.try_lock().unwrap(), ["a", "a", "b", "b"]);
+}
+
+#[test_case]
+fn mutex_waiters_are_served_in_order() {
+    let mut executor = Executor::new();
+    let mutex = Rc::new(Mutex::new(()));
+    let order = Rc::new(RefCell::new(Vec::new()));
+
+    let guard = mutex.try_lock().unwrap();
+    for i in 0..4 {
+        let mutex = mutex.clone();
+        let order = order.clone();
+        executor.spawn(async move {
+            let _guard = mutex.lock().await;
+            order.borrow_mut().push(i);
+            yield_now().await;
+        });
+    }
+    executor.run_until_idle();
+    assert!(order.borrow().is_empty());
+
+    drop(guard);
+    executor.run_until_idle();
+    assert_eq!(*order.borrow(), [0, 1, 2, 3]);
+}
+
+#[test_case]
+fn rwlock_writer_is_not_starved_by_readers() {
+    let mut executor = Executor::new();
+    let lock = Rc::new(RwLock::new(0));
+    let seen = Rc::new(RefCell::new(Vec::new()));
+
+    let reader = lock.try_read().unwrap();
+    {
+        let lock = lock.clone();
+        executor.spawn(async move { *lock.write().await += 1 });
+    }
+    executor.run_until_idle();
+    {
+        let lock = lock.clone();
+        let seen = seen.clone();
+        executor.spawn(async move {
+            let value = *lock.read().await;
+            seen.borrow_mut().push(value);
+        });
+    }
+    executor.run_until_idle();
+    assert!(lock.try_read().is_none());
+    assert!(seen.borrow().is_empty());
+
+    drop(reader);
+    executor.run_until_idle();
+    assert_eq!(*seen.borrow(), [1]);
+}
+
+#[test_case]
+fn semaphore_limits_concurrency() {
+    let mut executor = Executor::new();
+    let semaphore = Rc::new(Semaphore::new(2));
+    let running = Rc::new(Cell::new(0));
+    let max_running = Rc::new(Cell::new(0));
+
+    for _ in 0..5 {
+        let semaphore = semaphore.clone();
+        let running = running.clone();
+        let max_running = max_running.clone();
+        executor.spawn(async move {
+            let _permit = semaphore.acquire().await;
+            running.set(running.get() + 1);
+            max_running.set(max_running.get().max(running.get()));
+            yield_now().await;
+            running.set(running.get() - 1);
+        });
+    }
+    executor.run_until_idle();
+
+    assert_eq!(max_running.get(), 2);
+    assert_eq!(semaphore.available_permits(), 2);
+}
+
+#[test_case]
+fn notify_one_wakes_a_waiter_or_is_stored() {
+    let mut executor = Executor::new();
+    let notify = Rc::new(Notify::new());
+    let woken = Rc::new(Cell::new(0));
+
+    for _ in 0..2 {
+        let notify = notify.clone();
+        let woken = woken.clone();
+        executor.spawn(async move {
+            notify.notified().await;
+            woken.set(woken.get() + 1);
+        });
+    }
+    executor.run_until_idle();
+    assert_eq!(woken.get(), 0);
+
+    notify.notify_one();
+    executor.run_until_idle();
+    assert_eq!(woken.get(), 1);
+
+    notify.notify_waiters();
+    executor.run_until_idle();
+    assert_eq!(woken.get(), 2);
+
+    notify.notify_one();
+    let woken_clone = woken.clone();
+    executor.spawn(async move {
+        notify.notified().await;
+        woken_clone.set(woken_clone.get() + 1);
+    });
+    executor.run_until_idle();
+    assert_eq!(woken.get(), 3);
+}
+
+#[test_case]
+fn barrier_releases_all_with_one_leader() {
+    let mut executor = Executor::new();
+    let barrier = Rc::new(Barrier::new(3));
+    let leaders = Rc::new(Cell::new(0));
+    let passed = Rc::new(Cell::new(0));
+
+    for _ in 0..3 {
+        let barrier = barrier.clone();
+        let leaders = leaders.clone();
+        let passed = passed.clone();
+        executor.spawn(async move {
+            for _ in 0..2 {
+                if barrier.wait().await.is_leader() {
+                    leaders.set(leaders.get() + 1);
+                }
+                passed.set(passed.get() + 1);
+            }
+        });
+    }
+    executor.run_until_idle();
+
+    assert_eq!(passed.get(), 6);
+    assert_eq!(leaders.get(), 2);
+}
@@ -0,0 +1,139 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use blog_os::{thread, time::Instant};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::time::Duration;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn join_returns_output() {
+    let handle = thread::spawn(|| 6 * 7);
+    assert_eq!(handle.join(), 42);
+}
+
+#[test_case]
+fn busy_thread_is_preempted() {
+    static STARTED: AtomicBool = AtomicBool::new(false);
+
+    let handle = thread::spawn(|| STARTED.store(true, Ordering::Relaxed));
+    // Never yields; only the timer interrupt lets the other thread run.
+    while !STARTED.load(Ordering::Relaxed) {
+        core::hint::spin_loop();
+    }
+    handle.join();
+}
+
+#[test_case]
+fn sleep_lasts_at_least_the_duration() {
+    let start = Instant::now();
+    thread::sleep(Duration::from_millis(5));
+    assert!(start.elapsed() >= Duration::from_millis(5));
+}
+
+#[test_case]
+fn threads_run_round_robin() {
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    COUNTER.fetch_add(1, Ordering::Relaxed);
+                    thread::yield_now();
+                }
+                i
+            })
+        })
+        .collect();
+    let ids: Vec<_> = handles.into_iter().map(|handle| handle.join()).collect();
+
+    assert_eq!(ids, (0..8).collect::<Vec<_>>());
+    assert_eq!(COUNTER.load(Ordering::Relaxed), 800);
+}
+
+#[test_case]
+fn stack_has_guard_page() {
+    use blog_os::memory;
+    use x86_64::VirtAddr;
+
+    let local = thread::spawn(|| {
+        let local = 0u8;
+        VirtAddr::from_ptr(&local)
+    })
+    .join();
+    // the entry runs close to the top of the page-aligned stack
+    let top = local.align_up(4096u64);
+    let bottom = top - thread::STACK_SIZE as u64;
+    assert!(memory::effective_flags(bottom).is_some());
+    assert!(memory::effective_flags(bottom - 1u64).is_none());
+}
+
+blog_os::task_local! {
+    static THREAD_VALUE: u32;
+}
+
+#[test_case]
+fn task_locals_stay_with_preempted_threads() {
+    use blog_os::task::executor::Executor;
+
+    let handles: Vec<_> = (1..=2)
+        .map(|n| {
+            thread::spawn(move || {
+                let mut executor = Executor::new();
+                // Stays in a single poll across several time slices.
+                let handle = executor.spawn(THREAD_VALUE.scope(n, async move {
+                    let start = Instant::now();
+                    let mut mismatches = 0;
+                    while start.elapsed() < Duration::from_millis(50) {
+                        if THREAD_VALUE.get() != n {
+                            mismatches += 1;
+                        }
+                    }
+                    mismatches
+                }));
+                let result = alloc::rc::Rc::new(core::cell::Cell::new(None));
+                let result_clone = result.clone();
+                executor.spawn(async move { result_clone.set(Some(handle.await)) });
+                executor.run_until_idle();
+                result.take()
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        assert_eq!(handle.join(), Some(Ok(0)));
+    }
+    assert!(THREAD_VALUE.try_with(|_| ()).is_err());
+}
@@ -0,0 +1,111 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use blog_os::{thread, user};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use x86_64::{
+    structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+const CODE: u64 = 0x_7000_0000_0000;
+const STACK: u64 = 0x_7000_0000_1000;
+const DATA: u64 = 0x_7000_0000_2000;
+
+/// `mov eax, 42; int 0x80`
+const RETURN_42: &[u8] = &[0xb8, 0x2a, 0x00, 0x00, 0x00, 0xcd, 0x80];
+/// Offset of `WAIT_FOR_DATA` in the code page.
+const WAIT_FOR_DATA_OFFSET: u64 = 0x100;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::allocator;
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    thread::init();
+
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    for addr in [CODE, STACK, DATA] {
+        let page = Page::containing_address(VirtAddr::new(addr));
+        let frame = frame_allocator.allocate_frame().unwrap();
+        unsafe { mapper.map_to(page, frame, flags, &mut frame_allocator) }
+            .expect("mapping user page failed")
+            .flush();
+    }
+    unsafe {
+        load(0, RETURN_42);
+        load(WAIT_FOR_DATA_OFFSET, &wait_for_data());
+    }
+
+    test_main();
+    loop {}
+}
+
+/// `loop: mov rax, [DATA]; test rax, rax; jz loop; int 0x80`
+fn wait_for_data() -> [u8; 17] {
+    let mut code = [0; 17];
+    code[..2].copy_from_slice(&[0x48, 0xa1]);
+    code[2..10].copy_from_slice(&DATA.to_le_bytes());
+    code[10..].copy_from_slice(&[0x48, 0x85, 0xc0, 0x74, 0xf1, 0xcd, 0x80]);
+    code
+}
+
+unsafe fn load(offset: u64, code: &[u8]) {
+    let dest = (CODE + offset) as *mut u8;
+    core::ptr::copy_nonoverlapping(code.as_ptr(), dest, code.len());
+}
+
+fn stack_top() -> VirtAddr {
+    VirtAddr::new(STACK + 4096)
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn user_code_returns_to_kernel() {
+    let result = unsafe { user::enter_user_mode(VirtAddr::new(CODE), stack_top()) };
+    assert_eq!(result, 42);
+    assert!(x86_64::instructions::interrupts::are_enabled());
+}
+
+#[test_case]
+fn user_code_is_preempted() {
+    let data = DATA as *mut u64;
+    unsafe { data.write_volatile(0) };
+    // Only runs if the timer preempts the user code, which waits for it.
+    let writer = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(20));
+        unsafe { (DATA as *mut u64).write_volatile(7) };
+    });
+    let entry = VirtAddr::new(CODE + WAIT_FOR_DATA_OFFSET);
+    let result = unsafe { user::enter_user_mode(entry, stack_top()) };
+    assert_eq!(result, 7);
+    writer.join();
+}
+
+#[test_case]
+fn gs_bases_survive_user_mode() {
+    use blog_os::percpu;
+    use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+
+    unsafe { user::enter_user_mode(VirtAddr::new(CODE), stack_top()) };
+    assert_eq!(GsBase::read(), VirtAddr::from_ptr(percpu::cpu(0)));
+    assert_eq!(KernelGsBase::read(), VirtAddr::zero());
+}